@@ -3,3 +3,12 @@ pub mod dom_frontier;
 pub mod phi;
 pub mod cfg;
 pub mod natural_loop;
+pub mod preheader;
+pub mod liveness;
+pub mod stats;
+pub mod versions;
+pub mod verify;
+pub mod analyses;
+pub mod chains;
+pub mod alias;
+pub mod loop_deps;
@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, BTreeSet};
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use depile::ir::instr::BinaryOp;
+use crate::ssa::{SSAFunction, SSAInstr, SSAOpd};
+
+/// A memory address decomposed as the sum of symbolic bases (e.g. `GP` and
+/// `a_base#32760`), a constant offset, and the other variable parts.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Address {
+    pub bases: BTreeSet<SSAOpd>,
+    pub offset: i64,
+    pub index: Vec<SSAOpd>,
+}
+
+impl Address {
+    fn empty() -> Self { Address { bases: BTreeSet::new(), offset: 0, index: Vec::new() } }
+
+    fn merge(mut self, other: Address) -> Self {
+        self.bases.extend(other.bases);
+        self.offset += other.offset;
+        self.index.extend(other.index);
+        self.index.sort();
+        self
+    }
+}
+
+/// Decompose memory addresses in a function, following the additions which
+/// compute them.
+pub struct AddressMap<'a> {
+    instrs: BTreeMap<usize, &'a SSAInstr>,
+}
+
+impl<'a> AddressMap<'a> {
+    pub fn new(func: &'a SSAFunction) -> Self {
+        let mut instrs = BTreeMap::new();
+        for block in &func.blocks {
+            for (j, instr) in block.instructions.iter().enumerate() {
+                instrs.insert(block.first_index + j, instr);
+            }
+        }
+        AddressMap { instrs }
+    }
+
+    /// Decompose the address `opd`.
+    pub fn decompose(&self, opd: &SSAOpd) -> Address {
+        match opd {
+            SSAOpd::Operand(Operand::Register(x)) => match self.instrs.get(x) {
+                Some(Instr::Binary {op: BinaryOp::Add, lhs, rhs}) =>
+                    self.decompose(lhs).merge(self.decompose(rhs)),
+                _ => Address { index: vec![opd.clone()], ..Address::empty() },
+            }
+            SSAOpd::Operand(Operand::Const(c)) => Address { offset: *c, ..Address::empty() },
+            SSAOpd::Operand(_) => Address { bases: BTreeSet::from([opd.clone()]), ..Address::empty() },
+            _ => Address { index: vec![opd.clone()], ..Address::empty() },
+        }
+    }
+
+    /// Conservatively check whether addresses `x` and `y` might refer to the
+    /// same memory location.
+    pub fn may_alias(&self, x: &SSAOpd, y: &SSAOpd) -> bool {
+        may_alias(&self.decompose(x), &self.decompose(y))
+    }
+}
+
+/// Alias information consulted by passes moving memory accesses.
+pub trait AliasOracle {
+    /// Returns `true` if addresses `a` and `b` might refer to the same memory
+    /// location. Answering `true` is always safe.
+    fn may_alias(&self, a: &SSAOpd, b: &SSAOpd) -> bool;
+}
+
+/// The default oracle, assuming that any two addresses might alias.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConservativeOracle;
+
+impl AliasOracle for ConservativeOracle {
+    fn may_alias(&self, _: &SSAOpd, _: &SSAOpd) -> bool { true }
+}
+
+impl AliasOracle for AddressMap<'_> {
+    fn may_alias(&self, a: &SSAOpd, b: &SSAOpd) -> bool {
+        AddressMap::may_alias(self, a, b)
+    }
+}
+
+/// Conservatively check whether addresses `x` and `y` might refer to the same
+/// memory location. Addresses with different symbolic bases are assumed to
+/// refer to different objects, and addresses differing only by a constant
+/// offset to different locations in the same object.
+pub fn may_alias(x: &Address, y: &Address) -> bool {
+    if x.bases.is_empty() || y.bases.is_empty() { return true; }
+    if x.bases != y.bases { return false; }
+    if x.index == y.index { return x.offset == y.offset; }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::alias::AddressMap;
+    use crate::analysis::phi::PhiForge;
+    use crate::samples::{get_sample_functions, GCD};
+
+    #[test]
+    fn test_gcd_alias() {
+        let funcs = get_sample_functions(GCD);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[1];
+        let addrs = AddressMap::new(func);
+
+        let mut loads = Vec::new();
+        for block in &func.blocks {
+            for instr in block.instructions.iter() {
+                if let Instr::Load(opd) = instr { loads.push(opd.clone()); }
+            }
+        }
+        // a, b, res, a, b, res
+        assert_eq!(loads.len(), 6);
+        assert!(addrs.may_alias(&loads[0], &loads[3]));
+        assert!(!addrs.may_alias(&loads[0], &loads[1]));
+        assert!(!addrs.may_alias(&loads[1], &loads[2]));
+    }
+}
@@ -0,0 +1,81 @@
+use depile::analysis::control_flow::HasBranchingBehaviour;
+use depile::ir::Function;
+use depile::ir::instr::InstrExt;
+use crate::analysis::cfg::SimpleCfg;
+use crate::analysis::dom_frontier::compute_df_cfg;
+use crate::analysis::domtree::{BlockMap, compute_domtree, compute_idom, ImmDomRel};
+use crate::analysis::natural_loop::NaturalLoop;
+
+/// Analyses on the control flow of a function, which should be re-computed
+/// together whenever the CFG is changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FunctionAnalyses {
+    pub cfg: SimpleCfg,
+    pub domtree: BlockMap,
+    pub imm_doms: ImmDomRel,
+    pub dom_frontier: BlockMap,
+    pub loops: Vec<NaturalLoop>,
+}
+
+/// Re-compute all the analyses in [`FunctionAnalyses`] for `func`, e.g. after
+/// blocks or edges are inserted or removed by a pass.
+pub fn refresh_analyses<K: InstrExt>(func: &Function<K>) -> FunctionAnalyses
+    where K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let domtree = compute_domtree(func);
+    let imm_doms = compute_idom(&domtree);
+    let dom_frontier = compute_df_cfg(&domtree, &cfg);
+    let loops = NaturalLoop::compute_loops(func);
+    FunctionAnalyses { cfg, domtree, imm_doms, dom_frontier, loops }
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::analyses::{FunctionAnalyses, refresh_analyses};
+    use crate::analysis::cfg::SimpleCfg;
+    use crate::analysis::domtree::{dominate, imm_dominators};
+    use crate::samples::{get_sample_functions, PRIME};
+
+    fn check_consistent(analyses: &FunctionAnalyses) {
+        let FunctionAnalyses { cfg, domtree, imm_doms, dom_frontier, loops } = analyses;
+        for (b, idom) in imm_doms {
+            if let Some(d) = idom {
+                assert!(dominate(domtree, *d, *b) && d != b);
+                assert!(cfg.get_prevs(*b).iter().any(|p| dominate(domtree, *d, *p)));
+            }
+        }
+        for (x, df) in dom_frontier {
+            for y in df {
+                assert!(x == y || !dominate(domtree, *x, *y));
+                assert!(cfg.get_prevs(*y).iter().any(|p| dominate(domtree, *x, *p)));
+            }
+        }
+        for l in loops {
+            assert!(cfg.get_succs(l.back_edge).contains(&l.root));
+            assert!(l.nodes.iter().all(|n| dominate(domtree, l.root, *n)));
+        }
+    }
+
+    #[test]
+    fn test_refresh() {
+        let mut funcs = get_sample_functions(PRIME);
+        let func = &mut funcs.functions[0];
+        let before = refresh_analyses(func);
+        check_consistent(&before);
+
+        // Fold the branch `9 -> 11`, leaving only the fall-through `9 -> 10`.
+        let last = func.blocks[9].instructions.len() - 1;
+        func.blocks[9].instructions[last] = Instr::Nop;
+        let after = refresh_analyses(func);
+        check_consistent(&after);
+
+        assert_eq!(after.cfg, SimpleCfg::from(func.entry_block, func.blocks.as_slice()));
+        assert_ne!(before.cfg, after.cfg);
+        assert_eq!(imm_dominators(&before.imm_doms, 11), &Some(9));
+        assert_eq!(imm_dominators(&after.imm_doms, 11), &Some(10));
+        assert_eq!(before.loops.len(), after.loops.len());
+    }
+}
@@ -1,14 +1,18 @@
+use std::cmp::min;
 use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
-use depile::analysis::control_flow::{HasBranchingBehaviour, successor_blocks_impl};
-use depile::ir::Block;
+use depile::analysis::control_flow::{BranchingBehaviour, HasBranchingBehaviour, successor_blocks_impl};
+use depile::ir::{Block, Instr};
 use depile::ir::instr::InstrExt;
 use crate::analysis::domtree::BlockSet;
+use crate::ssa::SSAFunction;
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct SimpleCfg {
     pub entry: usize,
     pub edges: BTreeMap<usize, BlockSet>,
+    /// Predecessors of each block, cached from `edges`.
+    pub preds: BTreeMap<usize, BlockSet>,
 }
 
 impl Display for SimpleCfg {
@@ -24,6 +28,16 @@ impl Display for SimpleCfg {
 }
 
 impl SimpleCfg {
+    pub fn new(entry: usize, edges: BTreeMap<usize, BlockSet>) -> Self {
+        let mut preds: BTreeMap<usize, BlockSet> = edges.keys()
+            .map(|b| (*b, BlockSet::new()))
+            .collect();
+        for (x, ys) in &edges {
+            for y in ys { preds.entry(*y).or_default().insert(*x); }
+        }
+        Self { entry, edges, preds }
+    }
+
     pub fn from<K>(entry: usize, blocks: &[Block<K>]) -> Self
         where K: InstrExt,
               K::Branching: HasBranchingBehaviour,
@@ -37,7 +51,7 @@ impl SimpleCfg {
             }
             edges.insert(i, succs);
         }
-        Self { entry, edges }
+        Self::new(entry, edges)
     }
 
     pub fn get_succs(&self, block_idx: usize) -> BlockSet {
@@ -45,37 +59,487 @@ impl SimpleCfg {
     }
 
     pub fn get_prevs(&self, block_idx: usize) -> BlockSet {
-        let mut res = BlockSet::new();
-        for (b, blocks) in self.edges.iter() {
-            if blocks.contains(&block_idx) { res.insert(*b); }
+        self.preds.get(&block_idx).cloned().unwrap_or_default()
+    }
+
+    /// The transposed CFG, for backward analyses. The entry is left as is, so
+    /// that `cfg.reverse().reverse() == cfg`; see [`SimpleCfg::reverse_from`].
+    pub fn reverse(&self) -> SimpleCfg {
+        self.reverse_from(self.entry)
+    }
+
+    /// The transposed CFG starting at `entry`, usually the exit of the
+    /// original graph.
+    pub fn reverse_from(&self, entry: usize) -> SimpleCfg {
+        SimpleCfg::new(entry, self.preds.clone())
+    }
+
+    /// Blocks reachable from `block_idx`, including itself.
+    pub fn reachable_from(&self, block_idx: usize) -> BlockSet {
+        let mut visited = BlockSet::new();
+        let mut stack = vec![block_idx];
+        while let Some(b) = stack.pop() {
+            if !visited.insert(b) { continue; }
+            stack.extend(self.get_succs(b));
+        }
+        visited
+    }
+
+    /// Blocks reachable from the entry.
+    pub fn reachable_blocks(&self) -> BlockSet {
+        self.reachable_from(self.entry)
+    }
+
+    /// Blocks no path from the entry reaches, which no phi node should
+    /// take operands from.
+    pub fn unreachable_blocks(&self) -> BlockSet {
+        let reachable = self.reachable_blocks();
+        self.edges.keys().filter(|b| !reachable.contains(b)).cloned().collect()
+    }
+
+    /// Blocks in reverse post-order of a depth-first search from the entry,
+    /// visiting successors in ascending order. Blocks unreachable from the
+    /// entry are appended in ascending order, so that all the blocks appear.
+    pub fn reverse_post_order(&self) -> Vec<usize> {
+        let mut visited = BlockSet::from([self.entry]);
+        let mut post = Vec::new();
+        let pending = |b: usize| -> Vec<usize> { self.get_succs(b).into_iter().rev().collect() };
+        let mut stack = vec![(self.entry, pending(self.entry))];
+        loop {
+            let next = match stack.last_mut() {
+                Some((_, succs)) => succs.pop(),
+                None => break,
+            };
+            match next {
+                Some(s) => if visited.insert(s) { stack.push((s, pending(s))); },
+                None => post.push(stack.pop().unwrap().0),
+            }
+        }
+        post.reverse();
+        post.extend(self.edges.keys().filter(|b| !visited.contains(b)));
+        post
+    }
+
+    /// Returns `true` if the edge `from -> to` is critical, i.e. `from` has
+    /// multiple successors and `to` has multiple predecessors. A block has to
+    /// be inserted on such an edge before copies are placed on it.
+    pub fn is_critical_edge(&self, from: usize, to: usize) -> bool {
+        self.get_succs(from).len() > 1 && self.get_prevs(to).len() > 1
+    }
+
+    /// All the critical edges, as `(from, to)` in ascending order.
+    pub fn critical_edges(&self) -> Vec<(usize, usize)> {
+        self.edges.iter()
+            .flat_map(|(x, ys)| ys.iter().map(move |y| (*x, *y)))
+            .filter(|(x, y)| self.is_critical_edge(*x, *y))
+            .collect()
+    }
+
+    /// Strongly connected components by Tarjan's algorithm, in reverse
+    /// topological order. Unlike natural loops, irreducible cycles are found
+    /// as well. Every block is in some component; see [`SimpleCfg::is_cyclic`].
+    pub fn sccs(&self) -> Vec<BlockSet> {
+        struct Tarjan<'a> {
+            cfg: &'a SimpleCfg,
+            index: BTreeMap<usize, usize>,
+            low: BTreeMap<usize, usize>,
+            stack: Vec<usize>,
+            on_stack: BlockSet,
+            res: Vec<BlockSet>,
+        }
+
+        fn visit(t: &mut Tarjan, b: usize) {
+            let idx = t.index.len();
+            t.index.insert(b, idx);
+            t.low.insert(b, idx);
+            t.stack.push(b);
+            t.on_stack.insert(b);
+            for s in t.cfg.get_succs(b) {
+                if !t.index.contains_key(&s) {
+                    visit(t, s);
+                    let low = min(t.low[&b], t.low[&s]);
+                    t.low.insert(b, low);
+                } else if t.on_stack.contains(&s) {
+                    let low = min(t.low[&b], t.index[&s]);
+                    t.low.insert(b, low);
+                }
+            }
+            if t.low[&b] == t.index[&b] {
+                let mut scc = BlockSet::new();
+                while let Some(x) = t.stack.pop() {
+                    t.on_stack.remove(&x);
+                    scc.insert(x);
+                    if x == b { break; }
+                }
+                t.res.push(scc);
+            }
+        }
+
+        let mut t = Tarjan {
+            cfg: self,
+            index: BTreeMap::new(),
+            low: BTreeMap::new(),
+            stack: Vec::new(),
+            on_stack: BlockSet::new(),
+            res: Vec::new(),
+        };
+        for b in self.edges.keys() {
+            if !t.index.contains_key(b) { visit(&mut t, *b); }
+        }
+        t.res
+    }
+
+    /// Returns `true` if the strongly connected component `scc` contains a
+    /// cycle, i.e. it has more than one block or a block branching to itself.
+    pub fn is_cyclic(&self, scc: &BlockSet) -> bool {
+        scc.len() > 1 || scc.iter().any(|b| self.get_succs(*b).contains(b))
+    }
+
+    /// Blocks without any successor, i.e. the exits of the function.
+    pub fn exits(&self) -> BlockSet {
+        self.edges.iter()
+            .filter(|(_, succs)| succs.is_empty())
+            .map(|(b, _)| *b)
+            .collect()
+    }
+
+    /// Blocks from which no exit can be reached, found by walking the reversed
+    /// CFG from the exits. Blocks unreachable from the entry are not excluded.
+    pub fn reverse_unreachable(&self) -> BlockSet {
+        let mut visited = BlockSet::new();
+        let mut stack: Vec<usize> = self.exits().into_iter().collect();
+        while let Some(b) = stack.pop() {
+            if !visited.insert(b) { continue; }
+            stack.extend(self.get_prevs(b));
+        }
+        self.edges.keys().filter(|b| !visited.contains(b)).cloned().collect()
+    }
+
+    /// Render the CFG as a DOT graph called `name`, labelling each block with
+    /// `label`. The entry block is drawn in bold.
+    pub fn to_dot_with(&self, name: &str, label: impl Fn(usize) -> String) -> String {
+        let mut res = format!("digraph \"{}\" {{\n", escape_dot(name));
+        for b in self.edges.keys() {
+            let style = if *b == self.entry { ", style=bold" } else { "" };
+            res += &format!("  b{} [label=\"{}\"{}];\n", b, escape_dot(&label(*b)), style);
+        }
+        for (x, ys) in &self.edges {
+            for y in ys {
+                res += &format!("  b{} -> b{};\n", x, y);
+            }
+        }
+        res += "}\n";
+        res
+    }
+
+    /// Render the CFG as a DOT graph called `name`, with blocks labelled by
+    /// their indices, e.g. for `dot -Tpng`.
+    pub fn to_dot(&self, name: &str) -> String {
+        self.to_dot_with(name, |b| format!("b{}", b))
+    }
+}
+
+/// Successors of a block, distinguishing the fall-through from the branch
+/// target. Both might be the same block.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Successors {
+    /// The next block, if the block might fall through.
+    pub fallthrough: Option<usize>,
+    /// The destination of the branch at the end of the block.
+    pub taken: Option<usize>,
+}
+
+impl Successors {
+    /// Returns the successors in order, fall-through first, without duplicates.
+    pub fn ordered(&self) -> Vec<usize> {
+        let mut res: Vec<usize> = self.fallthrough.into_iter().collect();
+        if let Some(t) = self.taken {
+            if self.fallthrough != Some(t) { res.push(t); }
         }
         res
     }
 }
 
+/// Compute the successors of `block_idx` in `blocks`, telling the fall-through
+/// from the branch target by the branching behaviour of the last instruction.
+pub fn successors<K>(blocks: &[Block<K>], block_idx: usize) -> Successors
+    where K: InstrExt,
+          K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    let behaviour = blocks[block_idx].instructions.last()
+        .map_or(BranchingBehaviour { might_fallthrough: true, alternative_dest: None },
+                |instr| instr.get_branching_behaviour());
+    let next = block_idx + 1;
+    Successors {
+        fallthrough: if behaviour.might_fallthrough && next < blocks.len() { Some(next) } else { None },
+        taken: behaviour.alternative_dest,
+    }
+}
+
+/// Find the branches in `func` whose destinations are not blocks of `func`,
+/// as `(instruction index, destination)`.
+///
+/// Destinations of branches are always literal block indices in this IR, as
+/// there is no computed or indirect branch; a branch destination derived from
+/// an operand would show up here as an invalid destination.
+pub fn invalid_branches(func: &SSAFunction) -> Vec<(usize, usize)> {
+    let mut res = Vec::new();
+    for block in &func.blocks {
+        for (j, instr) in block.instructions.iter().enumerate() {
+            if let Instr::Branch(branching) = instr {
+                if branching.dest >= func.blocks.len() { res.push((block.first_index + j, branching.dest)); }
+            }
+        }
+    }
+    res
+}
+
+/// Options for [`function_dot`].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DotOptions {
+    /// Include the markers in a block as part of its label.
+    pub markers: bool,
+}
+
+/// Render the CFG of `func` in DOT format.
+pub fn function_dot(func: &SSAFunction, options: DotOptions) -> String {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    cfg.to_dot_with("cfg", |b| {
+        let mut label = format!("b{}", b);
+        if options.markers {
+            for instr in func.blocks[b].instructions.iter() {
+                if let Instr::Marker(_) = instr {
+                    label += &format!("\n{}", instr);
+                }
+            }
+        }
+        label
+    })
+}
+
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::BTreeSet;
     use crate::map_b_bs;
-    use crate::analysis::domtree::{BlockMap, BlockSet};
-    use crate::analysis::cfg::SimpleCfg;
-    use crate::samples::{get_sample_functions, PRIME};
+    use crate::analysis::domtree::{BlockMap, BlockSet, compute_domtree, dominate};
+    use crate::analysis::cfg::{DotOptions, function_dot, invalid_branches, SimpleCfg, successors, Successors};
+    use depile::ir::Instr;
+    use crate::analysis::phi::PhiForge;
+    use crate::samples::{get_sample_functions, ALL_SAMPLES, PRIME};
+    use crate::samples::synthetic::DEAD_LOOP;
 
     #[test]
     fn test_prime_cfg() {
         let funcs = get_sample_functions(PRIME);
         let func = &funcs.functions[0];
         let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
-        let cfg_ = SimpleCfg {
-            entry: 0,
-            edges: map_b_bs![
-                0 => [1], 1 => [2, 12], 2 => [3], 3 => [4, 9],
-                4 => [5, 6], 5 => [8], 6 => [7, 8], 7 => [8], 8 => [3],
-                9 => [10, 11], 10 => [11], 11 => [1], 12 => []
-            ]
-        };
+        let cfg_ = SimpleCfg::new(0, map_b_bs![
+            0 => [1], 1 => [2, 12], 2 => [3], 3 => [4, 9],
+            4 => [5, 6], 5 => [8], 6 => [7, 8], 7 => [8], 8 => [3],
+            9 => [10, 11], 10 => [11], 11 => [1], 12 => []
+        ]);
         assert_eq!(cfg, cfg_);
         assert_eq!(cfg.get_succs(6), BlockSet::from([7, 8]));
         assert_eq!(cfg.get_prevs(3), BlockSet::from([2, 8]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_reverse() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let rev = cfg.reverse_from(12);
+        let rev_ = SimpleCfg::new(12, map_b_bs![
+            0 => [], 1 => [0, 11], 2 => [1], 3 => [2, 8],
+            4 => [3], 5 => [4], 6 => [4], 7 => [6], 8 => [5, 6, 7],
+            9 => [3], 10 => [9], 11 => [9, 10], 12 => [1]
+        ]);
+        assert_eq!(rev, rev_);
+        for b in 0..func.blocks.len() { assert_eq!(rev.get_succs(b), cfg.get_prevs(b)); }
+        assert_eq!(cfg.reverse().reverse(), cfg);
+
+        let funcs = get_sample_functions(DEAD_LOOP);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert_eq!(cfg.reverse().reverse(), cfg);
+    }
+
+    #[test]
+    fn test_cached_prevs() {
+        for sample in ALL_SAMPLES {
+            for func in get_sample_functions(sample).functions.iter() {
+                let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+                for b in 0..func.blocks.len() {
+                    let scanned: BlockSet = cfg.edges.iter()
+                        .filter(|(_, succs)| succs.contains(&b))
+                        .map(|(x, _)| *x)
+                        .collect();
+                    assert_eq!(cfg.get_prevs(b), scanned);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unreachable_blocks() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert_eq!(cfg.reachable_blocks(), BlockSet::from_iter(0..func.blocks.len()));
+        assert!(cfg.unreachable_blocks().is_empty());
+
+        // Block 2 is only reachable from itself, and block 3 from block 2.
+        let cfg = SimpleCfg::new(0, map_b_bs![
+            0 => [1], 1 => [4], 2 => [2, 3], 3 => [4], 4 => []
+        ]);
+        assert_eq!(cfg.reachable_blocks(), BlockSet::from([0, 1, 4]));
+        assert_eq!(cfg.unreachable_blocks(), BlockSet::from([2, 3]));
+        assert_eq!(cfg.reachable_from(2), BlockSet::from([2, 3, 4]));
+    }
+
+    #[test]
+    fn test_reverse_post_order() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let domtree = compute_domtree(func);
+        let rpo = cfg.reverse_post_order();
+        assert_eq!(rpo[0], 0);
+        let mut sorted = rpo.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..func.blocks.len()).collect::<Vec<_>>());
+
+        // Only back edges go backward in reverse post-order.
+        let position = |b: usize| rpo.iter().position(|x| *x == b).unwrap();
+        for (x, ys) in &cfg.edges {
+            for y in ys {
+                if position(*y) <= position(*x) { assert!(dominate(&domtree, *y, *x)); }
+            }
+        }
+
+        let cfg = SimpleCfg::new(0, map_b_bs![
+            0 => [1], 1 => [4], 2 => [2, 3], 3 => [4], 4 => []
+        ]);
+        assert_eq!(cfg.reverse_post_order(), vec![0, 1, 4, 2, 3]);
+    }
+
+    #[test]
+    fn test_critical_edges() {
+        // A diamond in a loop, where only the back edge is critical.
+        let cfg = SimpleCfg::new(0, map_b_bs![
+            0 => [1], 1 => [2, 3], 2 => [4], 3 => [4], 4 => [1, 5], 5 => []
+        ]);
+        assert_eq!(cfg.critical_edges(), vec![(4, 1)]);
+        assert!(cfg.is_critical_edge(4, 1));
+        assert!(!cfg.is_critical_edge(1, 2));
+        assert!(!cfg.is_critical_edge(2, 4));
+    }
+
+    #[test]
+    fn test_sccs() {
+        // The cycle of blocks 1 and 2 can be entered from both blocks.
+        let cfg = SimpleCfg::new(0, map_b_bs![
+            0 => [1, 2], 1 => [2, 3], 2 => [1], 3 => []
+        ]);
+        let sccs = cfg.sccs();
+        assert_eq!(sccs, vec![BlockSet::from([3]), BlockSet::from([1, 2]), BlockSet::from([0])]);
+        assert_eq!(sccs.iter().filter(|scc| cfg.is_cyclic(scc)).count(), 1);
+
+        let cfg = SimpleCfg::new(0, map_b_bs![
+            0 => [1], 1 => [4], 2 => [2, 3], 3 => [4], 4 => []
+        ]);
+        let sccs = cfg.sccs();
+        assert_eq!(sccs.len(), 5);
+        assert!(cfg.is_cyclic(&BlockSet::from([2])));
+        assert!(!cfg.is_cyclic(&BlockSet::from([3])));
+    }
+
+    #[test]
+    fn test_reverse_unreachable() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert_eq!(cfg.exits(), BlockSet::from([12]));
+        assert!(cfg.reverse_unreachable().is_empty());
+
+        let funcs = get_sample_functions(DEAD_LOOP);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let dead = cfg.reverse_unreachable();
+        assert_eq!(dead.len(), 1);
+        let b = *dead.iter().next().unwrap();
+        assert_eq!(cfg.get_succs(b), BlockSet::from([b]));
+    }
+
+    #[test]
+    fn test_dot_markers() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let dot = cfg.to_dot("prime");
+        assert!(dot.starts_with("digraph \"prime\" {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("  b0 [label=\"b0\", style=bold];"));
+        assert!(dot.contains("  b3 [label=\"b3\"];"));
+        assert!(dot.contains("  b8 -> b3;"));
+        assert_eq!(dot.lines().filter(|l| l.contains(" -> ")).count(),
+                   cfg.edges.values().map(|ys| ys.len()).sum::<usize>());
+
+        for sample in ALL_SAMPLES {
+            let (ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            for func in &ssa.functions {
+                let plain = function_dot(func, DotOptions::default());
+                let marked = function_dot(func, DotOptions { markers: true });
+                for (b, block) in func.blocks.iter().enumerate() {
+                    for instr in block.instructions.iter() {
+                        if let Instr::Marker(_) = instr {
+                            let node = format!("  b{} [label=\"b{}\\n{}", b, b, instr);
+                            assert!(marked.contains(&node));
+                            assert!(!plain.contains(&node));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_successors() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let blocks = func.blocks.as_slice();
+        let cfg = SimpleCfg::from(func.entry_block, blocks);
+        assert_eq!(successors(blocks, 3), Successors { fallthrough: Some(4), taken: Some(9) });
+        assert_eq!(successors(blocks, 8), Successors { fallthrough: None, taken: Some(3) });
+        assert_eq!(successors(blocks, 12), Successors { fallthrough: None, taken: None });
+        for (b, succs) in &cfg.edges {
+            assert_eq!(&successors(blocks, *b).ordered().into_iter().collect::<BlockSet>(), succs);
+        }
+    }
+
+    #[test]
+    fn test_direct_branches() {
+        for sample in ALL_SAMPLES {
+            let (ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            for func in &ssa.functions {
+                assert_eq!(invalid_branches(func), Vec::new());
+            }
+        }
+
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PRIME));
+        let func = &mut ssa.functions[0];
+        let block = &mut func.blocks[8];
+        let last = block.instructions.len() - 1;
+        let instr_idx = block.first_index + last;
+        match &mut block.instructions[last] {
+            Instr::Branch(branching) => branching.dest = 42,
+            _ => panic!("expect a branch at the end of block 8"),
+        }
+        assert_eq!(invalid_branches(func), vec![(instr_idx, 42)]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::analysis::liveness::is_tracked;
+use crate::ssa::{SSAFunction, SSAFunctions, SSAOpd, SSAValues};
+
+/// Def-use chains of an SSA function: where each value is defined, and the
+/// instructions using it. Operands of phi nodes are used by the phi nodes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DefUse {
+    pub defs: BTreeMap<SSAOpd, usize>,
+    pub uses: BTreeMap<SSAOpd, Vec<usize>>,
+}
+
+impl DefUse {
+    pub fn from(func: &SSAFunction) -> Self {
+        let mut defs = BTreeMap::new();
+        let mut uses: BTreeMap<SSAOpd, Vec<usize>> = BTreeMap::new();
+        for block in &func.blocks {
+            for (j, instr) in block.instructions.iter().enumerate() {
+                let instr_idx = block.first_index + j;
+                for opd in instr.used_values() {
+                    if !is_tracked(opd) { continue; }
+                    let locations = uses.entry(opd.clone()).or_default();
+                    if locations.last() != Some(&instr_idx) { locations.push(instr_idx); }
+                }
+                for def in instr.defined_values(instr_idx) {
+                    if is_tracked(&def) { defs.insert(def, instr_idx); }
+                }
+            }
+        }
+        DefUse { defs, uses }
+    }
+
+    /// Indices of the instructions using `value`, in ascending order.
+    pub fn uses_of(&self, value: &SSAOpd) -> &[usize] {
+        self.uses.get(value).map_or(&[], |v| v.as_slice())
+    }
+}
+
+/// Use-def chains of an SSA function, the inverse of [`DefUse`]: for each
+/// instruction, where the values it uses are defined. Values without a
+/// definition in the function, such as parameters, are left out.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UseDef {
+    pub defs: BTreeMap<usize, BTreeMap<SSAOpd, usize>>,
+}
+
+impl UseDef {
+    pub fn from(func: &SSAFunction) -> Self {
+        UseDef::from_chains(&DefUse::from(func))
+    }
+
+    pub fn from_chains(chains: &DefUse) -> Self {
+        let mut defs: BTreeMap<usize, BTreeMap<SSAOpd, usize>> = BTreeMap::new();
+        for (value, uses) in &chains.uses {
+            if let Some(def) = chains.defs.get(value) {
+                for instr_idx in uses {
+                    defs.entry(*instr_idx).or_default().insert(value.clone(), *def);
+                }
+            }
+        }
+        UseDef { defs }
+    }
+
+    /// Index of the instruction defining `value` used at `instr_idx`.
+    pub fn def_of(&self, instr_idx: usize, value: &SSAOpd) -> Option<usize> {
+        self.defs.get(&instr_idx).and_then(|defs| defs.get(value)).cloned()
+    }
+}
+
+/// Print `funcs` as SSA, annotating each definition with its uses.
+pub fn annotate_uses(funcs: &SSAFunctions) -> String {
+    let mut res = String::new();
+    for (i, func) in funcs.functions.iter().enumerate() {
+        let entry = if i == funcs.entry_function { "(ENTRY) " } else { "" };
+        writeln!(res, "{}Function #{}:", entry, i).unwrap();
+        writeln!(res, "#parameters = {}", func.parameter_count).unwrap();
+        writeln!(res, "#local_vars = {}", func.local_var_count).unwrap();
+        writeln!(res).unwrap();
+        res += &annotate_function(func);
+        writeln!(res).unwrap();
+    }
+    res
+}
+
+/// Print the blocks in `func`, annotating each definition with its uses,
+/// e.g. `instr 8: move a$1 c$1    ; used at 3, 10, 12`.
+pub fn annotate_function(func: &SSAFunction) -> String {
+    let chains = DefUse::from(func);
+    let mut res = String::new();
+    for (b, block) in func.blocks.iter().enumerate() {
+        let entry = if b == func.entry_block { "(ENTRY) " } else { "" };
+        writeln!(res, "{}Block #{}:", entry, b).unwrap();
+        for (j, instr) in block.instructions.iter().enumerate() {
+            let instr_idx = block.first_index + j;
+            write!(res, "  instr {}: {}", instr_idx, instr).unwrap();
+            let uses: Vec<String> = instr.defined_values(instr_idx).iter()
+                .filter(|def| is_tracked(def))
+                .flat_map(|def| chains.uses_of(def).iter().map(|u| u.to_string()))
+                .collect();
+            if !uses.is_empty() { write!(res, "    ; used at {}", uses.join(", ")).unwrap(); }
+            writeln!(res).unwrap();
+        }
+        writeln!(res).unwrap();
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::chains::{annotate_function, DefUse, UseDef};
+    use crate::analysis::phi::PhiForge;
+    use crate::samples::{get_sample_functions, GCD, PRIME};
+    use crate::ssa::SSAOpd;
+
+    #[test]
+    fn test_gcd_uses() {
+        let funcs = get_sample_functions(GCD);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[0];
+        let chains = DefUse::from(func);
+
+        let b1 = SSAOpd::Subscribed("b".to_string(), 1);
+        let c1 = SSAOpd::Subscribed("c".to_string(), 1);
+        assert_eq!(chains.defs.get(&b1), Some(&5));
+        assert_eq!(chains.uses_of(&b1), &[6, 9, 10]);
+        assert_eq!(chains.uses_of(&c1), &[3, 10, 12]);
+
+        let text = annotate_function(func);
+        assert!(text.contains("instr 5: b$1 <- phi [b0: b$0] [b2: b$2]    ; used at 6, 9, 10\n"));
+        assert!(text.contains("instr 8: move a$1 c$1    ; used at 3, 10, 12\n"));
+        assert!(text.contains("instr 12: write c$1\n"));
+    }
+
+    #[test]
+    fn test_prime_chains() {
+        // In the inner loop of `prime`, `j` is defined by the phi node at 21:
+        //   instr 21: j$2 <- phi [b2: j$1] [b8: j$6]
+        // and used by the condition, the three subscripts `prime[j]`, the phi
+        // node merging the branches in the loop body, and the test `j == i`.
+        let funcs = get_sample_functions(PRIME);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[0];
+        let chains = DefUse::from(func);
+        let (j, _) = chains.defs.iter().find(|(_, def)| **def == 21).unwrap();
+        assert!(matches!(j, SSAOpd::Subscribed(var, _) if var == "j"));
+        assert_eq!(chains.uses_of(j), &[22, 24, 27, 38, 46, 50]);
+
+        let use_def = UseDef::from_chains(&chains);
+        for instr_idx in chains.uses_of(j) {
+            assert_eq!(use_def.def_of(*instr_idx, j), Some(21));
+        }
+        assert_eq!(use_def, UseDef::from(func));
+        let i = SSAOpd::Subscribed("i".to_string(), 0);
+        assert_eq!(use_def.def_of(22, &i), None);
+    }
+}
@@ -1,6 +1,7 @@
 use depile::ir::Function;
 use crate::analysis::cfg::SimpleCfg;
-use crate::analysis::domtree::{BlockSet, dominate, dominate_nodes, BlockMap, imm_dominators, ImmDomRel, compute_idom, root_of_domtree, compute_domtree};
+use crate::analysis::domtree::{BlockSet, dominate, dominate_nodes, BlockMap, imm_dominate_nodes, imm_dominators,
+                               ImmDomRel, compute_idom, root_of_domtree, compute_domtree, compute_postdomtree};
 
 /// Compute dominance frontier (DF) for `func`.
 pub fn compute_dom_frontier(func: &Function) -> BlockMap {
@@ -15,22 +16,93 @@ pub fn compute_dom_frontier_with_domtree(func: &Function, domtree: &BlockMap) ->
     compute_df_cfg(&domtree, &cfg)
 }
 
+/// Compute the control dependences of each block in `func`, i.e. the blocks
+/// deciding whether it is executed, as its post-dominance frontier (DF on the
+/// reverse CFG, rooted at the synthetic exit of [`compute_postdomtree`]).
+pub fn compute_control_dependence(func: &Function) -> BlockMap {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let exit = func.blocks.len();
+    let mut edges = cfg.preds.clone();
+    edges.insert(exit, cfg.exits());
+    let reverse = SimpleCfg::new(exit, edges);
+
+    let pdfs = compute_df_cfg(&compute_postdomtree(func), &reverse);
+    pdfs.into_iter()
+        .filter(|(b, _)| *b != exit)
+        .map(|(b, bs)| (b, bs.into_iter().filter(|x| *x != exit).collect()))
+        .collect()
+}
+
 /// Compute dominance frontier (DF) for all nodes in `domtree`.
+///
+/// The dominator tree is walked in post-order with an explicit stack rather
+/// than by recursion, so that deeply nested functions do not overflow the
+/// stack. Blocks unreachable from the root are walked afterwards.
 pub fn compute_df_cfg(domtree: &BlockMap, cfg: &SimpleCfg) -> BlockMap {
     let imm_doms: ImmDomRel = compute_idom(domtree);
     let root: usize = root_of_domtree(domtree);
     let mut res: BlockMap = BlockMap::new();
-    df(root, domtree, &imm_doms, cfg, &mut res);
+    let roots = std::iter::once(root)
+        .chain(imm_doms.iter().filter(|(b, d)| d.is_none() && **b != root).map(|(b, _)| *b));
+    for r in roots {
+        let mut stack = vec![(r, false)];
+        while let Some((b, visited)) = stack.pop() {
+            if res.contains_key(&b) { continue; }
+            if visited {
+                let df_b = df(b, domtree, &imm_doms, cfg, &res);
+                res.insert(b, df_b);
+                continue;
+            }
+            stack.push((b, true));
+            for child in imm_dominate_nodes(&imm_doms, b) { stack.push((child, false)); }
+        }
+    }
     res
 }
 
-/// Compute dominance frontier (DF) for `block_idx` and store the result in `dfs`.
-fn df<'a>(block_idx: usize,
-          domtree: &BlockMap,
-          imm_doms: &ImmDomRel,
-          cfg: &SimpleCfg,
-          dfs: &'a mut BlockMap) -> &'a BlockSet {
-    if dfs.contains_key(&block_idx) { return dfs.get(&block_idx).unwrap(); }
+/// Compute dominance frontier (DF) for all blocks in `cfg` from their
+/// immediate dominators `imm_doms` only, e.g. as given by
+/// [`compute_idom_lt`](crate::analysis::domtree::compute_idom_lt).
+///
+/// For each edge `p -> s`, `s` is in the DF of `p` and of its dominators up
+/// to the immediate dominator of `s` (Cooper, Harvey and Kennedy).
+pub fn compute_df_idom(imm_doms: &ImmDomRel, cfg: &SimpleCfg) -> BlockMap {
+    let mut res: BlockMap = imm_doms.keys().map(|b| (*b, BlockSet::new())).collect();
+    for (p, succs) in &cfg.edges {
+        for s in succs {
+            let idom = *imm_dominators(imm_doms, *s);
+            let mut runner = Some(*p);
+            while let Some(r) = runner {
+                if Some(r) == idom { break; }
+                res.entry(r).or_default().insert(*s);
+                runner = *imm_dominators(imm_doms, r);
+            }
+        }
+    }
+    res
+}
+
+/// Compute the iterated dominance frontier (DF+) of `defs` from the dominance
+/// frontiers `dfs`, i.e. the blocks where phi nodes are needed for a variable
+/// defined in `defs`.
+pub fn iterated_dominance_frontier(dfs: &BlockMap, defs: &BlockSet) -> BlockSet {
+    let mut res = BlockSet::new();
+    let mut worklist: Vec<usize> = defs.iter().cloned().collect();
+    while let Some(b) = worklist.pop() {
+        for df in dfs.get(&b).unwrap() {
+            if res.insert(*df) { worklist.push(*df); }
+        }
+    }
+    res
+}
+
+/// Compute dominance frontier (DF) for `block_idx`, where the DFs of the
+/// nodes it dominates are already in `dfs`.
+fn df(block_idx: usize,
+      domtree: &BlockMap,
+      imm_doms: &ImmDomRel,
+      cfg: &SimpleCfg,
+      dfs: &BlockMap) -> BlockSet {
     let mut res: BlockSet = BlockSet::new();
 
     // compute Local(idx)
@@ -43,23 +115,23 @@ fn df<'a>(block_idx: usize,
     // compute Up(idx)
     for child in dominate_nodes(domtree, block_idx) {
         if child == block_idx { continue; }
-        for node in df(child, domtree, imm_doms, cfg, dfs) {
+        for node in dfs.get(&child).into_iter().flatten() {
             if !dominate(domtree, block_idx, *node) { res.insert(*node); }
             if block_idx == *node { res.insert(*node); }
         }
     }
-    dfs.insert(block_idx, res);
-    return dfs.get(&block_idx).unwrap();
+    res
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeSet;
     use crate::analysis::cfg::SimpleCfg;
-    use super::{compute_df_cfg, compute_dom_frontier};
+    use super::{compute_control_dependence, compute_df_cfg, compute_df_idom, compute_dom_frontier, iterated_dominance_frontier};
     use crate::map_b_bs;
     use crate::samples::{get_sample_functions, PRIME, ALL_SAMPLES};
-    use crate::analysis::domtree::BlockMap;
+    use crate::samples::synthetic::PRUNED_PHI;
+    use crate::analysis::domtree::{BlockMap, compute_idom, compute_idom_lt};
 
     #[test]
     fn test_df() {
@@ -68,18 +140,29 @@ mod tests {
             4 => [0, 1, 3, 4], 5 => [0, 1, 3, 5],
             6 => [0, 1, 3, 6], 7 => [0, 1, 7]
         ];
-        let cfg: SimpleCfg = SimpleCfg {
-            entry: 0,
-            edges: map_b_bs![
-                0 => [1], 1 => [2, 3], 2 => [7], 3 => [4, 5],
-                4 => [6], 5 => [6]   , 6 => [7], 7 => [1]
-            ]
-        };
+        let cfg: SimpleCfg = SimpleCfg::new(0, map_b_bs![
+            0 => [1], 1 => [2, 3], 2 => [7], 3 => [4, 5],
+            4 => [6], 5 => [6]   , 6 => [7], 7 => [1]
+        ]);
         let dfs = map_b_bs![
             0 => [] , 1 => [1], 2 => [7], 3 => [7],
             4 => [6], 5 => [6], 6 => [7], 7 => [1]
         ];
         assert_eq!(dfs, compute_df_cfg(&domtree, &cfg));
+        assert_eq!(dfs, compute_df_idom(&compute_idom(&domtree), &cfg));
+        assert_eq!(iterated_dominance_frontier(&dfs, &BTreeSet::from([4, 5])), BTreeSet::from([1, 6, 7]));
+        assert_eq!(iterated_dominance_frontier(&dfs, &BTreeSet::from([0])), BTreeSet::new());
+    }
+
+    #[test]
+    fn test_control_dependence() {
+        // Block 0 branches to the then block 1 and the else block 2, joining at 3.
+        let funcs = get_sample_functions(PRUNED_PHI);
+        let cds = compute_control_dependence(&funcs.functions[0]);
+        assert_eq!(cds.get(&0), Some(&BTreeSet::new()));
+        assert_eq!(cds.get(&1), Some(&BTreeSet::from([0])));
+        assert_eq!(cds.get(&2), Some(&BTreeSet::from([0])));
+        assert_eq!(cds.get(&3), Some(&BTreeSet::new()));
     }
 
     #[test]
@@ -93,6 +176,8 @@ mod tests {
             9 => [1], 10 => [11], 11 => [1], 12 => []
         ];
         assert_eq!(dfs, dfs_);
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert_eq!(compute_df_idom(&compute_idom_lt(func), &cfg), dfs_);
     }
 
     #[test]
@@ -5,6 +5,7 @@ use depile::analysis::control_flow::HasBranchingBehaviour;
 use depile::analysis::data_flow::{AnalysisRes, ForwardAnalysis};
 use depile::ir::Function;
 use depile::ir::instr::InstrExt;
+use crate::analysis::cfg::SimpleCfg;
 use crate::analysis::domtree::dominance_analysis::DomAnalysis;
 
 /// A set of blocks
@@ -32,6 +33,17 @@ pub fn dominate(domtree: &BlockMap, x: usize, y: usize) -> bool {
     dominator(&domtree, y).contains(&x)
 }
 
+/// Returns `true` if `x` dom `y`, by walking up the immediate dominators of
+/// `y`, without the sets of dominators [`dominate`] needs.
+pub fn dominates_idom(imm_doms: &ImmDomRel, x: usize, y: usize) -> bool {
+    let mut curr = Some(y);
+    while let Some(b) = curr {
+        if b == x { return true; }
+        curr = *imm_dominators(imm_doms, b);
+    }
+    false
+}
+
 /// Returns dominators of `block_idx`, i.e. `x` dominates `block_idx`
 /// for `x` in return value.
 #[allow(unused)]
@@ -57,6 +69,24 @@ pub fn imm_dominators(imm_doms: &ImmDomRel, block_idx: usize) -> &Option<usize>
     imm_doms.get(&block_idx).unwrap()
 }
 
+/// Returns the nearest block dominating both `a` and `b`, which is either of
+/// them if it dominates the other. Panics if the idom chains never meet, e.g.
+/// when a block is unreachable from the entry.
+pub fn nearest_common_dominator(imm_doms: &ImmDomRel, a: usize, b: usize) -> usize {
+    let mut ancestors = BlockSet::new();
+    let mut curr = Some(a);
+    while let Some(x) = curr {
+        ancestors.insert(x);
+        curr = *imm_dominators(imm_doms, x);
+    }
+    let mut curr = Some(b);
+    while let Some(x) = curr {
+        if ancestors.contains(&x) { return x; }
+        curr = *imm_dominators(imm_doms, x);
+    }
+    panic!("No common dominator of #{} and #{}", a, b);
+}
+
 /// Returns the root of `domtree`.
 #[allow(unused)]
 pub fn root_of_domtree(domtree: &BlockMap) -> usize {
@@ -79,13 +109,216 @@ pub fn compute_domtree<K: InstrExt>(func: &Function<K>) -> BlockMap
     domtree
 }
 
+/// Compute post-dominator tree for function `func`, in the same shape as
+/// [`compute_domtree`], so that [`dominate`] and [`compute_idom`] apply.
+///
+/// The blocks without successors are joined at a synthetic exit block, whose
+/// index is the number of blocks in `func`, and which is the root of the
+/// tree. Blocks from which no exit is reachable are post-dominated by all.
+pub fn compute_postdomtree<K: InstrExt>(func: &Function<K>) -> BlockMap
+    where K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let exit = func.blocks.len();
+    let all = BlockSet::from_iter(0..=exit);
+    let mut pdoms: BlockMap = (0..exit).map(|b| (b, all.clone())).collect();
+    pdoms.insert(exit, BlockSet::from([exit]));
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..exit).rev() {
+            let mut succs = cfg.get_succs(b);
+            if succs.is_empty() { succs.insert(exit); }
+            let mut res = all.clone();
+            for s in succs {
+                res = res.intersection(pdoms.get(&s).unwrap()).cloned().collect();
+            }
+            res.insert(b);
+            if &res != pdoms.get(&b).unwrap() {
+                pdoms.insert(b, res);
+                changed = true;
+            }
+        }
+    }
+    pdoms
+}
+
 /// Compute immediate dominator for all blocks from `domtree`.
 pub fn compute_idom(domtree: &BlockMap) -> ImmDomRel {
     let mut idoms = BTreeMap::new();
     for (i, _) in domtree { idoms.insert(*i, get_idom(*i, domtree)); }
+    debug_assert_eq!(validate_domtree(domtree, &idoms), Ok(()));
     idoms
 }
 
+/// Compute immediate dominators for all blocks in `func` by the
+/// Lengauer-Tarjan algorithm, without computing the sets of dominators as
+/// [`compute_domtree`] does. Blocks unreachable from the entry have no
+/// immediate dominator, as with [`compute_idom`].
+pub fn compute_idom_lt<K: InstrExt>(func: &Function<K>) -> ImmDomRel
+    where K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    const NONE: usize = usize::MAX;
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let n = func.blocks.len();
+
+    // Step 1: number the blocks in DFS preorder.
+    let mut semi = vec![NONE; n];
+    let mut parent = vec![NONE; n];
+    let mut vertex = Vec::new();
+    let mut stack = vec![(cfg.entry, NONE)];
+    while let Some((b, p)) = stack.pop() {
+        if semi[b] != NONE { continue; }
+        semi[b] = vertex.len();
+        parent[b] = p;
+        vertex.push(b);
+        for s in cfg.get_succs(b).into_iter().rev() {
+            if semi[s] == NONE { stack.push((s, b)); }
+        }
+    }
+
+    fn compress(v: usize, ancestor: &mut [usize], label: &mut [usize], semi: &[usize]) {
+        let a = ancestor[v];
+        if ancestor[a] == NONE { return; }
+        compress(a, ancestor, label, semi);
+        if semi[label[a]] < semi[label[v]] { label[v] = label[a]; }
+        ancestor[v] = ancestor[a];
+    }
+
+    fn eval(v: usize, ancestor: &mut [usize], label: &mut [usize], semi: &[usize]) -> usize {
+        if ancestor[v] == NONE { return v; }
+        compress(v, ancestor, label, semi);
+        label[v]
+    }
+
+    // Step 2 and 3: compute semi-dominators, and implicitly define
+    // immediate dominators from them.
+    let mut ancestor = vec![NONE; n];
+    let mut label: Vec<usize> = (0..n).collect();
+    let mut idom = vec![NONE; n];
+    let mut bucket: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for &w in vertex.iter().skip(1).rev() {
+        for v in cfg.get_prevs(w) {
+            if semi[v] == NONE { continue; }
+            let u = eval(v, &mut ancestor, &mut label, &semi);
+            if semi[u] < semi[w] { semi[w] = semi[u]; }
+        }
+        bucket[vertex[semi[w]]].push(w);
+        let p = parent[w];
+        ancestor[w] = p;
+        for v in std::mem::take(&mut bucket[p]) {
+            let u = eval(v, &mut ancestor, &mut label, &semi);
+            idom[v] = if semi[u] < semi[v] { u } else { p };
+        }
+    }
+
+    // Step 4: explicitly define immediate dominators in preorder.
+    for &w in vertex.iter().skip(1) {
+        if idom[w] != vertex[semi[w]] { idom[w] = idom[idom[w]]; }
+    }
+
+    (0..n).map(|b| (b, if idom[b] == NONE { None } else { Some(idom[b]) })).collect()
+}
+
+/// Render the dominator tree given by `imm_doms` in DOT format, with an edge
+/// from each immediate dominator. Blocks without immediate dominator, i.e.
+/// the root, are drawn in bold like the entry in [`SimpleCfg::to_dot`].
+pub fn domtree_to_dot(imm_doms: &ImmDomRel) -> String {
+    let mut res = String::from("digraph domtree {\n");
+    for (b, idom) in imm_doms {
+        let style = if idom.is_none() { ", style=bold" } else { "" };
+        res += &format!("  b{} [label=\"b{}\"{}];\n", b, b, style);
+    }
+    for (b, idom) in imm_doms {
+        if let Some(d) = idom { res += &format!("  b{} -> b{};\n", d, b); }
+    }
+    res += "}\n";
+    res
+}
+
+/// Check that `imm_doms` forms a tree consistent with `domtree`: it is rooted
+/// at the unique block dominated only by itself, has no cycles, and the
+/// dominators of each block are its immediate dominator's plus itself.
+///
+/// Blocks unreachable from the entry, whose dominators are all the blocks,
+/// might have no immediate dominator.
+pub fn validate_domtree(domtree: &BlockMap, imm_doms: &ImmDomRel) -> Result<(), String> {
+    let roots: Vec<usize> = domtree.iter()
+        .filter(|(i, doms)| doms.len() == 1 && doms.contains(*i))
+        .map(|(i, _)| *i)
+        .collect();
+    let root = match roots.as_slice() {
+        [root] => *root,
+        _ => return Err(format!("expect a unique root, found {:?}", roots)),
+    };
+
+    for (b, doms) in domtree {
+        if !doms.contains(b) { return Err(format!("block #{} does not dominate itself", b)); }
+        let idom = imm_doms.get(b).ok_or_else(|| format!("block #{} has no idom entry", b))?;
+        match idom {
+            None if *b == root => (),
+            None if doms.len() == domtree.len() => (),
+            None => return Err(format!("block #{} has no idom but is not the root", b)),
+            Some(d) if *b == root => return Err(format!("root #{} has idom #{}", b, d)),
+            Some(d) => {
+                let mut expected = domtree.get(d).ok_or_else(|| format!("idom #{} of #{} is unknown", d, b))?.clone();
+                expected.insert(*b);
+                if d == b || &expected != doms {
+                    return Err(format!("idom #{} of #{} is inconsistent with dominators {:?}", d, b, doms));
+                }
+
+                let mut curr = *b;
+                for _ in 0..domtree.len() {
+                    match imm_doms.get(&curr) {
+                        Some(Some(next)) => curr = *next,
+                        _ => break,
+                    }
+                }
+                if curr != root { return Err(format!("idom chain of #{} does not reach the root", b)); }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Update `domtree` and `imm_doms` of `func` after an edge into `to` is
+/// removed, e.g. by folding a branch. Only the blocks reachable from `to`
+/// might have their dominators changed, so only those are re-computed.
+pub fn update_on_edge_removal<K: InstrExt>(func: &Function<K>,
+                                           domtree: &mut BlockMap,
+                                           imm_doms: &mut ImmDomRel,
+                                           to: usize)
+    where K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let all = BlockSet::from_iter(0..func.blocks.len());
+    let affected = cfg.reachable_from(to);
+
+    for b in &affected { domtree.insert(*b, all.clone()); }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in &affected {
+            let mut doms = all.clone();
+            if *b == cfg.entry { doms.clear(); }
+            for prev in cfg.get_prevs(*b) {
+                doms = doms.intersection(domtree.get(&prev).unwrap()).cloned().collect();
+            }
+            doms.insert(*b);
+            if &doms != domtree.get(b).unwrap() {
+                domtree.insert(*b, doms);
+                changed = true;
+            }
+        }
+    }
+
+    for b in &affected { imm_doms.insert(*b, get_idom(*b, domtree)); }
+}
+
 /// Compute immediate dominator for `block_idx`.
 fn get_idom(block_idx: usize, domtree: &BlockMap) -> Option<usize> {
     let doms: &BlockSet = domtree.get(&block_idx).unwrap();
@@ -156,8 +389,12 @@ mod dominance_analysis {
 #[cfg(test)]
 mod tests {
     use std::collections::{BTreeMap, BTreeSet};
-    use crate::samples::{get_sample_functions, PRIME, ALL_SAMPLES};
-    use crate::analysis::domtree::{compute_domtree, compute_idom};
+    use depile::ir::Instr;
+    use crate::samples::{get_sample_functions, COLLATZ, PRIME, ALL_SAMPLES};
+    use crate::analysis::cfg::SimpleCfg;
+    use crate::analysis::domtree::{compute_domtree, compute_idom, compute_idom_lt, compute_postdomtree, dominate,
+                                   dominates_idom, domtree_to_dot, nearest_common_dominator, update_on_edge_removal, validate_domtree};
+    use crate::analysis::natural_loop::NaturalLoop;
     use super::BlockMap;
 
     #[test]
@@ -188,6 +425,52 @@ mod tests {
         assert_eq!(idoms, idoms_);
     }
 
+    #[test]
+    fn test_dominates_idom() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let domtree = compute_domtree(func);
+        let idoms = compute_idom(&domtree);
+        for x in 0..func.blocks.len() {
+            for y in 0..func.blocks.len() {
+                assert_eq!(dominates_idom(&idoms, x, y), dominate(&domtree, x, y), "{} dom {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_domtree_dot() {
+        let funcs = get_sample_functions(PRIME);
+        let idoms = compute_idom(&compute_domtree(&funcs.functions[0]));
+        let dot = domtree_to_dot(&idoms);
+        assert!(dot.starts_with("digraph domtree {\n"));
+        assert_eq!(dot.lines().filter(|l| l.contains(" [label=")).count(), 13);
+        assert_eq!(dot.lines().filter(|l| l.contains(" -> ")).count(), 12);
+        assert!(dot.contains("  b0 [label=\"b0\", style=bold];\n"));
+        assert!(dot.contains("  b4 -> b8;\n"));
+    }
+
+    #[test]
+    fn test_nearest_common_dominator() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let domtree = compute_domtree(func);
+        let idoms = compute_idom(&domtree);
+        assert_eq!(nearest_common_dominator(&idoms, 5, 7), 4);
+        assert_eq!(nearest_common_dominator(&idoms, 10, 8), 3);
+        assert_eq!(nearest_common_dominator(&idoms, 11, 12), 1);
+        // One dominates the other.
+        assert_eq!(nearest_common_dominator(&idoms, 3, 7), 3);
+        assert_eq!(nearest_common_dominator(&idoms, 6, 6), 6);
+
+        for a in 0..func.blocks.len() {
+            for b in 0..func.blocks.len() {
+                let d = nearest_common_dominator(&idoms, a, b);
+                assert!(dominate(&domtree, d, a) && dominate(&domtree, d, b));
+            }
+        }
+    }
+
     #[test]
     fn test_samples_dom() {
         for s in ALL_SAMPLES {
@@ -196,4 +479,77 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_idom_lt() {
+        let funcs = get_sample_functions(PRIME);
+        let func = &funcs.functions[0];
+        let idoms = compute_idom_lt(func);
+        assert_eq!(idoms.get(&0), Some(&None));
+        assert_eq!(idoms.get(&8), Some(&Some(4)));
+        assert_eq!(idoms.get(&12), Some(&Some(1)));
+
+        for s in ALL_SAMPLES {
+            for func in get_sample_functions(s).functions.iter() {
+                assert_eq!(compute_idom_lt(func), compute_idom(&compute_domtree(func)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_postdomtree() {
+        let funcs = get_sample_functions(COLLATZ);
+        let func = &funcs.functions[0];
+        let pdoms = compute_postdomtree(func);
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let exits: Vec<usize> = cfg.exits().into_iter().collect();
+        assert_eq!(exits.len(), 1);
+
+        let loops = NaturalLoop::compute_loops(func);
+        assert!(!loops.is_empty());
+        for nl in &loops {
+            for b in &nl.nodes { assert!(dominate(&pdoms, exits[0], *b)); }
+        }
+
+        // The synthetic exit is the root of the tree.
+        let idoms = compute_idom(&pdoms);
+        assert_eq!(idoms.get(&func.blocks.len()), Some(&None));
+        assert_eq!(idoms.get(&exits[0]), Some(&Some(func.blocks.len())));
+    }
+
+    #[test]
+    fn test_edge_removal() {
+        let mut funcs = get_sample_functions(PRIME);
+        let func = &mut funcs.functions[0];
+        let mut domtree = compute_domtree(func);
+        let mut idoms = compute_idom(&domtree);
+        assert_eq!(idoms.get(&11), Some(&Some(9)));
+
+        // Fold the branch `9 -> 11`, leaving only the fall-through `9 -> 10`.
+        let last = func.blocks[9].instructions.len() - 1;
+        func.blocks[9].instructions[last] = Instr::Nop;
+        update_on_edge_removal(func, &mut domtree, &mut idoms, 11);
+
+        assert_eq!(domtree, compute_domtree(func));
+        assert_eq!(idoms, compute_idom(&compute_domtree(func)));
+        assert_eq!(idoms.get(&11), Some(&Some(10)));
+    }
+
+    #[test]
+    fn test_validate_domtree() {
+        let domtree: BlockMap = map_b_bs![0 => [0], 1 => [0, 1], 2 => [0, 1, 2]];
+        let idoms = BTreeMap::from_iter([(0, None), (1, Some(0)), (2, Some(1))]);
+        assert_eq!(validate_domtree(&domtree, &idoms), Ok(()));
+
+        let cycle = BTreeMap::from_iter([(0, None), (1, Some(2)), (2, Some(1))]);
+        assert!(validate_domtree(&domtree, &cycle).is_err());
+
+        let skipped = BTreeMap::from_iter([(0, None), (1, Some(0)), (2, Some(0))]);
+        assert!(validate_domtree(&domtree, &skipped).is_err());
+
+        // Block 1 and 2 claim to dominate each other.
+        let inconsistent: BlockMap = map_b_bs![0 => [0], 1 => [0, 1, 2], 2 => [0, 1, 2], 3 => [0, 3]];
+        let idoms = BTreeMap::from_iter([(0, None), (1, None), (2, None), (3, Some(0))]);
+        assert!(validate_domtree(&inconsistent, &idoms).is_err());
+    }
 }
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, BTreeSet};
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use depile::ir::instr::HasOperand;
+use depile::ir::instr::stripped::Function;
+use crate::analysis::cfg::SimpleCfg;
+use crate::analysis::domtree::BlockSet;
+use crate::opt::loop_invariant::Hoist;
+use crate::ssa::{Phi, SSAFunction, SSAOpd, SSAValues};
+
+/// Mapping block to the set of values live at its entry (or exit).
+pub type LiveSets = BTreeMap<usize, BTreeSet<SSAOpd>>;
+
+/// Returns `true` if `opd` is a value tracked by liveness analysis, i.e. a
+/// defined subscribed variable or a register.
+pub fn is_tracked(opd: &SSAOpd) -> bool {
+    match opd {
+        SSAOpd::Subscribed(_, _) => true,
+        SSAOpd::Operand(Operand::Register(_)) => true,
+        _ => false,
+    }
+}
+
+/// Compute live-in and live-out sets for all blocks in `func`.
+///
+/// Operands of a phi node are live at the exit of the corresponding
+/// predecessor, and the destination of a phi node is defined at the entry
+/// of its block.
+pub fn compute_liveness(func: &SSAFunction) -> (LiveSets, LiveSets) {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let mut upward: LiveSets = BTreeMap::new();
+    let mut defs: LiveSets = BTreeMap::new();
+    let mut phi_uses: LiveSets = BTreeMap::new();
+    let mut live_in: LiveSets = BTreeMap::new();
+    let mut live_out: LiveSets = BTreeMap::new();
+
+    for i in 0..func.blocks.len() {
+        upward.insert(i, BTreeSet::new());
+        defs.insert(i, BTreeSet::new());
+        phi_uses.insert(i, BTreeSet::new());
+        live_in.insert(i, BTreeSet::new());
+        live_out.insert(i, BTreeSet::new());
+    }
+
+    for (i, block) in func.blocks.iter().enumerate() {
+        let mut instr_idx = block.first_index;
+        for instr in block.instructions.iter() {
+            match instr {
+                Instr::Extra(Phi {vars, blocks, dest: _}) =>
+                    for (var, b) in vars.iter().zip(blocks.iter()) {
+                        if is_tracked(var) { phi_uses.get_mut(b).unwrap().insert(var.clone()); }
+                    }
+                _ => for opd in instr.used_values() {
+                    if is_tracked(opd) && !defs.get(&i).unwrap().contains(opd) {
+                        upward.get_mut(&i).unwrap().insert(opd.clone());
+                    }
+                }
+            }
+            for def in instr.defined_values(instr_idx) {
+                if is_tracked(&def) { defs.get_mut(&i).unwrap().insert(def); }
+            }
+            instr_idx += 1;
+        }
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..func.blocks.len()).rev() {
+            let mut out = phi_uses.get(&i).unwrap().clone();
+            for succ in cfg.get_succs(i) {
+                out.extend(live_in.get(&succ).unwrap().iter().cloned());
+            }
+            let mut inn = upward.get(&i).unwrap().clone();
+            for opd in &out {
+                if !defs.get(&i).unwrap().contains(opd) { inn.insert(opd.clone()); }
+            }
+            if &out != live_out.get(&i).unwrap() { live_out.insert(i, out); changed = true; }
+            if &inn != live_in.get(&i).unwrap() { live_in.insert(i, inn); changed = true; }
+        }
+    }
+
+    (live_in, live_out)
+}
+
+/// Mapping block to the set of source variables live at its entry (or exit).
+pub type VarLiveSets = BTreeMap<usize, BTreeSet<String>>;
+
+/// Compute live-in and live-out sets of source variables for all blocks in
+/// `func` before SSA construction, e.g. to build pruned SSA.
+pub fn compute_var_liveness(func: &Function) -> (VarLiveSets, VarLiveSets) {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let mut upward: VarLiveSets = BTreeMap::new();
+    let mut defs: VarLiveSets = BTreeMap::new();
+    for (i, block) in func.blocks.iter().enumerate() {
+        let mut ue = BTreeSet::new();
+        let mut def = BTreeSet::new();
+        for instr in block.instructions.iter() {
+            let uses: Vec<&Operand> = match instr {
+                Instr::Move {source, dest: _} => vec![source],
+                _ => instr.get_operands().into_iter().collect(),
+            };
+            for opd in uses {
+                if let Operand::Var(var, _) = opd {
+                    if !def.contains(var) { ue.insert(var.clone()); }
+                }
+            }
+            if let Instr::Move {source: _, dest: Operand::Var(var, _)} = instr { def.insert(var.clone()); }
+        }
+        upward.insert(i, ue);
+        defs.insert(i, def);
+    }
+
+    let mut live_in: VarLiveSets = (0..func.blocks.len()).map(|i| (i, BTreeSet::new())).collect();
+    let mut live_out: VarLiveSets = live_in.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..func.blocks.len()).rev() {
+            let mut out = BTreeSet::new();
+            for succ in cfg.get_succs(i) {
+                out.extend(live_in.get(&succ).unwrap().iter().cloned());
+            }
+            let mut inn = upward.get(&i).unwrap().clone();
+            for var in &out {
+                if !defs.get(&i).unwrap().contains(var) { inn.insert(var.clone()); }
+            }
+            if &out != live_out.get(&i).unwrap() { live_out.insert(i, out); changed = true; }
+            if &inn != live_in.get(&i).unwrap() { live_in.insert(i, inn); changed = true; }
+        }
+    }
+    (live_in, live_out)
+}
+
+/// Values live just before the instruction at `instr_index` in block
+/// `block_idx`, found by scanning backward from the live-out set of the block.
+/// An `instr_index` past the last instruction gives the live-out set.
+///
+/// Phi nodes define their destinations at the entry of the block, and their
+/// operands are live at the predecessors instead.
+pub fn live_at(func: &SSAFunction, block_idx: usize, instr_index: usize) -> BTreeSet<SSAOpd> {
+    let (_, live_out) = compute_liveness(func);
+    let block = &func.blocks[block_idx];
+    let mut live = live_out.get(&block_idx).unwrap().clone();
+    for (i, instr) in block.instructions.iter().enumerate().rev() {
+        let idx = block.first_index + i;
+        if idx < instr_index { break; }
+        for def in instr.defined_values(idx) { live.remove(&def); }
+        if let Instr::Extra(_) = instr { continue; }
+        for opd in instr.used_values() {
+            if is_tracked(opd) { live.insert(opd.clone()); }
+        }
+    }
+    live
+}
+
+/// Re-compute the liveness of each value in `values` only, walking backward
+/// from its uses to its definition. Blocks not on a live path of these values
+/// are left untouched.
+pub fn update_values(func: &SSAFunction,
+                     live_in: &mut LiveSets,
+                     live_out: &mut LiveSets,
+                     values: &BTreeSet<SSAOpd>) {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    for value in values {
+        for set in live_in.values_mut() { set.remove(value); }
+        for set in live_out.values_mut() { set.remove(value); }
+        if !is_tracked(value) { continue; }
+
+        let mut def_blocks = BlockSet::new();
+        let mut phi_preds = BlockSet::new();
+        let mut use_blocks = BlockSet::new();
+        for (i, block) in func.blocks.iter().enumerate() {
+            let mut instr_idx = block.first_index;
+            let mut defined = false;
+            for instr in block.instructions.iter() {
+                match instr {
+                    Instr::Extra(Phi {vars, blocks, dest: _}) =>
+                        for (var, b) in vars.iter().zip(blocks.iter()) {
+                            if var == value { phi_preds.insert(*b); }
+                        }
+                    _ => if !defined && instr.used_values().contains(&value) {
+                        use_blocks.insert(i);
+                    }
+                }
+                if instr.defined_values(instr_idx).contains(value) {
+                    def_blocks.insert(i);
+                    defined = true;
+                }
+                instr_idx += 1;
+            }
+        }
+
+        for b in use_blocks { mark_live_in(&cfg, b, value, &def_blocks, live_in, live_out); }
+        for p in phi_preds { mark_live_out(&cfg, p, value, &def_blocks, live_in, live_out); }
+    }
+
+    fn mark_live_out(cfg: &SimpleCfg,
+                     block_idx: usize,
+                     value: &SSAOpd,
+                     def_blocks: &BlockSet,
+                     live_in: &mut LiveSets,
+                     live_out: &mut LiveSets) {
+        live_out.get_mut(&block_idx).unwrap().insert(value.clone());
+        if def_blocks.contains(&block_idx) { return; }
+        mark_live_in(cfg, block_idx, value, def_blocks, live_in, live_out);
+    }
+
+    fn mark_live_in(cfg: &SimpleCfg,
+                    block_idx: usize,
+                    value: &SSAOpd,
+                    def_blocks: &BlockSet,
+                    live_in: &mut LiveSets,
+                    live_out: &mut LiveSets) {
+        if !live_in.get_mut(&block_idx).unwrap().insert(value.clone()) { return; }
+        for prev in cfg.get_prevs(block_idx) {
+            mark_live_out(cfg, prev, value, def_blocks, live_in, live_out);
+        }
+    }
+}
+
+/// Incrementally update liveness after `hoists` are done at once by
+/// [`hoist_loop`](crate::opt::loop_invariant::LoopInVariant::hoist_loop),
+/// where `live_in` and `live_out` describe the function before hoisting and
+/// `func` is the function after it.
+///
+/// Hoisting shifts every instruction after the preheader by the number of
+/// hoisted instructions, and moves each of them from `origin`, before or
+/// after the preheader, to `target`, so the registers in the sets are
+/// renumbered first; then only the hoisted values and their operands are
+/// re-computed.
+pub fn update_after_hoist(func: &SSAFunction,
+                          live_in: &mut LiveSets,
+                          live_out: &mut LiveSets,
+                          hoists: &[Hoist]) {
+    let end = match hoists.iter().map(|hoist| hoist.target).min() {
+        Some(end) => end,
+        None => return,
+    };
+    debug_assert!(hoists.iter().all(|hoist| hoist.preheader == hoists[0].preheader),
+                  "hoists into several preheaders: {:?}", hoists);
+    let count = hoists.len();
+    let targets: BTreeMap<usize, usize> = hoists.iter().map(|hoist| (hoist.origin, hoist.target)).collect();
+    let renumber = |opd: &SSAOpd| match opd {
+        SSAOpd::Operand(Operand::Register(x)) => match targets.get(x) {
+            Some(target) => SSAOpd::Operand(Operand::Register(*target)),
+            None if *x >= end => SSAOpd::Operand(Operand::Register(x + count)),
+            None => opd.clone(),
+        },
+        _ => opd.clone(),
+    };
+    for set in live_in.values_mut().chain(live_out.values_mut()) {
+        *set = set.iter().map(renumber).collect();
+    }
+
+    let block = &func.blocks[hoists[0].preheader];
+    let mut values = BTreeSet::new();
+    for hoist in hoists {
+        let instr = &block.instructions[hoist.target - block.first_index];
+        values.extend(instr.defined_values(hoist.target));
+        values.extend(instr.used_values().into_iter().cloned());
+    }
+    update_values(func, live_in, live_out, &values);
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::liveness::{compute_liveness, compute_var_liveness, live_at, update_after_hoist};
+    use crate::analysis::natural_loop::NaturalLoop;
+    use crate::analysis::phi::PhiForge;
+    use crate::opt::loop_invariant::LoopInVariant;
+    use crate::samples::{get_sample_functions, GCD};
+    use crate::samples::synthetic::{BOTTOM_TEST, INVARIANT, LIVE_ACROSS};
+    use crate::ssa::{SSAOpd, SSAValues};
+
+    #[test]
+    fn test_update_after_hoist() {
+        // The loop body is placed before the preheader in `BOTTOM_TEST`, so
+        // the instruction is hoisted to a later index.
+        for (sample, forward) in [(INVARIANT, false), (BOTTOM_TEST, true)] {
+            let funcs = get_sample_functions(sample);
+            let (mut ssa, _) = PhiForge::run(&funcs);
+            let func = &mut ssa.functions[0];
+
+            let mut lv = LoopInVariant::new();
+            let loops = lv.insert_preheaders(func);
+            let (mut live_in, mut live_out) = compute_liveness(func);
+            let hoists = lv.hoist_loop(func, &loops[0]);
+            assert!(!hoists.is_empty());
+            assert_eq!(hoists.iter().all(|hoist| hoist.origin < hoist.target), forward);
+            update_after_hoist(func, &mut live_in, &mut live_out, &hoists);
+
+            assert_eq!((live_in, live_out), compute_liveness(func));
+        }
+    }
+
+    #[test]
+    fn test_live_at() {
+        let funcs = get_sample_functions(INVARIANT);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[0];
+        let (live_in, live_out) = compute_liveness(func);
+
+        let header = NaturalLoop::compute_loops(func)[0].root;
+        let block = &func.blocks[header];
+        let phis: Vec<_> = block.instructions.iter()
+            .take_while(|instr| matches!(instr, Instr::Extra(_)))
+            .collect();
+        assert!(!phis.is_empty());
+
+        let after_phis = block.first_index + phis.len();
+        let live = live_at(func, header, after_phis);
+        for (offset, phi) in phis.iter().enumerate() {
+            for def in phi.defined_values(block.first_index + offset) {
+                assert!(live.contains(&def), "{} is not live after phi nodes", def);
+            }
+        }
+
+        let end = block.first_index + block.instructions.len();
+        assert_eq!(&live_at(func, header, end), live_out.get(&header).unwrap());
+        assert_eq!(&live_at(func, header, block.first_index), live_in.get(&header).unwrap());
+    }
+
+    #[test]
+    fn test_live_across_loop() {
+        // `n` is defined in the entry block, and only used after the loop.
+        let funcs = get_sample_functions(LIVE_ACROSS);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[0];
+        let (live_in, live_out) = compute_liveness(func);
+
+        let n = SSAOpd::Subscribed("n".to_string(), 0);
+        let lp = &NaturalLoop::compute_loops(func)[0];
+        for b in &lp.nodes {
+            assert!(live_in.get(b).unwrap().contains(&n), "{} is not live into block {}", n, b);
+            assert!(live_out.get(b).unwrap().contains(&n), "{} is not live out of block {}", n, b);
+        }
+        assert!(live_out.get(&func.entry_block).unwrap().contains(&n));
+        assert!(!live_in.get(&func.entry_block).unwrap().contains(&n));
+    }
+
+    #[test]
+    fn test_var_liveness() {
+        // `c` is assigned in the loop body before it is used, unlike `a` and `b`.
+        let funcs = get_sample_functions(GCD);
+        let (live_in, _) = compute_var_liveness(&funcs.functions[0]);
+        let header = live_in.get(&1).unwrap();
+        assert!(header.contains("a") && header.contains("b"));
+        assert!(!header.contains("c"));
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeSet;
+use depile::ir::Instr;
+use crate::analysis::alias::{AddressMap, may_alias};
+use crate::analysis::natural_loop::NaturalLoop;
+use crate::ssa::{SSAFunction, SSAOpd, SSAValues};
+
+/// Loop-carried dependencies of a natural loop, i.e. a store in an iteration
+/// which might be read by a load in a later iteration.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LoopDependence {
+    /// The root of the loop.
+    pub root: usize,
+    /// Pairs of instruction indices of the store and the load.
+    pub dependencies: Vec<(usize, usize)>,
+}
+
+impl LoopDependence {
+    /// Returns `true` if iterations of the loop are independent in memory.
+    pub fn is_parallel(&self) -> bool { self.dependencies.is_empty() }
+}
+
+/// Find the loop-carried dependencies of `nl` in `func`, using the
+/// conservative alias model in [`may_alias`].
+///
+/// A store and a load through the same address are within one iteration only
+/// if the address depends on a value defined in the loop, e.g. `a[i]`.
+pub fn loop_carried_dependencies(func: &SSAFunction, nl: &NaturalLoop) -> LoopDependence {
+    let addrs = AddressMap::new(func);
+    let mut defs = BTreeSet::new();
+    let mut stores = Vec::new();
+    let mut loads = Vec::new();
+    for n in &nl.nodes {
+        let block = &func.blocks[*n];
+        for (j, instr) in block.instructions.iter().enumerate() {
+            let instr_idx = block.first_index + j;
+            defs.extend(instr.defined_values(instr_idx));
+            match instr {
+                Instr::Store {data: _, address} => stores.push((instr_idx, addrs.decompose(address))),
+                Instr::Load(address) => loads.push((instr_idx, addrs.decompose(address))),
+                _ => (),
+            }
+        }
+    }
+
+    let varies = |opd: &SSAOpd| defs.contains(opd);
+    let mut dependencies = Vec::new();
+    for (s, store) in &stores {
+        for (l, load) in &loads {
+            if !may_alias(store, load) { continue; }
+            if store == load && store.index.iter().any(varies) { continue; }
+            dependencies.push((*s, *l));
+        }
+    }
+    LoopDependence { root: nl.root, dependencies }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::loop_deps::loop_carried_dependencies;
+    use crate::analysis::natural_loop::NaturalLoop;
+    use crate::analysis::phi::PhiForge;
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::{PARALLEL, REDUCTION};
+
+    #[test]
+    fn test_loop_carried() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(REDUCTION));
+        let func = &ssa.functions[0];
+        let loops = NaturalLoop::compute_loops(func);
+        assert_eq!(loops.len(), 1);
+        let deps = loop_carried_dependencies(func, &loops[0]);
+        assert!(!deps.is_parallel());
+        assert_eq!(deps.dependencies.len(), 1);
+
+        let (ssa, _) = PhiForge::run(&get_sample_functions(PARALLEL));
+        let func = &ssa.functions[0];
+        let loops = NaturalLoop::compute_loops(func);
+        assert_eq!(loops.len(), 1);
+        assert!(loop_carried_dependencies(func, &loops[0]).is_parallel());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use depile::analysis::control_flow::HasBranchingBehaviour;
 use depile::ir::Function;
 use depile::ir::instr::InstrExt;
@@ -47,12 +48,101 @@ impl NaturalLoop {
         loops
     }
 
+    /// Returns the existing preheader of this loop in `cfg`, i.e. the unique
+    /// predecessor of the root outside the loop, whose only successor is the root.
+    pub fn preheader(&self, cfg: &SimpleCfg) -> Option<usize> {
+        let preds: Vec<usize> = cfg.get_prevs(self.root).into_iter()
+            .filter(|p| !self.nodes.contains(p))
+            .collect();
+        match preds.as_slice() {
+            [p] if cfg.get_succs(*p) == BlockSet::from([self.root]) => Some(*p),
+            _ => None,
+        }
+    }
+
+    /// Returns the loop nesting forest of `loops`, mapping each loop, by its
+    /// index in `loops`, to the indices of the loops immediately nested in it.
+    /// Loops sharing a header are merged into the first one of them first,
+    /// and the others are left out of the forest.
+    pub fn nesting_forest(loops: &[NaturalLoop]) -> BTreeMap<usize, Vec<usize>> {
+        let mut merged: BTreeMap<usize, (usize, BlockSet)> = BTreeMap::new();
+        for (i, nl) in loops.iter().enumerate() {
+            merged.entry(nl.root)
+                .or_insert_with(|| (i, BlockSet::new()))
+                .1.extend(nl.nodes.iter().cloned());
+        }
+
+        let mut forest: BTreeMap<usize, Vec<usize>> = merged.values().map(|(i, _)| (*i, Vec::new())).collect();
+        for (i, nodes) in merged.values() {
+            // The parent is the smallest loop containing this one.
+            let parent = merged.values()
+                .filter(|(j, outer)| j != i && outer.is_superset(nodes))
+                .min_by_key(|(_, outer)| outer.len());
+            if let Some((j, _)) = parent { forest.get_mut(j).unwrap().push(*i); }
+        }
+        forest.values_mut().for_each(|children| children.sort());
+        forest
+    }
+}
+
+/// Returns the loops in `loops` containing block `block_idx`.
+pub fn loops_containing(block_idx: usize, loops: &[NaturalLoop]) -> Vec<&NaturalLoop> {
+    loops.iter().filter(|nl| nl.nodes.contains(&block_idx)).collect()
+}
+
+/// Returns the loops in `loops` enclosing `nl`, including `nl` itself, from
+/// the outermost one to the innermost one.
+pub fn enclosing_loops<'a>(nl: &NaturalLoop, loops: &'a [NaturalLoop]) -> Vec<&'a NaturalLoop> {
+    let mut res: Vec<&NaturalLoop> = loops.iter().filter(|outer| outer.nodes.is_superset(&nl.nodes)).collect();
+    res.sort_by_key(|outer| std::cmp::Reverse(outer.nodes.len()));
+    res
+}
+
+/// Returns `true` if blocks `a` and `b` are both in some loop in `loops`.
+pub fn same_loop(a: usize, b: usize, loops: &[NaturalLoop]) -> bool {
+    loops_containing(a, loops).iter().any(|nl| nl.nodes.contains(&b))
+}
+
+/// Returns the headers of the irreducible loops in `func`, i.e. the entries
+/// of the cycles entered at more than one block. Such cycles are found as
+/// strongly connected components of the CFG, which are not described by a
+/// natural loop rooted at their only entry.
+pub fn irreducible_headers<K: InstrExt>(func: &Function<K>) -> BlockSet
+    where K: InstrExt,
+          K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let reachable = cfg.reachable_blocks();
+    let roots: BlockSet = NaturalLoop::compute_loops(func).iter().map(|nl| nl.root).collect();
+    let mut res = BlockSet::new();
+    for scc in cfg.sccs() {
+        if !cfg.is_cyclic(&scc) || !scc.is_subset(&reachable) { continue; }
+        let entries: BlockSet = scc.iter()
+            .filter(|b| **b == cfg.entry || cfg.get_prevs(**b).iter().any(|p| !scc.contains(p)))
+            .cloned()
+            .collect();
+        if entries.len() > 1 || !entries.is_subset(&roots) { res.extend(entries); }
+    }
+    res
+}
+
+/// Returns `true` if every loop in `func` is a natural loop.
+pub fn is_reducible<K: InstrExt>(func: &Function<K>) -> bool
+    where K: InstrExt,
+          K::Branching: HasBranchingBehaviour,
+          K::Marker: HasBranchingBehaviour,
+          K::Extra: HasBranchingBehaviour {
+    irreducible_headers(func).is_empty()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::analysis::natural_loop::NaturalLoop;
+    use std::collections::BTreeMap;
+    use crate::analysis::natural_loop::{enclosing_loops, irreducible_headers, is_reducible, loops_containing, NaturalLoop, same_loop};
+    use crate::analysis::cfg::SimpleCfg;
     use crate::samples::{get_sample_functions, PRIME};
+    use crate::samples::synthetic::{INVARIANT, IRREDUCIBLE, JUMP_ENTRY, NESTED_LOOPS, TWO_LOOPS};
 
     #[test]
     fn test_loop() {
@@ -60,4 +150,80 @@ mod test {
         let func = &funcs.functions[0];
         assert_eq!(NaturalLoop::compute_loops(func).len(), 2);
     }
+
+    #[test]
+    fn test_preheader() {
+        let funcs = get_sample_functions(INVARIANT);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let loops = NaturalLoop::compute_loops(func);
+        assert_eq!(loops[0].preheader(&cfg), Some(loops[0].root - 1));
+
+        // The block before the header branches elsewhere as well.
+        let funcs = get_sample_functions(JUMP_ENTRY);
+        let func = &funcs.functions[0];
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let loops = NaturalLoop::compute_loops(func);
+        assert_eq!(loops[0].preheader(&cfg), None);
+    }
+
+    #[test]
+    fn test_same_loop() {
+        let funcs = get_sample_functions(TWO_LOOPS);
+        let func = &funcs.functions[0];
+        let loops = NaturalLoop::compute_loops(func);
+        assert_eq!(loops.len(), 2);
+        let (first, second) = (&loops[0], &loops[1]);
+
+        assert_eq!(loops_containing(first.back_edge, &loops), vec![first]);
+        assert!(loops_containing(func.entry_block, &loops).is_empty());
+        assert!(same_loop(first.root, first.back_edge, &loops));
+        assert!(!same_loop(first.back_edge, second.back_edge, &loops));
+        assert!(!same_loop(first.back_edge, func.entry_block, &loops));
+    }
+
+    #[test]
+    fn test_enclosing_loops() {
+        let funcs = get_sample_functions(NESTED_LOOPS);
+        let func = &funcs.functions[0];
+        let loops = NaturalLoop::compute_loops(func);
+        assert_eq!(loops.len(), 2);
+        let outer = loops.iter().max_by_key(|nl| nl.nodes.len()).unwrap();
+        let inner = loops.iter().min_by_key(|nl| nl.nodes.len()).unwrap();
+        assert!(outer.nodes.contains(&inner.root));
+
+        assert_eq!(enclosing_loops(inner, &loops), vec![outer, inner]);
+        assert_eq!(enclosing_loops(outer, &loops), vec![outer]);
+    }
+
+    #[test]
+    fn test_nesting_forest() {
+        let funcs = get_sample_functions(NESTED_LOOPS);
+        let func = &funcs.functions[0];
+        let mut loops = NaturalLoop::compute_loops(func);
+        let (outer, inner) = if loops[0].nodes.len() > loops[1].nodes.len() { (0, 1) } else { (1, 0) };
+        let forest = NaturalLoop::nesting_forest(&loops);
+        assert_eq!(forest, BTreeMap::from([(outer, vec![inner]), (inner, vec![])]));
+
+        // Another back edge to the inner header is merged into the inner loop.
+        loops.push(NaturalLoop { back_edge: loops[inner].root, ..loops[inner].clone() });
+        assert_eq!(NaturalLoop::nesting_forest(&loops), forest);
+
+        let funcs = get_sample_functions(TWO_LOOPS);
+        let loops = NaturalLoop::compute_loops(&funcs.functions[0]);
+        assert_eq!(NaturalLoop::nesting_forest(&loops), BTreeMap::from([(0, vec![]), (1, vec![])]));
+    }
+
+    #[test]
+    fn test_irreducible() {
+        for sample in [INVARIANT, JUMP_ENTRY, NESTED_LOOPS, TWO_LOOPS, PRIME] {
+            assert!(is_reducible(&get_sample_functions(sample).functions[0]));
+        }
+
+        // The cycle between blocks 1 and 2 is entered at both of them.
+        let funcs = get_sample_functions(IRREDUCIBLE);
+        let func = &funcs.functions[0];
+        assert!(!is_reducible(func));
+        assert_eq!(irreducible_headers(func), [1, 2].into());
+    }
 }
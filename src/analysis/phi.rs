@@ -3,28 +3,37 @@ use std::collections::{BTreeMap, BTreeSet};
 use depile::ir::{Block, Function, Instr};
 use depile::ir::instr::basic::Operand;
 use depile::ir::instr::basic::Operand::Var;
-use depile::ir::instr::{BranchKind, InstrExt};
-use depile::ir::instr::stripped::Functions;
+use depile::ir::instr::{BranchKind, HasOperand, InstrExt};
+use depile::ir::instr::stripped::{Functions, InterProc};
+use displaydoc::Display as DisplayDoc;
+use thiserror::Error;
 use crate::to_isize;
-use crate::analysis::cfg::SimpleCfg;
+use crate::analysis::cfg::{SimpleCfg, successors};
 use crate::ir::converter::block_convert;
 use crate::ir::panning::{Pannable, PannableBlock};
-use crate::analysis::dom_frontier::compute_df_cfg;
-use crate::analysis::domtree::{BlockMap, BlockSet, compute_domtree, compute_idom, ImmDomRel, root_of_domtree};
+use crate::analysis::dom_frontier::{compute_df_idom, iterated_dominance_frontier};
+use crate::analysis::liveness::compute_var_liveness;
+use crate::analysis::domtree::{BlockMap, BlockSet, compute_idom_lt, ImmDomRel};
 use crate::ir::params::scan_parameters;
-use crate::ssa::{Phi, SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAInterProc, SSAOpd};
+use crate::ssa::{Phi, SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAInterProc, SSAOpd, SSAValues};
 
 /// Find all the variable definitions in `block`.
 pub fn find_defs<K: InstrExt>(block: &Block<K>) -> BTreeSet<String>
     where K::Operand: HasVariableOperand {
-    let mut vars = BTreeSet::new();
-    for instr in block.instructions.iter() {
-        match instr {
-            Instr::Move { source: _, dest: dst }  => if dst.is_var() { vars.insert(dst.unwrap()); }
-            _ => { }
-        }
+    block.instructions.iter().filter_map(defined_var).collect()
+}
+
+/// The variable `instr` assigns to, if any.
+///
+/// `Move` is the only instruction with a named destination: arithmetic,
+/// `load` and `read` produce registers, and `store` writes to the memory its
+/// address points to, even if the address is held in a variable.
+pub fn defined_var<K: InstrExt>(instr: &Instr<K>) -> Option<String>
+    where K::Operand: HasVariableOperand {
+    match instr {
+        Instr::Move { source: _, dest } => dest.get_var_name(),
+        _ => None,
     }
-    vars
 }
 
 /// Indicates a variable can be got from this operand.
@@ -75,14 +84,75 @@ pub type BlockPhiCells = BTreeMap<usize, BTreeMap<String, PhiCell>>;
 pub struct PhiForge {
     pub params: Vec<String>,
     pub cfg: SimpleCfg,
-    pub domtree: BlockMap,
     pub imm_doms: ImmDomRel,
     pub dom_frontier: BlockMap,
     pub phi_cells: BlockPhiCells,
+    /// Order of variables by their first definitions in the function, which
+    /// decides the order of phi nodes in a block.
+    pub var_order: BTreeMap<String, usize>,
+    /// Log the rename stacks during renaming into `rename_log`.
+    pub explain: bool,
+    /// Build pruned SSA: place phi nodes only where the variable is live.
+    pub pruned: bool,
+    pub rename_log: Vec<String>,
+    /// Version memory as the variable [`MEMORY`], see [`PhiForge::run_with_memory`].
+    pub memory: bool,
+    pub memory_versions: MemoryVersions,
 }
 
+/// Name of the variable standing for the whole memory in memory SSA.
+pub const MEMORY: &str = "@mem";
+
+/// Mapping the index of each `load` to the version of [`MEMORY`] it reads,
+/// and of each `store` or call to the version it defines.
+pub type MemoryVersions = BTreeMap<usize, SSAOpd>;
+
 impl PhiForge {
     pub fn run(funcs: &Functions) -> (SSAFunctions, Vec<Vec<String>>) {
+        PhiForge::try_run(funcs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Build SSA for `funcs` like [`PhiForge::run`], but returns an error
+    /// instead of panicking if a variable name collides with the subscripts.
+    pub fn try_run(funcs: &Functions) -> Result<(SSAFunctions, Vec<Vec<String>>), PhiError> {
+        let (res, params, _, _) = PhiForge::run_with(funcs, |_| ())?;
+        Ok((res, params))
+    }
+
+    /// Build SSA for `funcs` like [`PhiForge::run`], and also returns a log of
+    /// the rename stacks on entry to and exit from each node of the dominator
+    /// tree during renaming.
+    pub fn run_explained(funcs: &Functions) -> (SSAFunctions, Vec<Vec<String>>, Vec<String>) {
+        PhiForge::try_run_explained(funcs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as [`PhiForge::run_explained`], but returns an error instead of
+    /// panicking, see [`PhiForge::try_run`].
+    pub fn try_run_explained(funcs: &Functions) -> Result<(SSAFunctions, Vec<Vec<String>>, Vec<String>), PhiError> {
+        let (res, params, log, _) = PhiForge::run_with(funcs, |forge| forge.explain = true)?;
+        Ok((res, params, log))
+    }
+
+    /// Build pruned SSA for `funcs`, where phi nodes of variables not live
+    /// at the block are left out.
+    pub fn run_pruned(funcs: &Functions) -> (SSAFunctions, Vec<Vec<String>>) {
+        let (res, params, _, _) = PhiForge::run_with(funcs, |forge| forge.pruned = true)
+            .unwrap_or_else(|e| panic!("{}", e));
+        (res, params)
+    }
+
+    /// Build SSA for `funcs` with memory versioned as the variable [`MEMORY`],
+    /// which every `store` (or call) defines and every `load` uses. Phi nodes
+    /// of memory are placed like those of other variables, and the versions
+    /// of memory at loads and stores of each function are also returned.
+    pub fn run_with_memory(funcs: &Functions) -> (SSAFunctions, Vec<Vec<String>>, Vec<MemoryVersions>) {
+        let (res, params, _, mems) = PhiForge::run_with(funcs, |forge| forge.memory = true)
+            .unwrap_or_else(|e| panic!("{}", e));
+        (res, params, mems)
+    }
+
+    fn run_with(funcs: &Functions, setup: impl Fn(&mut PhiForge))
+        -> Result<(SSAFunctions, Vec<Vec<String>>, Vec<String>, Vec<MemoryVersions>), PhiError> {
         fn count_instructions(func: &SSAFunction) -> usize {
             func.blocks.iter().fold(0, |x, block| x + block.instructions.len())
         }
@@ -90,40 +160,59 @@ impl PhiForge {
         let mut curr_idx: usize = 0;
         let mut res = Vec::new();
         let mut params = Vec::new();
+        let mut log = Vec::new();
+        let mut mems = Vec::new();
 
-        for func in &funcs.functions {
+        for (i, func) in funcs.functions.iter().enumerate() {
             curr_idx = max(curr_idx, func.blocks[0].first_index);
-            let (func_res, params_res) = PhiForge::run_func(&func, curr_idx);
+            let (func_res, forge) = PhiForge::run_func(&func, curr_idx, &setup)?;
             curr_idx += count_instructions(&func_res);
             res.push(func_res);
-            params.push(params_res);
+            params.push(forge.params);
+            if forge.explain {
+                log.push(format!("Function #{}:", i));
+                log.extend(forge.rename_log);
+            }
+            mems.push(forge.memory_versions);
         }
 
-        ( SSAFunctions { functions: res, entry_function: funcs.entry_function }, params )
+        Ok(( SSAFunctions { functions: res, entry_function: funcs.entry_function }, params, log, mems ))
     }
 
-    fn run_func(func: &Function, instr_idx: usize) -> (SSAFunction, Vec<String>) {
+    fn run_func(func: &Function, instr_idx: usize, setup: &impl Fn(&mut PhiForge)) -> Result<(SSAFunction, PhiForge), PhiError> {
+        check_var_names(func)?;
         let mut forge = PhiForge::new(func);
+        setup(&mut forge);
         forge.infer_phi(func);
         forge.top_down_domtree();
         let mut func_phi = forge.place_phi_placeholder(func, instr_idx);
         forge.place_phi(&mut func_phi);
         forge.rename_phi(&mut func_phi);
-        (func_phi, forge.params)
+        debug_assert!(duplicate_subscripts(&func_phi).is_empty(),
+                      "variables defined more than once: {:?}", duplicate_subscripts(&func_phi));
+        debug_assert!(single_pred_phis(&func_phi).is_empty(),
+                      "phi nodes placed in single-predecessor blocks: {:?}", single_pred_phis(&func_phi));
+        Ok((func_phi, forge))
     }
 
     fn new(func: &Function) -> Self {
         let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
-        let domtree = compute_domtree(func);
-        let imm_doms = compute_idom(&domtree);
-        let dfs = compute_df_cfg(&domtree, &cfg);
+        // Only immediate dominators are needed, and the sets of dominators
+        // are too slow to compute for large functions.
+        let imm_doms = compute_idom_lt(func);
+        let dfs = compute_df_idom(&imm_doms, &cfg);
         Self {
             params: scan_parameters(func),
             cfg: cfg,
-            domtree: domtree,
             imm_doms: imm_doms,
             dom_frontier: dfs,
             phi_cells: BTreeMap::new(),
+            var_order: BTreeMap::new(),
+            explain: false,
+            pruned: false,
+            rename_log: Vec::new(),
+            memory: false,
+            memory_versions: BTreeMap::new(),
         }
     }
 
@@ -138,6 +227,15 @@ impl PhiForge {
             defs.insert(i, find_defs(block));
         }
 
+        self.var_order.clear();
+        for block in func.blocks.iter() {
+            for var in block.instructions.iter().filter_map(defined_var) {
+                if !self.var_order.contains_key(&var) {
+                    self.var_order.insert(var, self.var_order.len());
+                }
+            }
+        }
+
         let mut def_sites: BTreeMap<String, Vec<usize>> = BTreeMap::new();
         for (i, _) in func.blocks.iter().enumerate() {
             for var in defs.get(&i).unwrap() {
@@ -148,23 +246,35 @@ impl PhiForge {
             }
         }
 
-        // Step 3: insert phi-functions
+        if self.memory {
+            let mem = MEMORY.to_string();
+            let sites: Vec<usize> = func.blocks.iter().enumerate()
+                .filter(|(_, block)| block.instructions.iter().any(|instr|
+                    matches!(instr, Instr::Store {..} | Instr::InterProc(InterProc::Call {..}))))
+                .map(|(i, _)| i)
+                .collect();
+            if !sites.is_empty() { def_sites.insert(mem.clone(), sites); }
+            self.var_order.insert(mem, self.var_order.len());
+        }
+
+        // Step 3: insert phi-functions, only where the variable is live if pruned
+        let live_in = if self.pruned { Some(compute_var_liveness(func).0) } else { None };
         let phi_instrs = &mut self.phi_cells;
         phi_instrs.clear();
 
         for i in 0..func.blocks.len() { phi_instrs.insert(i, BTreeMap::new()); }
         for (var, bs) in def_sites.iter() {
-            let mut blocks: Vec<usize> = bs.clone();
-            while !blocks.is_empty() {
-                let b = blocks.pop().unwrap();
-
-                for df in dfs.get(&b).unwrap() {
-                    let phis = phi_instrs.get_mut(df).unwrap();
-                    if !phis.contains_key(var) {
-                        phis.insert(var.clone(), PhiCell::new(var));
-                        blocks.push(df.clone());
-                    }
-                    phis.get_mut(var).unwrap().insert(b);
+            let defs: BlockSet = bs.iter().cloned().collect();
+            let phi_blocks: BlockSet = iterated_dominance_frontier(dfs, &defs).into_iter()
+                .filter(|b| live_in.as_ref().map_or(true, |live| live.get(b).unwrap().contains(var)))
+                .collect();
+            for b in &phi_blocks {
+                phi_instrs.get_mut(b).unwrap().insert(var.clone(), PhiCell::new(var));
+            }
+            // A phi node originates from each block whose frontier it is in.
+            for b in defs.union(&phi_blocks) {
+                for df in dfs.get(b).unwrap() {
+                    if let Some(cell) = phi_instrs.get_mut(df).unwrap().get_mut(var) { cell.insert(*b); }
                 }
             }
         }
@@ -172,11 +282,20 @@ impl PhiForge {
         phi_instrs
     }
 
+    /// Variables of the phi nodes in `block_idx`, in the order of their slots.
+    /// The order follows the first definitions of variables rather than their
+    /// names, so that it is kept under a consistent renaming of variables.
+    pub fn phi_slots(&self, block_idx: usize) -> Vec<String> {
+        let mut vars: Vec<String> = self.phi_cells.get(&block_idx).unwrap().keys().cloned().collect();
+        vars.sort_by_key(|var| (self.var_order.get(var).cloned().unwrap_or(usize::MAX), var.clone()));
+        vars
+    }
+
     /// Pre-order walk over dominator tree.
     pub fn top_down_domtree(&self) -> BlockMap {
         let mut res: BlockMap = BlockMap::new();
-        for (i, _) in self.domtree.iter().enumerate() {
-            res.insert(i, BlockSet::new());
+        for i in self.imm_doms.keys() {
+            res.insert(*i, BlockSet::new());
         }
         for (i, j) in &self.imm_doms {
             if j.is_some() { res.get_mut(&j.unwrap()).unwrap().insert(*i); }
@@ -184,6 +303,15 @@ impl PhiForge {
         res
     }
 
+    /// Number of local variables in the function, i.e. the renamed variables
+    /// other than the parameters and [`MEMORY`].
+    pub fn local_var_count(&self) -> u64 {
+        let locals = self.var_order.keys()
+            .filter(|var| !self.params.contains(var) && var.as_str() != MEMORY)
+            .count();
+        locals as u64
+    }
+
     pub fn place_phi_placeholder(&self, func: &Function, instr_idx: usize) -> SSAFunction {
         let mut blocks: Vec<SSABlock> = Vec::new();
         let mut id = instr_idx;
@@ -191,6 +319,7 @@ impl PhiForge {
         for (i, b) in func.blocks.iter().enumerate() {
             let offset = id - b.first_index;
             let block = block_convert(b)
+                .expect("stripped functions have no extra instructions")
                 .pan(&|x| x + offset)
                 .panning_forward_fill(self.phi_cells.get(&i).unwrap().len());
             id += block.instructions.len();
@@ -199,7 +328,7 @@ impl PhiForge {
 
         SSAFunction {
             parameter_count: func.parameter_count,
-            local_var_count: 0, // TODO
+            local_var_count: self.local_var_count(),
             entry_block: func.entry_block,
             blocks: blocks,
         }
@@ -219,23 +348,32 @@ impl PhiForge {
         func
     }
 
-    pub fn rename_phi<'a>(&self, func: &'a mut SSAFunction) -> &'a mut SSAFunction {
+    pub fn rename_phi<'a>(&mut self, func: &'a mut SSAFunction) -> &'a mut SSAFunction {
         let mut rename_stack = RenameStack::new();
         let td_tree = self.top_down_domtree();
-        let root = root_of_domtree(&self.domtree);
+        let root = self.cfg.entry;
         for param in &self.params { rename_stack.request_push(param); }
+        // The memory on entry.
+        if self.memory { rename_stack.request_push(&MEMORY.to_string()); }
 
-        visit(self, root, func, &mut rename_stack, &td_tree);
+        let mut log = Vec::new();
+        let mut mem = MemoryVersions::new();
+        visit(self, root, func, &mut rename_stack, &td_tree, &mut log, &mut mem);
+        self.rename_log = log;
+        self.memory_versions = mem;
 
         fn visit(forge: &PhiForge,
                  block_idx: usize,
                  func: &mut SSAFunction,
                  rename_stack: &mut RenameStack,
-                 td_tree: &BlockMap) {
+                 td_tree: &BlockMap,
+                 log: &mut Vec<String>,
+                 mem: &mut MemoryVersions) {
+            if forge.explain { log.push(format!("Enter block #{}:\n{}", block_idx, rename_stack)); }
             let block: &mut SSABlock = func.blocks.get_mut(block_idx).unwrap();
 
             // Step 1: generate unique names and push them.
-            for (j, (var, _)) in forge.phi_cells.get(&block_idx).unwrap().iter().enumerate() {
+            for (j, var) in forge.phi_slots(block_idx).iter().enumerate() {
                 let var_index: usize = rename_stack.request_push(var);
                 match block.instructions.get_mut(j).unwrap() {
                     Instr::Extra(Phi {vars: _, blocks: _, dest}) =>
@@ -244,15 +382,24 @@ impl PhiForge {
                 }
             }
 
-            // Step 2: rewrite names.
-            for instr in block.instructions.iter_mut() {
+            // Step 2: rewrite names, and version memory at loads and stores.
+            let memory = MEMORY.to_string();
+            for (j, instr) in block.instructions.iter_mut().enumerate() {
                 instr.rename_by(rename_stack);
+                if !forge.memory { continue; }
+                let version = match instr {
+                    Instr::Load(_) => rename_stack.get(&memory),
+                    Instr::Store {..} | Instr::InterProc(SSAInterProc::Call {..}) => rename_stack.request_push(&memory),
+                    _ => continue,
+                };
+                mem.insert(block.first_index + j, SSAOpd::Subscribed(memory.clone(), to_isize!(version)));
             }
 
             // Step 3: fill in phi parameters of successor blocks.
-            for succ in forge.cfg.get_succs(block_idx) {
+            // Fall-through first, so that operands are filled in a stable order.
+            for succ in successors(func.blocks.as_slice(), block_idx).ordered() {
                 let succ_block = func.blocks.get_mut(succ).unwrap();
-                for (j, (var, _)) in forge.phi_cells.get(&succ).unwrap().iter().enumerate() {
+                for (j, var) in forge.phi_slots(succ).iter().enumerate() {
                     let instr = succ_block.instructions.get_mut(j).unwrap();
                     let var_idx = rename_stack.try_get(var);
                     push_phi_param(instr, var, var_idx, block_idx.try_into().unwrap());
@@ -260,13 +407,14 @@ impl PhiForge {
             }
 
             // Step 4: recurse on children.
+            // Counters are merged back for all variables, including those first
+            // seen in the child, so that siblings never reuse a subscript.
             for child in td_tree.get(&block_idx).unwrap() {
                 let mut rs = rename_stack.clone();
-                visit(forge, *child, func, &mut rs, td_tree);
-                for (var, cell) in &mut rename_stack.var_stacks {
-                    cell.counter = rs.var_stacks.get(var).unwrap().counter;
-                }
+                visit(forge, *child, func, &mut rs, td_tree, log, mem);
+                rename_stack.merge_counters(&rs);
             }
+            if forge.explain { log.push(format!("Exit block #{}:\n{}", block_idx, rename_stack)); }
         }
         func
     }
@@ -280,9 +428,9 @@ pub struct RenameStack {
 impl RenameStack {
     fn new() -> Self { RenameStack { var_stacks: BTreeMap::new() } }
 
-    fn try_get(&mut self, var: &String) -> isize {
+    fn try_get(&mut self, var: &String) -> Option<usize> {
         let cell = self.var_stack_mut(var);
-        cell.stack.last().map_or(-1, |x| to_isize!(*x))
+        cell.stack.last().copied()
     }
 
     fn get(&mut self, var: &String) -> usize {
@@ -308,6 +456,15 @@ impl RenameStack {
         cell.stack.push(var_idx);
     }
 
+    /// Keep the larger counter of each variable in `self` or `other`, without
+    /// touching the stacks. Variables only in `other` get empty stacks.
+    fn merge_counters(&mut self, other: &RenameStack) {
+        for (var, cell) in &other.var_stacks {
+            let counter = &mut self.var_stack_mut(var).counter;
+            *counter = max(*counter, cell.counter);
+        }
+    }
+
     fn var_stack_mut(&mut self, var: &String) -> &mut RenameStackCell {
         if !self.var_stacks.contains_key(var) {
             self.var_stacks.insert(var.clone(), RenameStackCell::new());
@@ -316,6 +473,15 @@ impl RenameStack {
     }
 }
 
+impl std::fmt::Display for RenameStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (var, cell) in &self.var_stacks {
+            writeln!(f, "  {}: counter = {}, stack = {:?}", var, cell.counter, cell.stack)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RenameStackCell {
     pub counter: usize,
@@ -398,10 +564,110 @@ impl Renameable for SSAInstr {
     }
 }
 
-fn push_phi_param(instr: &mut SSAInstr, var: &String, var_idx: isize, block_idx: isize) {
+/// All the phi nodes in `func`, as `(block index, instruction index, phi)`.
+pub fn phi_nodes(func: &SSAFunction) -> Vec<(usize, usize, &Phi)> {
+    let mut res = Vec::new();
+    for (i, block) in func.blocks.iter().enumerate() {
+        let mut instr_idx = block.first_index;
+        for instr in block.instructions.iter() {
+            if let Instr::Extra(phi) = instr { res.push((i, instr_idx, phi)); }
+            instr_idx += 1;
+        }
+    }
+    res
+}
+
+/// Find phi nodes placed in blocks with a single predecessor, as `(block
+/// index, destination)`. Such phi nodes are always redundant, and usually
+/// indicate a bug in computing dominance frontiers.
+pub fn single_pred_phis(func: &SSAFunction) -> Vec<(usize, SSAOpd)> {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let mut res = Vec::new();
+    for (block_idx, _, phi) in phi_nodes(func) {
+        if cfg.get_prevs(block_idx).len() == 1 { res.push((block_idx, phi.dest.clone())); }
+    }
+    res
+}
+
+/// Find subscribed variables defined more than once in `func`, which breaks
+/// the single assignment property. Usually caused by the rename counters
+/// getting out of sync between sibling blocks of the dominator tree.
+pub fn duplicate_subscripts(func: &SSAFunction) -> Vec<SSAOpd> {
+    let mut seen = BTreeSet::new();
+    let mut res = Vec::new();
+    for block in &func.blocks {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            for def in instr.defined_values(block.first_index + i) {
+                if let SSAOpd::Subscribed(_, _) = def {
+                    if !seen.insert(def.clone()) && !res.contains(&def) { res.push(def); }
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Find phi nodes with more than `max_args` arguments, as `(block index,
+/// destination, number of arguments)`. Such phi nodes often come from an
+/// unstructured CFG, and blow up later passes.
+pub fn wide_phis(func: &SSAFunction, max_args: usize) -> Vec<(usize, SSAOpd, usize)> {
+    phi_nodes(func).into_iter()
+        .filter(|(_, _, phi)| phi.vars.len() > max_args)
+        .map(|(block_idx, _, phi)| (block_idx, phi.dest.clone(), phi.vars.len()))
+        .collect()
+}
+
+/// Errors in building SSA, see [`check_var_names`], and inconsistencies
+/// between phi nodes and the CFG, see [`verify_phi_nodes`].
+#[derive(Debug, DisplayDoc, Error, Clone, Eq, PartialEq)]
+pub enum PhiError {
+    /// variable names {0:?} contain `$`, which is reserved for subscripts
+    NameCollision(Vec<String>),
+    /// phi node of {dest} in block #{block} has {vars} operands from {blocks} blocks, but {preds} predecessors
+    OperandCount { block: usize, dest: SSAOpd, vars: usize, blocks: usize, preds: usize },
+    /// phi node of {dest} in block #{block} takes operands from blocks {found:?}, but the predecessors are {expected:?}
+    Predecessors { block: usize, dest: SSAOpd, found: Vec<usize>, expected: BlockSet },
+}
+
+/// Check that no variable or parameter name in `func` contains `$`, which
+/// would make the subscribed names `v$i` ambiguous.
+pub fn check_var_names(func: &Function) -> Result<(), PhiError> {
+    let mut names = BTreeSet::new();
+    for instr in func.blocks.iter().flat_map(|block| block.instructions.iter()) {
+        let opds = instr.get_operands().into_iter().filter_map(|opd| opd.get_var_name());
+        for var in opds.chain(defined_var(instr)) {
+            if var.contains('$') { names.insert(var); }
+        }
+    }
+    if names.is_empty() { Ok(()) } else { Err(PhiError::NameCollision(names.into_iter().collect())) }
+}
+
+/// Check that each phi node in `func` has exactly one operand from each
+/// predecessor of its block. Unreachable predecessors are not counted, since
+/// renaming never visits them.
+pub fn verify_phi_nodes(func: &SSAFunction) -> Result<(), PhiError> {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let reachable = cfg.reachable_blocks();
+    for (block, _, phi) in phi_nodes(func) {
+        let expected: BlockSet = cfg.get_prevs(block).intersection(&reachable).cloned().collect();
+        let dest = phi.dest.clone();
+        if phi.vars.len() != expected.len() || phi.blocks.len() != expected.len() {
+            return Err(PhiError::OperandCount {
+                block, dest, vars: phi.vars.len(), blocks: phi.blocks.len(), preds: expected.len(),
+            });
+        }
+        let found: BlockSet = phi.blocks.iter().cloned().collect();
+        if found != expected {
+            return Err(PhiError::Predecessors { block, dest, found: phi.blocks.clone(), expected });
+        }
+    }
+    Ok(())
+}
+
+fn push_phi_param(instr: &mut SSAInstr, var: &String, var_idx: Option<usize>, block_idx: isize) {
     match instr {
         Instr::Extra(Phi {vars, blocks, dest: _}) => {
-            vars.push(SSAOpd::Subscribed(var.clone(), var_idx));
+            vars.push(var_idx.map_or(SSAOpd::Undef, |i| SSAOpd::Subscribed(var.clone(), to_isize!(i))));
             blocks.push(block_idx.try_into().unwrap());
         }
         _ => panic!("Not phi instruction."),
@@ -415,10 +681,17 @@ macro_rules! to_isize {
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeSet;
     use std::io::{ Write, BufWriter };
-    use depile::ir::Function;
-    use crate::analysis::phi::{find_defs, PhiForge};
-    use crate::samples::{ALL_SAMPLES, get_sample_functions, PRIME};
+    use depile::ir::{Function, Instr};
+    use depile::ir::instr::basic::Operand;
+    use crate::analysis::natural_loop::NaturalLoop;
+    use crate::analysis::phi::{check_var_names, defined_var, duplicate_subscripts, find_defs, MEMORY, phi_nodes, PhiError, PhiForge, RenameStack, single_pred_phis, verify_phi_nodes, wide_phis};
+    use crate::analysis::verify::check_ssa;
+    use crate::interp::interpret;
+    use crate::samples::{ALL_SAMPLES, get_sample_functions, GCD, PRIME, SIEVE};
+    use crate::samples::synthetic::{DEEP_DEF, INVARIANT, MANY_PREDS, NESTED_DEF, PRUNED_PHI, STORE_LOAD};
+    use crate::ssa::{Phi, SSAFunctions, SSAOpd};
 
     #[test]
     fn test_find_defs() {
@@ -460,4 +733,261 @@ mod test {
             write!(&mut writer, "{}", res).unwrap();
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_single_pred_phis() {
+        for str in ALL_SAMPLES {
+            let (res, _) = PhiForge::run(&get_sample_functions(str));
+            for func in &res.functions {
+                assert_eq!(single_pred_phis(func), Vec::new());
+            }
+        }
+    }
+
+    #[test]
+    fn test_phi_round_trip() {
+        for str in ALL_SAMPLES {
+            let (res, _) = PhiForge::run(&get_sample_functions(str));
+            for func in &res.functions {
+                for (_, _, phi) in phi_nodes(func) {
+                    assert_ne!(phi.dest, SSAOpd::NOpd);
+                    assert_eq!(phi.to_string().parse::<Phi>().unwrap(), *phi);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_undef_operands() {
+        let (res, _) = PhiForge::run(&get_sample_functions(GCD));
+        let vars: Vec<SSAOpd> = phi_nodes(&res.functions[0]).into_iter()
+            .flat_map(|(_, _, phi)| phi.vars.clone())
+            .collect();
+        assert!(vars.contains(&SSAOpd::Undef));
+        assert!(vars.iter().all(|opd| !matches!(opd, SSAOpd::Subscribed(_, i) if *i < 0)));
+    }
+
+    #[test]
+    fn test_wide_phis() {
+        let (res, _) = PhiForge::run(&get_sample_functions(MANY_PREDS));
+        let func = &res.functions[0];
+        let wide = wide_phis(func, 3);
+        assert_eq!(wide.len(), 1);
+        let (block_idx, dest, args) = &wide[0];
+        assert_eq!(*block_idx, func.blocks.len() - 1);
+        assert!(matches!(dest, SSAOpd::Subscribed(var, _) if var == "x"));
+        assert_eq!(*args, 4);
+        assert!(wide_phis(func, 4).is_empty());
+    }
+
+    #[test]
+    fn test_phi_slots_renaming() {
+        fn phi_vars(sample: &str) -> Vec<String> {
+            let (res, _) = PhiForge::run(&get_sample_functions(sample));
+            phi_nodes(&res.functions[0]).iter()
+                .map(|(_, _, phi)| match &phi.dest {
+                    SSAOpd::Subscribed(var, _) => var.clone(),
+                    _ => panic!("phi node not renamed"),
+                })
+                .collect()
+        }
+
+        // Reverse the alphabetical order of variables in `gcd`.
+        let renamed = GCD.replace(" a#", " z#").replace(" b#", " y#").replace(" c#", " x#");
+        let rename_back = |var: String| match var.as_str() {
+            "z" => "a".to_string(),
+            "y" => "b".to_string(),
+            "x" => "c".to_string(),
+            _ => var,
+        };
+        let origin = phi_vars(GCD);
+        assert_eq!(origin, vec!["c", "a", "b"]);
+        assert_eq!(phi_vars(&renamed).into_iter().map(rename_back).collect::<Vec<_>>(), origin);
+    }
+
+    #[test]
+    fn test_merge_counters() {
+        let (x, y) = ("x".to_string(), "y".to_string());
+        let mut parent = RenameStack::new();
+        parent.request_push(&x);
+        let mut child = parent.clone();
+        child.request_push(&x);
+        child.request_push(&y);
+        parent.merge_counters(&child);
+        assert_eq!(parent.to_string(), "  x: counter = 2, stack = [0]\n  y: counter = 1, stack = []\n");
+
+        // `z` is first defined three levels down the dominator tree.
+        let (ssa, _) = PhiForge::run(&get_sample_functions(NESTED_DEF));
+        let func = &ssa.functions[0];
+        assert_eq!(duplicate_subscripts(func), Vec::new());
+        assert_eq!(interpret(func, &[]).unwrap(), " 7 9\n");
+    }
+
+    #[test]
+    fn test_rename_stack_display() {
+        let mut rs = RenameStack::new();
+        let (x, y) = ("x".to_string(), "y".to_string());
+        rs.request_push(&x);
+        rs.request_push(&y);
+        rs.request_push(&x);
+        assert_eq!(rs.to_string(), "  x: counter = 2, stack = [0, 1]\n  y: counter = 1, stack = [0]\n");
+
+        let (_, _, log) = PhiForge::run_explained(&get_sample_functions(INVARIANT));
+        assert_eq!(log[0], "Function #0:");
+        assert!(log[1].starts_with("Enter block #0:"));
+        // `i` is defined before the loop, by the phi node, and in the loop body.
+        assert!(log.iter().any(|l| l.contains("i: counter = 3, stack = [0, 1, 2]")));
+    }
+
+    #[test]
+    fn test_memory_ssa() {
+        let funcs = get_sample_functions(SIEVE);
+        let (ssa, _, mems) = PhiForge::run_with_memory(&funcs);
+        let func = &ssa.functions[0];
+        let mems = &mems[0];
+        let mem_phis: Vec<(usize, SSAOpd)> = phi_nodes(func).into_iter()
+            .filter(|(_, _, phi)| matches!(&phi.dest, SSAOpd::Subscribed(x, _) if x == MEMORY))
+            .map(|(b, _, phi)| (b, phi.dest.clone()))
+            .collect();
+        assert!(!mem_phis.is_empty());
+
+        // Each store defines a new version of memory.
+        let stores: Vec<&SSAOpd> = func.blocks.iter()
+            .flat_map(|block| block.instructions.iter().enumerate()
+                .filter(|(_, instr)| matches!(instr, Instr::Store {..}))
+                .map(move |(j, _)| mems.get(&(block.first_index + j)).unwrap()))
+            .collect();
+        assert_eq!(stores.len(), 4);
+        assert_eq!(stores.iter().collect::<BTreeSet<_>>().len(), stores.len());
+
+        // The first load is in the outer loop of crossing out, whose inner
+        // loop stores, so it reads memory merged at the loop header.
+        let loops = NaturalLoop::compute_loops(func);
+        let (b, j) = func.blocks.iter().enumerate()
+            .find_map(|(b, block)| block.instructions.iter()
+                .position(|instr| matches!(instr, Instr::Load(_)))
+                .map(|j| (b, block.first_index + j)))
+            .unwrap();
+        let read = mems.get(&j).unwrap();
+        let header = loops.iter()
+            .filter(|nl| nl.nodes.contains(&b))
+            .map(|nl| nl.root)
+            .find(|h| mem_phis.contains(&(*h, read.clone())));
+        assert!(header.is_some(), "{} at {} is not merged at a loop header", read, j);
+        assert!(!stores.contains(&read));
+    }
+
+    #[test]
+    fn test_store_to_var_address() {
+        // `store i#-8 (9)` writes `i` to memory rather than assigning `i`.
+        let funcs = get_sample_functions(STORE_LOAD);
+        let func = &funcs.functions[0];
+        let defs: Vec<String> = func.blocks.iter()
+            .flat_map(|block| block.instructions.iter().filter_map(defined_var))
+            .collect();
+        assert_eq!(defs, vec!["i".to_string(), "i".to_string()]);
+        let all: BTreeSet<String> = func.blocks.iter().flat_map(|block| find_defs(block)).collect();
+        assert_eq!(all, BTreeSet::from(["i".to_string()]));
+
+        let (ssa, _) = PhiForge::run(&funcs);
+        let phis = phi_nodes(&ssa.functions[0]);
+        assert_eq!(phis.len(), 1);
+        assert!(matches!(&phis[0].2.dest, SSAOpd::Subscribed(x, _) if x == "i"));
+    }
+
+    #[test]
+    fn test_pruned() {
+        let funcs = get_sample_functions(PRIME);
+        let (minimal, _) = PhiForge::run(&funcs);
+        let (pruned, _) = PhiForge::run_pruned(&funcs);
+        let count = |ssa: &SSAFunctions| ssa.functions.iter().map(|f| phi_nodes(f).len()).sum::<usize>();
+        assert!(count(&pruned) < count(&minimal));
+
+        for sample in ALL_SAMPLES {
+            let funcs = get_sample_functions(sample);
+            let (pruned, _) = PhiForge::run_pruned(&funcs);
+            for func in &pruned.functions { assert_eq!(duplicate_subscripts(func), Vec::new()); }
+        }
+        let (minimal, _) = PhiForge::run(&get_sample_functions(DEEP_DEF));
+        let (pruned, _) = PhiForge::run_pruned(&get_sample_functions(DEEP_DEF));
+        assert_eq!(interpret(&pruned.functions[0], &[]).unwrap(), interpret(&minimal.functions[0], &[]).unwrap());
+    }
+
+    #[test]
+    fn test_duplicate_subscripts() {
+        // `y` is first defined in the children of the nested branches, and
+        // the siblings must not reuse the subscripts of each other.
+        let (ssa, _) = PhiForge::run(&get_sample_functions(DEEP_DEF));
+        let func = &ssa.functions[0];
+        assert_eq!(duplicate_subscripts(func), Vec::new());
+        let ys: Vec<SSAOpd> = phi_nodes(func).iter().map(|(_, _, phi)| phi.dest.clone()).collect();
+        assert_eq!(ys.len(), 2);
+        assert_ne!(ys[0], ys[1]);
+        assert_eq!(interpret(func, &[]).unwrap(), " 3 5\n");
+
+        let (ssa, _) = PhiForge::run(&get_sample_functions(PRUNED_PHI));
+        assert_eq!(duplicate_subscripts(&ssa.functions[0]), Vec::new());
+        for sample in ALL_SAMPLES {
+            let (ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            for func in &ssa.functions { assert_eq!(duplicate_subscripts(func), Vec::new()); }
+        }
+    }
+
+    #[test]
+    fn test_name_collision() {
+        let mut funcs = get_sample_functions(GCD);
+        for block in funcs.functions[0].blocks.iter_mut() {
+            for instr in block.instructions.iter_mut() {
+                if let Instr::Move {source: _, dest: Operand::Var(var, _)} = instr {
+                    if var == "c" { *var = "c$1".to_string(); }
+                }
+            }
+        }
+        assert_eq!(check_var_names(&funcs.functions[1]), Ok(()));
+        let err = PhiError::NameCollision(vec!["c$1".to_string()]);
+        assert_eq!(check_var_names(&funcs.functions[0]), Err(err.clone()));
+        assert_eq!(PhiForge::try_run(&funcs).err(), Some(err));
+    }
+
+    #[test]
+    fn test_verify_phi_nodes() {
+        for sample in ALL_SAMPLES {
+            let (ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            for func in &ssa.functions { assert_eq!(verify_phi_nodes(func), Ok(())); }
+        }
+
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let func = &mut ssa.functions[0];
+        let (block, j) = func.blocks.iter().enumerate()
+            .find_map(|(i, block)| block.instructions.iter()
+                .position(|instr| matches!(instr, Instr::Extra(_)))
+                .map(|j| (i, j)))
+            .unwrap();
+        let phi = match &mut func.blocks[block].instructions[j] {
+            Instr::Extra(phi) => phi,
+            _ => unreachable!(),
+        };
+        phi.vars.pop();
+        phi.blocks.pop();
+        assert!(matches!(verify_phi_nodes(func), Err(PhiError::OperandCount { vars: 1, blocks: 1, preds: 2, .. })));
+
+        let phi = match &mut func.blocks[block].instructions[j] {
+            Instr::Extra(phi) => phi,
+            _ => unreachable!(),
+        };
+        phi.vars.push(phi.vars[0].clone());
+        phi.blocks.push(phi.blocks[0]);
+        assert!(matches!(verify_phi_nodes(func), Err(PhiError::Predecessors { .. })));
+    }
+
+    #[test]
+    fn test_check_ssa() {
+        for sample in ALL_SAMPLES {
+            let funcs = get_sample_functions(sample);
+            let (ssa, _) = PhiForge::run(&funcs);
+            for func in &ssa.functions { assert_eq!(check_ssa(func), Ok(())); }
+            let (pruned, _) = PhiForge::run_pruned(&funcs);
+            for func in &pruned.functions { assert_eq!(check_ssa(func), Ok(())); }
+        }
+    }
+}
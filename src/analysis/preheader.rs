@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use depile::ir::Instr;
+use depile::ir::instr::{Branching, BranchKind};
+use crate::analysis::cfg::{SimpleCfg, successors};
+use crate::analysis::domtree::BlockSet;
+use crate::analysis::natural_loop::NaturalLoop;
+use crate::ir::insert_block::BlockInserter;
+use crate::ir::panning::insert_instr;
+use crate::ssa::{Phi, SSAFunction, SSAInstr, SSAOpd, SSAValues};
+
+/// Returns the preheader of `nl` in `func`, i.e. the only predecessor of the
+/// header outside the loop, falling through into the header as its only
+/// successor, so that instructions can be appended to it.
+///
+/// If there is no such block, a new one is inserted before the header. The
+/// edges from the predecessors outside the loop are redirected to the new
+/// block, while the back edges still target the header. Phi nodes in the
+/// header take the values from outside the loop from the new block, merged
+/// by new phi nodes in it if they differ.
+///
+/// A block of the loop right before the header, falling through into it,
+/// would fall into the new block instead, so it branches to the header
+/// explicitly first. If it already ends with a branch elsewhere, its
+/// fall-through edge is split by a block doing so, and more than one block
+/// is inserted before the header.
+pub fn ensure_preheader(func: &mut SSAFunction, nl: &NaturalLoop) -> usize {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    if let Some(p) = nl.preheader(&cfg) {
+        if successors(func.blocks.as_slice(), p).taken.is_none() { return p; }
+    }
+
+    let (root, nodes) = branch_to_header(func, nl);
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let shift = |b: usize| if b >= root { b + 1 } else { b };
+    let forward: BlockSet = cfg.get_prevs(root).into_iter()
+        .filter(|p| !nodes.contains(p))
+        .map(shift)
+        .collect();
+
+    let preheader = BlockInserter::run(func, root).unwrap();
+    let header = preheader + 1;
+    for (b, block) in func.blocks.iter_mut().enumerate() {
+        if let Some(Instr::Branch(branching)) = block.instructions.last_mut() {
+            if branching.dest == preheader || branching.dest == header {
+                branching.dest = if forward.contains(&b) { preheader } else { header };
+            }
+        }
+    }
+
+    let mut next = next_subscripts(func);
+    let mut merged: Vec<SSAInstr> = Vec::new();
+    for instr in func.blocks[header].instructions.iter_mut() {
+        if let Instr::Extra(Phi {vars, blocks, dest: SSAOpd::Subscribed(var, _)}) = instr {
+            let (outside, inside): (Vec<_>, Vec<_>) = vars.iter().cloned().zip(blocks.iter().cloned())
+                .partition(|(_, b)| forward.contains(b));
+            if outside.is_empty() { continue; }
+            let value = if outside.iter().all(|(v, _)| *v == outside[0].0) {
+                outside[0].0.clone()
+            } else {
+                let i = next.entry(var.clone()).or_insert(0);
+                let dest = SSAOpd::Subscribed(var.clone(), *i);
+                *i += 1;
+                merged.push(Instr::Extra(Phi {
+                    vars: outside.iter().map(|(v, _)| v.clone()).collect(),
+                    blocks: outside.iter().map(|(_, b)| *b).collect(),
+                    dest: dest.clone(),
+                }));
+                dest
+            };
+            *vars = inside.iter().map(|(v, _)| v.clone()).chain([value]).collect();
+            *blocks = inside.iter().map(|(_, b)| *b).chain([preheader]).collect();
+        }
+    }
+    for (offset, phi) in merged.into_iter().enumerate() {
+        insert_instr(func, preheader, offset, phi);
+    }
+    preheader
+}
+
+/// Make the block of `nl` right before its header, if it falls through into
+/// the header, branch to the header instead. Returns the header and the
+/// blocks of the loop afterwards, as splitting the fall-through edge shifts
+/// the header by the new block.
+fn branch_to_header(func: &mut SSAFunction, nl: &NaturalLoop) -> (usize, BlockSet) {
+    let root = nl.root;
+    if root == 0 || !nl.nodes.contains(&(root - 1)) { return (root, nl.nodes.clone()); }
+    let succs = successors(func.blocks.as_slice(), root - 1);
+    if succs.fallthrough != Some(root) { return (root, nl.nodes.clone()); }
+
+    let (latch, root, nodes) = match succs.taken {
+        None => (root - 1, root, nl.nodes.clone()),
+        Some(_) => {
+            let new = BlockInserter::split_edge(func, root - 1, root);
+            let nodes = nl.nodes.iter()
+                .map(|b| if *b >= root { b + 1 } else { *b })
+                .chain([new])
+                .collect();
+            (new, root + 1, nodes)
+        }
+    };
+    let branch = Instr::Branch(Branching { method: BranchKind::Unconditional, dest: root });
+    insert_instr(func, latch, func.blocks[latch].instructions.len(), branch);
+    (root, nodes)
+}
+
+/// The next unused subscript of each variable in `func`.
+fn next_subscripts(func: &SSAFunction) -> BTreeMap<String, isize> {
+    let mut res: BTreeMap<String, isize> = BTreeMap::new();
+    for block in &func.blocks {
+        for (j, instr) in block.instructions.iter().enumerate() {
+            for def in instr.defined_values(block.first_index + j) {
+                if let SSAOpd::Subscribed(var, i) = def {
+                    let next = res.entry(var).or_insert(0);
+                    *next = (*next).max(i + 1);
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::cfg::SimpleCfg;
+    use crate::analysis::natural_loop::NaturalLoop;
+    use crate::analysis::phi::PhiForge;
+    use crate::analysis::preheader::ensure_preheader;
+    use crate::interp::interpret;
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::{FALLTHROUGH_LATCH, INVARIANT, JUMP_ENTRY, LATCH_EXIT, TWO_ENTRIES};
+
+    #[test]
+    fn test_reuse_preheader() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(INVARIANT));
+        let func = &mut ssa.functions[0];
+        let count = func.blocks.len();
+        let nl = &NaturalLoop::compute_loops(func)[0];
+        assert_eq!(ensure_preheader(func, nl), nl.root - 1);
+        assert_eq!(func.blocks.len(), count);
+    }
+
+    #[test]
+    fn test_back_edge() {
+        for (sample, inputs) in [(JUMP_ENTRY, vec![0]), (TWO_ENTRIES, vec![0, 1])] {
+            let (mut ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            let func = &mut ssa.functions[0];
+            let expected: Vec<_> = inputs.iter().map(|i| interpret(func, &[*i])).collect();
+
+            let nl = NaturalLoop::compute_loops(func)[0].clone();
+            let preheader = ensure_preheader(func, &nl);
+            let header = preheader + 1;
+            let latch = nl.back_edge + 1;
+            let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+            assert_eq!(cfg.get_succs(preheader), [header].into());
+            assert_eq!(cfg.get_prevs(header), [preheader, latch].into());
+            assert!(matches!(func.blocks[latch].instructions.last(), Some(Instr::Branch(b)) if b.dest == header));
+            assert_eq!(inputs.iter().map(|i| interpret(func, &[*i])).collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fallthrough_latch() {
+        // The loop body is placed right before the header and falls through
+        // into it, either as its only successor or besides an exit.
+        for (sample, inserted) in [(FALLTHROUGH_LATCH, 1), (LATCH_EXIT, 2)] {
+            let (mut ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            let func = &mut ssa.functions[0];
+            let expected = interpret(func, &[]).unwrap();
+            let count = func.blocks.len();
+
+            let nl = NaturalLoop::compute_loops(func)[0].clone();
+            assert_eq!(nl.back_edge, nl.root - 1);
+            let preheader = ensure_preheader(func, &nl);
+            let header = preheader + 1;
+            assert_eq!(func.blocks.len(), count + inserted);
+            assert_eq!(header, nl.root + inserted);
+
+            let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+            let latch = preheader - 1;
+            assert_eq!(cfg.get_succs(preheader), [header].into());
+            assert_eq!(cfg.get_prevs(header), [preheader, latch].into());
+            assert!(!cfg.get_succs(latch).contains(&preheader));
+            assert!(matches!(func.blocks[latch].instructions.last(), Some(Instr::Branch(b)) if b.dest == header));
+            assert_eq!(interpret(func, &[]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_merge_entries() {
+        // `i` comes from either of the two entries of the loop.
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(TWO_ENTRIES));
+        let func = &mut ssa.functions[0];
+        let nl = NaturalLoop::compute_loops(func)[0].clone();
+        let preheader = ensure_preheader(func, &nl);
+        let phis: Vec<String> = func.blocks[preheader].instructions.iter().map(|instr| instr.to_string()).collect();
+        assert_eq!(phis.len(), 1);
+        assert!(phis[0].starts_with("i$"));
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use crate::analysis::cfg::SimpleCfg;
+use crate::analysis::natural_loop::NaturalLoop;
+use crate::analysis::phi::phi_nodes;
+use crate::analysis::versions::version_counts;
+use crate::ssa::{SSAFunction, SSAFunctions};
+
+/// Summary statistics of a program in SSA form.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ProgramStats {
+    pub functions: usize,
+    pub blocks: usize,
+    /// Number of instructions, not counting `nop`s.
+    pub instructions: usize,
+    pub phis: usize,
+    /// Number of subscribed versions of all variables.
+    pub versions: usize,
+}
+
+impl ProgramStats {
+    pub fn from(funcs: &SSAFunctions) -> Self {
+        let mut stats = ProgramStats::default();
+        for func in &funcs.functions {
+            stats.functions += 1;
+            stats.blocks += func.blocks.len();
+            stats.instructions += count_instructions(func);
+            stats.phis += phi_nodes(func).len();
+            stats.versions += version_counts(func).values().sum::<usize>();
+        }
+        stats
+    }
+}
+
+impl Display for ProgramStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Number of functions: {}", self.functions)?;
+        writeln!(f, "  Number of blocks: {}", self.blocks)?;
+        writeln!(f, "  Number of instructions: {}", self.instructions)?;
+        writeln!(f, "  Number of phi nodes: {}", self.phis)?;
+        writeln!(f, "  Number of SSA versions: {}", self.versions)
+    }
+}
+
+/// Statistics of a function, emitted as a row of CSV.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct FunctionStats {
+    pub index: usize,
+    pub blocks: usize,
+    /// Number of instructions, not counting `nop`s.
+    pub instructions: usize,
+    pub phis: usize,
+    pub loops: usize,
+    /// Number of constants propagated.
+    pub const_prop: usize,
+    /// Number of statements hoisted out of loops.
+    pub hoisted: usize,
+    /// Cyclomatic complexity, i.e. `edges - blocks + 2`.
+    pub cyclomatic: usize,
+}
+
+impl FunctionStats {
+    /// Header of the CSV, matching [`FunctionStats::csv_row`].
+    pub const CSV_HEADER: &'static str =
+        "function,blocks,instructions,phis,loops,const_prop,hoisted,cyclomatic";
+
+    /// Collect the statistics of `func`, whose index is `index`. Counts of
+    /// the optimizations are left as zero.
+    pub fn from(index: usize, func: &SSAFunction) -> Self {
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let edges: usize = cfg.edges.values().map(|succs| succs.len()).sum();
+        FunctionStats {
+            index,
+            blocks: func.blocks.len(),
+            instructions: count_instructions(func),
+            phis: phi_nodes(func).len(),
+            loops: NaturalLoop::compute_loops(func).len(),
+            const_prop: 0,
+            hoisted: 0,
+            cyclomatic: (edges + 2).saturating_sub(func.blocks.len()),
+        }
+    }
+
+    pub fn csv_row(&self) -> String {
+        format!("{},{},{},{},{},{},{},{}", self.index, self.blocks, self.instructions, self.phis,
+                self.loops, self.const_prop, self.hoisted, self.cyclomatic)
+    }
+}
+
+/// Count the instructions in `func`, not counting `nop`s.
+pub fn count_instructions(func: &SSAFunction) -> usize {
+    func.blocks.iter()
+        .map(|block| block.instructions.iter().filter(|instr| !matches!(instr, Instr::Nop)).count())
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::phi::PhiForge;
+    use crate::analysis::stats::{FunctionStats, ProgramStats};
+    use crate::samples::{get_sample_functions, GCD};
+
+    #[test]
+    fn test_gcd_stats() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let stats = ProgramStats::from(&ssa);
+        assert_eq!(stats.functions, 2);
+        assert_eq!(stats.blocks, 5);
+        assert_eq!(stats.phis, 3);
+    }
+
+    #[test]
+    fn test_function_stats() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let stats = FunctionStats::from(0, &ssa.functions[0]);
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.loops, 1);
+        // 0 -> 1, 1 -> 2, 1 -> 3, 2 -> 1
+        assert_eq!(stats.cyclomatic, 2);
+        assert_eq!(stats.csv_row(), format!("0,4,{},3,1,0,0,2", stats.instructions));
+    }
+}
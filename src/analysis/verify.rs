@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use depile::ir::Instr;
+use crate::analysis::domtree::{compute_domtree, dominate};
+use crate::ssa::{Phi, SSAFunction, SSAOpd, SSAValues};
+
+/// Check that `func` is in SSA form: every subscribed variable is defined
+/// exactly once, and each use is dominated by the definition. Operands of
+/// phi nodes are used at the end of the corresponding predecessors.
+///
+/// Variables never defined in `func` are allowed with subscript `0`, i.e.
+/// parameters defined on entry. Values undefined on some path are
+/// [`SSAOpd::Undef`], which needs no definition. Returns descriptions of all
+/// the violations.
+pub fn check_ssa(func: &SSAFunction) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+
+    // Definitions as `(block, instruction index)`.
+    let mut defs: BTreeMap<SSAOpd, (usize, usize)> = BTreeMap::new();
+    for (i, block) in func.blocks.iter().enumerate() {
+        for (j, instr) in block.instructions.iter().enumerate() {
+            let instr_idx = block.first_index + j;
+            for def in instr.defined_values(instr_idx) {
+                if !matches!(def, SSAOpd::Subscribed(_, _)) { continue; }
+                if defs.insert(def.clone(), (i, instr_idx)).is_some() {
+                    errors.push(format!("{} is defined more than once", def));
+                }
+            }
+        }
+    }
+
+    let domtree = compute_domtree(func);
+    let dominates = |opd: &SSAOpd, block: usize, instr_idx: usize| match defs.get(opd) {
+        Some((b, idx)) => dominate(&domtree, *b, block) && (*b != block || *idx < instr_idx),
+        None => matches!(opd, SSAOpd::Undef | SSAOpd::Subscribed(_, 0)),
+    };
+    for (i, block) in func.blocks.iter().enumerate() {
+        for (j, instr) in block.instructions.iter().enumerate() {
+            let instr_idx = block.first_index + j;
+            let uses: Vec<(&SSAOpd, usize, usize)> = match instr {
+                Instr::Extra(Phi {vars, blocks, dest: _}) => vars.iter().zip(blocks.iter())
+                    .map(|(var, pred)| (var, *pred, usize::MAX))
+                    .collect(),
+                _ => instr.used_values().into_iter().map(|opd| (opd, i, instr_idx)).collect(),
+            };
+            for (opd, b, idx) in uses {
+                if matches!(opd, SSAOpd::Subscribed(_, _)) && !dominates(opd, b, idx) {
+                    errors.push(format!("{} is used at instruction {} without a dominating definition", opd, instr_idx));
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::phi::PhiForge;
+    use crate::analysis::verify::check_ssa;
+    use crate::samples::{get_sample_functions, GCD};
+    use crate::ssa::SSAOpd;
+
+    #[test]
+    fn test_violations() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let func = &mut ssa.functions[0];
+        assert_eq!(check_ssa(func), Ok(()));
+
+        // Redefine the destination of the first move at the end of the function.
+        let dest = func.blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .find_map(|instr| match instr {
+                Instr::Move {source: _, dest: dest @ SSAOpd::Subscribed(_, _)} => Some(dest.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let used = SSAOpd::Subscribed("unknown".to_string(), 3);
+        let last = func.blocks.last_mut().unwrap();
+        let mut instrs = std::mem::take(&mut last.instructions).into_vec();
+        instrs.insert(0, Instr::Move {source: used.clone(), dest: dest.clone()});
+        last.instructions = instrs.into_boxed_slice();
+
+        let errors = check_ssa(func).unwrap_err();
+        assert!(errors.contains(&format!("{} is defined more than once", dest)));
+        assert!(errors.iter().any(|e| e.starts_with(&format!("{} is used at instruction", used))));
+    }
+}
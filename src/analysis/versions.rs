@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use depile::ir::Instr;
+use crate::ssa::{SSAFunction, SSAFunctions, SSAOpd, SSAValues};
+
+/// All the subscribed versions of each source variable in `func`.
+pub fn all_versions(func: &SSAFunction) -> BTreeMap<String, BTreeSet<isize>> {
+    let mut res: BTreeMap<String, BTreeSet<isize>> = BTreeMap::new();
+    let mut record = |opd: &SSAOpd| match opd {
+        SSAOpd::Subscribed(var, i) =>
+            { res.entry(var.clone()).or_default().insert(*i); }
+        _ => ()
+    };
+    for block in &func.blocks {
+        let mut instr_idx = block.first_index;
+        for instr in block.instructions.iter() {
+            for opd in instr.used_values() { record(opd); }
+            for opd in instr.defined_values(instr_idx) { record(&opd); }
+            instr_idx += 1;
+        }
+    }
+    res
+}
+
+/// Number of subscribed versions of each source variable in `func`.
+pub fn version_counts(func: &SSAFunction) -> BTreeMap<String, usize> {
+    all_versions(func).into_iter().map(|(var, vs)| (var, vs.len())).collect()
+}
+
+/// Where a version of a variable is created.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VersionSite {
+    /// Never defined in the function, i.e. the value of a parameter on entry.
+    Param,
+    /// Defined by a phi node in the block.
+    Phi(usize),
+    /// Defined by an instruction, as `(block, instruction index)`.
+    Instr(usize, usize),
+}
+
+/// Versions of each source variable in `func` in definition order, with
+/// where each version is created.
+pub fn version_timeline(func: &SSAFunction) -> BTreeMap<String, Vec<(isize, VersionSite)>> {
+    let mut sites = BTreeMap::new();
+    for (b, block) in func.blocks.iter().enumerate() {
+        for (j, instr) in block.instructions.iter().enumerate() {
+            let instr_idx = block.first_index + j;
+            let site = match instr {
+                Instr::Extra(_) => VersionSite::Phi(b),
+                _ => VersionSite::Instr(b, instr_idx),
+            };
+            for def in instr.defined_values(instr_idx) { sites.insert(def, site); }
+        }
+    }
+    all_versions(func).into_iter()
+        .map(|(var, vs)| {
+            let timeline = vs.into_iter()
+                .map(|i| {
+                    let site = sites.get(&SSAOpd::Subscribed(var.clone(), i)).cloned();
+                    (i, site.unwrap_or(VersionSite::Param))
+                })
+                .collect();
+            (var, timeline)
+        })
+        .collect()
+}
+
+/// Print the version timeline of each variable in `funcs`, one line per
+/// variable, e.g. `i: i$0 (param), i$1 @ b2#5, i$2 @ phi b1`.
+pub fn format_timelines(funcs: &SSAFunctions) -> String {
+    let mut res = String::new();
+    for (i, func) in funcs.functions.iter().enumerate() {
+        writeln!(res, "Function #{}:", i).unwrap();
+        for (var, timeline) in version_timeline(func) {
+            let versions: Vec<String> = timeline.iter()
+                .map(|(v, site)| {
+                    let opd = SSAOpd::Subscribed(var.clone(), *v);
+                    match site {
+                        VersionSite::Param => format!("{} (param)", opd),
+                        VersionSite::Phi(b) => format!("{} @ phi b{}", opd, b),
+                        VersionSite::Instr(b, idx) => format!("{} @ b{}#{}", opd, b, idx),
+                    }
+                })
+                .collect();
+            writeln!(res, "  {}: {}", var, versions.join(", ")).unwrap();
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::phi::PhiForge;
+    use crate::analysis::versions::{format_timelines, version_counts, version_timeline, VersionSite};
+    use crate::samples::{get_sample_functions, GCD};
+    use crate::samples::synthetic::INVARIANT;
+
+    #[test]
+    fn test_version_counts() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(INVARIANT));
+        let counts = version_counts(&ssa.functions[0]);
+        assert!(counts.get("i").unwrap() > &1);
+        assert_eq!(counts.get("n"), Some(&1));
+    }
+
+    #[test]
+    fn test_gcd_timeline() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let timeline = version_timeline(&ssa.functions[0]);
+        assert_eq!(timeline.get("b").unwrap(),
+                   &vec![(0, VersionSite::Param), (1, VersionSite::Phi(1)), (2, VersionSite::Instr(2, 11))]);
+        assert!(format_timelines(&ssa).contains("  b: b$0 (param), b$1 @ phi b1, b$2 @ b2#11\n"));
+    }
+}
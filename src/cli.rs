@@ -1,30 +1,87 @@
 
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
 use std::path::PathBuf;
+use std::time::Instant;
 use thiserror::Error;
 use displaydoc::Display as DisplayDoc;
 use parse_display::{Display, FromStr};
 use clap::{ArgEnum, Parser};
 
-use depile::ir::{block, function, Blocks};
-use depile::ir::program::{self, display_program, read_program};
-use crate::analysis::phi::PhiForge;
-use crate::ir::converter::functions_revert;
-use crate::ir::ssa_to_aaa::SSATo3Addr;
-use crate::opt::loop_invariant::LoopInVariant;
+use depile::ir::{block, function, Blocks, Instr};
+use depile::ir::instr::stripped::{Functions, InterProc};
+use depile::ir::program::{self, display_program, read_program, Program};
+use crate::analysis::cfg::SimpleCfg;
+use crate::analysis::chains::annotate_uses;
+use crate::analysis::domtree::{compute_domtree, compute_idom, domtree_to_dot};
+use crate::analysis::natural_loop::irreducible_headers;
+use crate::analysis::phi::{PhiError, PhiForge, wide_phis};
+use crate::analysis::stats::{FunctionStats, ProgramStats};
+use crate::analysis::versions::format_timelines;
+use crate::ir::converter::{ConvertError, functions_revert};
+use crate::ir::llvm::emit_functions;
+use crate::ir::panning::panning_functions;
+use crate::ir::ssa_to_aaa::{FrameLayout, SSATo3Addr};
+use crate::opt::driver::{count_all_instructions, Driver};
+use crate::opt::pass::Pass;
+use crate::ssa::SSAFunctions;
 
 /// Entry to the command line interface.
 #[derive(Parser)]
 #[clap(author, version, about)]
 pub struct Cli {
-    /// The input three-address code source file.
+    /// The input three-address code source file, or `-` for the standard input.
     #[clap(parse(from_os_str))]
     input: PathBuf,
+    /// Write the resulting program to this file instead of the standard
+    /// output, and print reports and warnings to the standard error.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
     /// Output format.
     #[clap(short, long, arg_enum, default_value_t = Format::SSA)]
     target: Format,
     /// Optimizations.
     #[clap(short, long, arg_enum, default_value_t = OptOption::None)]
     opt: OptOption,
+    /// Verbosity: `-v` prints pass reports, `-vv` adds timings and statistics,
+    /// and `-vvv` adds the IR after each pass.
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: usize,
+    /// Maximum rounds of the optimization pipeline, repeated until no function changes.
+    #[clap(long, default_value_t = 1)]
+    max_iterations: usize,
+    /// Skip expensive passes on functions with more instructions than this.
+    #[clap(long)]
+    opt_budget: Option<usize>,
+    /// Annotate each definition in the SSA output with its uses.
+    #[clap(long)]
+    annotate_uses: bool,
+    /// Warn about phi nodes with more arguments than this.
+    #[clap(long)]
+    max_phi_args: Option<usize>,
+    /// Number the first instruction of the output with this index, instead
+    /// of keeping the indices of the input, or starting from 3 after
+    /// converting out of SSA.
+    #[clap(long)]
+    base_index: Option<usize>,
+    /// Print the totals over all the functions after optimizations, before
+    /// converting out of SSA.
+    #[clap(long)]
+    stats: bool,
+    /// Print statistics of each function as CSV, instead of the program.
+    #[clap(long)]
+    csv: bool,
+    /// Print the rename stacks while renaming variables in SSA construction.
+    #[clap(long)]
+    explain_phi: bool,
+    /// Print the versions of each variable created in SSA construction.
+    #[clap(long)]
+    versions: bool,
+    /// Process only the function with this index, together with the
+    /// functions it calls so that the output is self-contained.
+    #[clap(long)]
+    function: Option<usize>,
 }
 
 /// Supported target formats.
@@ -41,6 +98,19 @@ pub enum Format {
     Recovered,
     /// Flat 3-address after converting to SSA.
     Flatten,
+    /// LLVM-like textual SSA, for cross-checking with other tools.
+    Llvm,
+    /// Control flow graph of each function.
+    Cfg,
+    /// Control flow graph of each function in DOT, e.g. for `dot -Tpng`.
+    CfgDot,
+    /// Immediate dominator of each block, before SSA construction.
+    DomTree,
+    /// Dominator tree of each function in DOT, before SSA construction.
+    DomTreeDot,
+    /// Structured JSON of the SSA form, see [`crate::ir::json`].
+    #[cfg(feature = "serde")]
+    Json,
 }
 
 /// Supported optimizations.
@@ -51,9 +121,16 @@ pub enum OptOption {
     None,
     /// Constant propagation.
     ConstProp,
+    /// Sparse conditional constant propagation.
+    Sccp,
+    /// Copy propagation.
+    CopyProp,
+    /// Dead code elimination.
+    Dce,
     /// Loop invariant code motion.
     LoopInv,
-    /// All the optimizations.
+    /// Constant propagation, loop invariant code motion and dead code
+    /// elimination, in this order.
     All,
 }
 
@@ -71,8 +148,16 @@ pub enum Error {
     MalformedFunctions(#[from] function::Error),
     /// failed to resolve function call instructions: {0}
     CannotResolveFunctionCall(#[from] function::ResolveError),
+    /// {0}
+    CannotConvert(#[from] ConvertError),
+    /// cannot build SSA: {0}
+    CannotBuildSsa(#[from] PhiError),
     /// failed to read file: {0}
     Io(#[from] std::io::Error),
+    /// no input from the standard input
+    EmptyInput,
+    /// function #{index} is out of range, the input has {count} functions
+    FunctionOutOfRange { index: usize, count: usize },
     /// cannot format the output: {0}
     CannotFormat(#[from] std::fmt::Error),
 }
@@ -80,69 +165,528 @@ pub enum Error {
 /// Result type for the command line interface.
 pub type Result = std::result::Result<(), Error>;
 
+impl OptOption {
+    /// The passes to run for this option, in order.
+    pub fn passes(&self) -> Vec<Pass> {
+        match self {
+            OptOption::None => vec![],
+            OptOption::ConstProp => vec![Pass::ConstProp],
+            OptOption::Sccp => vec![Pass::Sccp],
+            OptOption::CopyProp => vec![Pass::CopyProp],
+            OptOption::Dce => vec![Pass::Dce],
+            OptOption::LoopInv => vec![Pass::LoopInv],
+            // Dead code elimination cleans up after the other passes.
+            OptOption::All => vec![Pass::ConstProp, Pass::LoopInv, Pass::Dce],
+        }
+    }
+}
+
 impl Cli {
     /// Run the command line interface.
     pub fn run() -> Result {
         let options: Cli = Cli::try_parse()?;
-        let contents = std::fs::read_to_string(&options.input)?;
+        options.execute(&mut std::io::stdout(), &mut std::io::stderr())
+    }
+
+    /// Group `program` into functions, keeping only the one selected by
+    /// `--function` and those it calls, if any. The selected function becomes
+    /// the entry, and calls are renumbered.
+    fn functions(&self, program: &Program) -> std::result::Result<Functions, Error> {
+        let blocks = Blocks::try_from(program)?;
+        let functions = blocks.functions()?;
+        let index = match self.function {
+            Some(index) => index,
+            None => return Ok(functions),
+        };
+        let count = functions.functions.len();
+        if index >= count { return Err(Error::FunctionOutOfRange { index, count }); }
+
+        let mut selected = vec![index];
+        let mut i = 0;
+        while i < selected.len() {
+            for instr in functions.functions[selected[i]].blocks.iter().flat_map(|b| b.instructions.iter()) {
+                if let Instr::InterProc(InterProc::Call {dest}) = instr {
+                    if !selected.contains(dest) { selected.push(*dest); }
+                }
+            }
+            i += 1;
+        }
+        selected[1..].sort();
+        let renumber: BTreeMap<usize, usize> = selected.iter().enumerate().map(|(i, f)| (*f, i)).collect();
+
+        let mut res = Vec::new();
+        for f in selected {
+            let mut func = functions.functions[f].clone();
+            for block in func.blocks.iter_mut() {
+                for instr in block.instructions.iter_mut() {
+                    if let Instr::InterProc(InterProc::Call {dest}) = instr { *dest = renumber[dest]; }
+                }
+            }
+            res.push(func);
+        }
+        Ok(Functions { functions: res, entry_function: 0 })
+    }
+
+    /// Run the command line interface with these options, writing to `out`.
+    /// With `--output`, the results go to the file, and only the reports and
+    /// warnings are written, to `err`, so that `out` is left to the results.
+    pub fn execute(&self, out: &mut impl Write, err: &mut impl Write) -> Result {
+        match self.output {
+            Some(_) => self.process(err),
+            None => self.process(out),
+        }
+    }
+
+    /// Process the input, writing the reports and warnings to `out`, as well
+    /// as the results unless they are written to the output file.
+    fn process(&self, out: &mut impl Write) -> Result {
+        let contents = self.read_input(std::io::stdin())?;
         let program = read_program(&contents)?;
 
-        match options.target {
+        match self.target {
             Format::Raw => {
-                println!("{}", display_program(&program)?);
-                return Ok(());
+                return self.write_result(out, display_program(&program)?);
             }
             Format::Functions => {
-                let blocks = Blocks::try_from(program.as_ref())?;
-                let functions = blocks.functions()?;
-                println!("{}", functions);
-                return Ok(());
+                let functions = self.functions(&program)?;
+                return self.write_result(out, functions);
+            }
+            Format::DomTree | Format::DomTreeDot => {
+                let functions = self.functions(&program)?;
+                let mut res = String::new();
+                for (i, func) in functions.functions.iter().enumerate() {
+                    let idoms = compute_idom(&compute_domtree(func));
+                    if self.target == Format::DomTreeDot {
+                        res += &format!("// function #{}\n{}", i, domtree_to_dot(&idoms));
+                        continue;
+                    }
+                    res += &format!("function #{}:\n", i);
+                    for (b, idom) in &idoms {
+                        match idom {
+                            Some(d) => res += &format!("  {} <- {}\n", b, d),
+                            None => res += &format!("  {} <- none\n", b),
+                        }
+                    }
+                }
+                return self.write_result(out, res);
             }
             _ => ()
         }
 
-        let blocks = Blocks::try_from(program.as_ref())?;
-        let functions = blocks.functions()?;
-        let (mut ssa, params) = PhiForge::run(&functions);
-
-        match options.opt {
-            OptOption::ConstProp => {
-                let reports = crate::opt::const_prop::ConstProp::run(&mut ssa);
-                println!("Report of constant propagation: ");
-                for r in reports { println!("{}", r); }
-            }
-            OptOption::LoopInv => {
-                let reports = LoopInVariant::run(&mut ssa);
-                println!("Report of loop invariant: ");
-                for r in reports { println!("{}", r); }
-            }
-            OptOption::All => {
-                let reports = crate::opt::const_prop::ConstProp::run(&mut ssa);
-                println!("Report of constant propagation: ");
-                for r in reports { println!("{}", r); }
-                let reports = LoopInVariant::run(&mut ssa);
-                println!("Report of loop invariant: ");
-                for r in reports { println!("{}", r); }
+        let functions = self.functions(&program)?;
+        let start = Instant::now();
+        let (mut ssa, params, log) = if self.explain_phi {
+            PhiForge::try_run_explained(&functions)?
+        } else {
+            let (ssa, params) = PhiForge::try_run(&functions)?;
+            (ssa, params, Vec::new())
+        };
+        self.trace(out, "SSA construction", start, &ssa)?;
+        for line in log { writeln!(out, "{}", line)?; }
+        if self.versions { write!(out, "{}", format_timelines(&ssa))?; }
+        if let Some(max_args) = self.max_phi_args {
+            for (i, func) in ssa.functions.iter().enumerate() {
+                for (block_idx, dest, args) in wide_phis(func, max_args) {
+                    writeln!(out, "Warning: phi node of {} in block #{} of function #{} has {} arguments",
+                             dest, block_idx, i, args)?;
+                }
             }
-            _ => ()
         }
 
-        match options.target {
+        if self.opt.passes().contains(&Pass::LoopInv) {
+            for (i, func) in ssa.functions.iter().enumerate() {
+                let headers = irreducible_headers(func);
+                if !headers.is_empty() {
+                    writeln!(out, "Warning: function #{} has irreducible loops at blocks {:?}", i, headers)?;
+                }
+            }
+        }
+
+        let mut driver = Driver::new(self.opt.passes(), self.max_iterations);
+        driver.dump = self.verbose >= 3;
+        driver.budget = self.opt_budget;
+        let initial = count_all_instructions(&ssa);
+        let (rounds, runs) = driver.run(&mut ssa);
+        let removed = initial as isize - count_all_instructions(&ssa) as isize;
+        let mut counts = vec![(0, 0); ssa.functions.len()];
+        for run in &runs {
+            for (i, report) in run.reports.iter().enumerate() {
+                match run.pass {
+                    Pass::ConstProp | Pass::Sccp => counts[i].0 += report.opt_count,
+                    Pass::LoopInv => counts[i].1 += report.opt_count,
+                    Pass::CopyProp | Pass::Dce => (),
+                }
+            }
+        }
+        for run in runs {
+            if self.verbose >= 1 {
+                writeln!(out, "Report of {}: ", run.pass)?;
+                for r in run.reports { writeln!(out, "{}", r)?; }
+            }
+            if self.verbose >= 2 {
+                writeln!(out, "Time of {}: {:?}", run.pass, run.elapsed)?;
+            }
+            if let Some(ir) = run.ir {
+                writeln!(out, "IR after {}: ", run.pass)?;
+                writeln!(out, "{}", ir)?;
+            }
+        }
+        if self.verbose >= 1 && !self.opt.passes().is_empty() {
+            writeln!(out, "Instructions removed: {}", removed)?;
+        }
+        if self.verbose >= 2 && rounds > 1 {
+            writeln!(out, "Rounds of optimization: {}", rounds)?;
+        }
+
+        if self.verbose >= 2 {
+            writeln!(out, "Statistics: ")?;
+            writeln!(out, "{}", ProgramStats::from(&ssa))?;
+        }
+
+        if self.stats {
+            let stats = ProgramStats::from(&ssa);
+            writeln!(out, "Total blocks: {}", stats.blocks)?;
+            writeln!(out, "Total instructions: {}", stats.instructions)?;
+            writeln!(out, "Total phi nodes: {}", stats.phis)?;
+            if !self.opt.passes().is_empty() {
+                writeln!(out, "Total instructions removed: {}", removed)?;
+                writeln!(out, "Total constants folded: {}", counts.iter().map(|c| c.0).sum::<usize>())?;
+                writeln!(out, "Total statements hoisted: {}", counts.iter().map(|c| c.1).sum::<usize>())?;
+            }
+        }
+
+        if self.csv {
+            let mut rows = vec![FunctionStats::CSV_HEADER.to_string()];
+            for (i, func) in ssa.functions.iter().enumerate() {
+                let mut stats = FunctionStats::from(i, func);
+                stats.const_prop = counts[i].0;
+                stats.hoisted = counts[i].1;
+                rows.push(stats.csv_row());
+            }
+            return self.write_result(out, rows.join("\n"));
+        }
+
+        if let Some(base) = self.base_index {
+            panning_functions(&mut ssa, base);
+        }
+        match self.target {
+            Format::SSA if self.annotate_uses => {
+                self.write_result(out, annotate_uses(&ssa))?
+            }
             Format::SSA => {
-                println!("{}", ssa)
+                self.write_result(out, ssa)?
+            }
+            Format::Llvm => {
+                self.write_result(out, emit_functions(&ssa))?
+            }
+            #[cfg(feature = "serde")]
+            Format::Json => {
+                self.write_result(out, crate::ir::json::to_json(&ssa))?
+            }
+            Format::Cfg | Format::CfgDot => {
+                let graphs: Vec<String> = ssa.functions.iter().map(|func| {
+                    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+                    let name = format!("function {}", func.blocks[0].first_index);
+                    match self.target {
+                        Format::CfgDot => cfg.to_dot(&name),
+                        _ => format!("{}:\n{}", name, cfg),
+                    }
+                }).collect();
+                self.write_result(out, graphs.join("\n"))?
             }
             Format::Recovered => {
-                SSATo3Addr::run(&mut ssa, &params);
-                println!("{}", ssa)
+                SSATo3Addr::run_with(&mut ssa, &params, self.layout());
+                self.write_result(out, ssa)?
             }
             Format::Flatten => {
-                SSATo3Addr::run(&mut ssa, &params);
-                let funcs = functions_revert(&ssa);
+                SSATo3Addr::run_with(&mut ssa, &params, self.layout());
+                let funcs = functions_revert(&ssa)?;
                 let new_prog = funcs.destruct().flatten();
-                println!("{}", display_program(&new_prog)?)
+                self.write_result(out, display_program(&new_prog)?)?
             }
             _ => ()
         }
         Ok(())
     }
+
+    /// Frame layout of the program converted out of SSA.
+    fn layout(&self) -> FrameLayout {
+        let default = FrameLayout::default();
+        FrameLayout { first_index: self.base_index.unwrap_or(default.first_index), ..default }
+    }
+
+    /// Write the resulting `text` to the output file if any, or to `out`.
+    fn write_result(&self, out: &mut impl Write, text: impl std::fmt::Display) -> Result {
+        match &self.output {
+            Some(path) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                writeln!(file, "{}", text)?;
+                file.flush()?;
+            }
+            None => writeln!(out, "{}", text)?,
+        }
+        Ok(())
+    }
+
+    /// Read the source from the input file, or from `stdin` if the input is `-`.
+    pub fn read_input(&self, mut stdin: impl Read) -> std::result::Result<String, Error> {
+        if self.input.as_os_str() != "-" {
+            return Ok(std::fs::read_to_string(&self.input)?);
+        }
+        let mut contents = String::new();
+        stdin.read_to_string(&mut contents)?;
+        if contents.trim().is_empty() { return Err(Error::EmptyInput); }
+        Ok(contents)
+    }
+
+    /// Print the time spent in `stage` since `start` with `-vv`, and the
+    /// resulting IR with `-vvv`.
+    fn trace(&self, out: &mut impl Write, stage: &str, start: Instant, ssa: &SSAFunctions) -> Result {
+        if self.verbose >= 2 {
+            writeln!(out, "Time of {}: {:?}", stage, start.elapsed())?;
+        }
+        if self.verbose >= 3 {
+            writeln!(out, "IR after {}: ", stage)?;
+            writeln!(out, "{}", ssa)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use crate::analysis::phi::PhiForge;
+    use crate::cli::{Cli, Error, OptOption};
+    use crate::opt::driver::Driver;
+    use crate::opt::pass::Pass;
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::INVARIANT;
+
+    /// Run the command line interface with `args`, and returns the output.
+    pub fn run_cli(args: &[&str]) -> String {
+        run_cli_streams(args).0
+    }
+
+    /// Same as [`run_cli`], returning both the output and the error output.
+    pub fn run_cli_streams(args: &[&str]) -> (String, String) {
+        let options = Cli::try_parse_from(args).unwrap();
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        options.execute(&mut out, &mut err).unwrap();
+        (String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-o", "all", "-v"]);
+        assert!(out.contains("Report of constant propagation"));
+        assert!(out.contains("Report of dead code elimination"));
+        assert!(out.contains("  Number of statement removed: "));
+        assert!(!out.contains("Time of"));
+        assert!(!out.contains("IR after"));
+
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-o", "all", "-vv"]);
+        assert!(out.contains("Time of loop invariant"));
+        assert!(out.contains("Number of phi nodes"));
+        assert!(!out.contains("IR after"));
+
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-o", "all", "-vvv"]);
+        assert!(out.contains("IR after SSA construction"));
+        assert!(out.contains("IR after constant propagation"));
+        assert!(out.contains("IR after loop invariant"));
+    }
+
+    #[test]
+    fn test_annotate_uses() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "--annotate-uses"]);
+        assert!(out.contains("instr 10: mod c$1 b$1    ; used at 11\n"));
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt"]);
+        assert!(!out.contains("; used at"));
+    }
+
+    #[test]
+    fn test_csv() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-o", "all", "--csv"]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "function,blocks,instructions,phis,loops,const_prop,hoisted,cyclomatic");
+        assert_eq!(lines.len(), 3);
+        for (i, line) in lines[1..].iter().enumerate() {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields.len(), 8);
+            assert_eq!(fields[0], i.to_string());
+            assert!(fields.iter().all(|f| f.parse::<usize>().is_ok()));
+        }
+    }
+
+    #[test]
+    fn test_explain_phi() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "--explain-phi"]);
+        assert!(out.contains("Enter block #1:"));
+        assert!(out.contains("Exit block #1:"));
+        assert!(out.contains("  a: counter = "));
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt"]);
+        assert!(!out.contains("Enter block"));
+    }
+
+    #[test]
+    fn test_llvm() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-t", "llvm"]);
+        assert!(out.contains("define @f0() {"));
+        assert!(out.contains("define @f1() {"));
+        assert!(out.contains(" = phi ["));
+    }
+
+    #[test]
+    fn test_instructions_removed() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(INVARIANT));
+        let (_, runs) = Driver::new(OptOption::All.passes(), 1).run(&mut ssa);
+        let total: isize = runs.iter().map(|run| run.removed).sum();
+
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-o", "all", "-v"]);
+        assert!(out.contains(&format!("Instructions removed: {}\n", total)));
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-o", "all"]);
+        assert!(!out.contains("Instructions removed"));
+    }
+
+    #[test]
+    fn test_sccp() {
+        let out = run_cli(&["forgessa", "samples/synthetic/pruned_phi.txt", "-o", "sccp", "-v"]);
+        assert!(out.contains("Report of sparse conditional constant propagation"));
+        assert!(out.contains("  Edges pruned: [("));
+    }
+
+    #[test]
+    fn test_irreducible_warning() {
+        let out = run_cli(&["forgessa", "samples/synthetic/irreducible.txt", "-o", "all"]);
+        assert!(out.contains("Warning: function #0 has irreducible loops at blocks {1, 2}\n"));
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-o", "all"]);
+        assert!(!out.contains("irreducible"));
+    }
+
+    #[test]
+    fn test_stdin() {
+        let options = Cli::try_parse_from(&["forgessa", "-"]).unwrap();
+        let source = std::fs::read_to_string("samples/synthetic/invariant.txt").unwrap();
+        assert_eq!(options.read_input(source.as_bytes()).unwrap(), source);
+        assert!(matches!(options.read_input(&b"\n"[..]), Err(Error::EmptyInput)));
+
+        let options = Cli::try_parse_from(&["forgessa", "samples/synthetic/invariant.txt"]).unwrap();
+        assert_eq!(options.read_input(&b""[..]).unwrap(), source);
+    }
+
+    #[test]
+    fn test_output_file() {
+        let path = std::env::temp_dir().join("forgessa_test_output.txt");
+        let path_str = path.to_str().unwrap();
+        for target in ["ssa", "flatten"] {
+            let expected = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-t", target]);
+            let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-t", target, "--output", path_str]);
+            assert!(out.is_empty());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        }
+
+        let (out, err) = run_cli_streams(&["forgessa", "samples/synthetic/invariant.txt", "-o", "all", "-v", "--output", path_str]);
+        assert!(out.is_empty());
+        assert!(err.contains("Report of"));
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("Report of"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cfg() {
+        let first = get_sample_functions(INVARIANT).functions[0].blocks[0].first_index;
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-t", "cfg"]);
+        assert!(out.starts_with(&format!("function {}:\nCFG: entry 0\n", first)));
+        assert!(out.contains("  1 -> 2\n"));
+
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-t", "cfg-dot"]);
+        assert!(out.starts_with(&format!("digraph \"function {}\" {{\n", first)));
+        assert!(out.contains("  b1 -> b2;\n"));
+    }
+
+    #[test]
+    fn test_domtree() {
+        let out = run_cli(&["forgessa", "samples/3-addr/prime.txt", "-t", "dom-tree"]);
+        assert!(out.starts_with("function #0:\n  0 <- none\n"));
+        assert!(out.contains("  8 <- 4\n"));
+
+        let out = run_cli(&["forgessa", "samples/3-addr/prime.txt", "-t", "dom-tree-dot"]);
+        assert!(out.starts_with("// function #0\ndigraph domtree {\n"));
+        assert!(out.contains("  b4 -> b8;\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_json() {
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "-t", "json"]);
+        assert!(out.starts_with("{\n  \"functions\": ["));
+        assert!(out.contains("\"op\": \"phi\""));
+    }
+
+    #[test]
+    fn test_stats() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "--stats", "-t", "flatten"]);
+        assert!(out.contains("Total blocks: 5\n"));
+        assert!(out.contains("Total phi nodes: 3\n"));
+        assert!(!out.contains("Total instructions removed"));
+
+        let out = run_cli(&["forgessa", "samples/synthetic/invariant.txt", "--stats", "-o", "all"]);
+        assert!(out.contains("Total instructions removed: "));
+        assert!(out.contains("Total statements hoisted: "));
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt"]);
+        assert!(!out.contains("Total"));
+    }
+
+    #[test]
+    fn test_function() {
+        // Function #3 calls itself and function #2.
+        let args = ["forgessa", "samples/3-addr/hanoifibfac.txt", "--function", "3"];
+        let out = run_cli(&[&args[..], &["-t", "functions"]].concat());
+        assert!(out.starts_with("(ENTRY) Function #0:"));
+        assert!(out.contains("Function #1:") && !out.contains("Function #2:"));
+        assert!(out.contains("call [0]") && out.contains("call [1]") && !out.contains("call [2]"));
+        assert!(run_cli(&[&args[..], &["-t", "flatten", "-o", "all"]].concat()).contains("call [1]"));
+
+        let options = Cli::try_parse_from(&["forgessa", "samples/3-addr/hanoifibfac.txt", "--function", "6"]).unwrap();
+        let res = options.execute(&mut Vec::new(), &mut Vec::new());
+        assert!(matches!(res, Err(Error::FunctionOutOfRange { index: 6, count: 6 })));
+    }
+
+    /// Indices of the instructions in the textual SSA `out`.
+    fn instr_indices(out: &str) -> Vec<usize> {
+        out.lines()
+            .filter_map(|line| line.trim_start().strip_prefix("instr "))
+            .map(|line| line.split(':').next().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_base_index() {
+        for target in ["ssa", "recovered"] {
+            let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-t", target]);
+            let based = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "-t", target, "--base-index", "0"]);
+            let (indices, based_indices) = (instr_indices(&out), instr_indices(&based));
+            assert_eq!(based_indices[0], 0);
+            let shift = indices[0];
+            assert_eq!(based_indices, indices.iter().map(|i| i - shift).collect::<Vec<_>>());
+            // Registers are shifted as well, while branches refer to blocks.
+            let registers = |out: &str| -> Vec<usize> {
+                out.split('(').skip(1).filter_map(|s| s.split(')').next().unwrap().parse().ok()).collect()
+            };
+            assert_eq!(registers(&based), registers(&out).iter().map(|r| r - shift).collect::<Vec<_>>());
+            let branches = |out: &str| -> Vec<String> {
+                out.split('[').skip(1).map(|s| s.split(']').next().unwrap().to_string()).collect()
+            };
+            assert_eq!(branches(&based), branches(&out));
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt", "--versions"]);
+        assert!(out.contains("  b: b$0 (param), b$1 @ phi b1, b$2 @ b2#11\n"));
+        let out = run_cli(&["forgessa", "samples/3-addr/gcd.txt"]);
+        assert!(!out.contains("(param)"));
+    }
 }
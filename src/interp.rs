@@ -0,0 +1,215 @@
+//! A minimal interpreter for [`SSAFunction`]s, used to check that
+//! transformations preserve the output of a program.
+//!
+//! Memory is a sparse map from addresses to values. Base addresses such as
+//! `a_base#32760` evaluate to their offsets, and other operands never
+//! assigned (e.g. `GP`) evaluate to zero. Function calls are not supported.
+
+use std::collections::{BTreeMap, VecDeque};
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use depile::ir::instr::BranchKind;
+use displaydoc::Display as DisplayDoc;
+use thiserror::Error;
+use crate::analysis::cfg::successors;
+use crate::ssa::{Phi, SSAFunction, SSAInstr, SSAOpd};
+
+/// Errors during interpretation.
+#[derive(Debug, DisplayDoc, Error, Clone, Eq, PartialEq)]
+pub enum InterpError {
+    /// use of undefined value {0}
+    Undefined(SSAOpd),
+    /// no operand of phi node for predecessor {0:?}
+    MissingPhiOperand(Option<usize>),
+    /// unsupported instruction: {0}
+    Unsupported(String),
+    /// no more input for read
+    NoInput,
+    /// division by zero
+    DivisionByZero,
+    /// exceeded the limit of {0} steps
+    StepLimit(usize),
+}
+
+/// Interpreter state for a single function.
+#[derive(Debug, Clone, Default)]
+pub struct Interpreter {
+    regs: BTreeMap<usize, i64>,
+    vars: BTreeMap<SSAOpd, i64>,
+    memory: BTreeMap<i64, i64>,
+    input: VecDeque<i64>,
+    /// Text written by `write` and `wrl`.
+    pub output: String,
+    /// Maximum number of instructions executed before giving up.
+    pub max_steps: usize,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter { max_steps: 1_000_000, ..Default::default() }
+    }
+
+    /// Values returned by `read`, in order.
+    pub fn with_input(mut self, input: &[i64]) -> Self {
+        self.input = input.iter().cloned().collect();
+        self
+    }
+
+    /// Run `func` from its entry block until it leaves the last block or
+    /// returns, and returns the output.
+    pub fn run(&mut self, func: &SSAFunction) -> Result<&str, InterpError> {
+        let mut steps = 0;
+        let mut prev: Option<usize> = None;
+        let mut curr = Some(func.entry_block);
+        while let Some(block_idx) = curr {
+            let block = &func.blocks[block_idx];
+
+            // phi nodes at the head of a block are evaluated simultaneously.
+            let mut phi_values = Vec::new();
+            for instr in block.instructions.iter() {
+                if let Instr::Extra(Phi {vars, blocks, dest}) = instr {
+                    let var = blocks.iter().position(|b| Some(*b) == prev)
+                        .map(|i| &vars[i])
+                        .ok_or(InterpError::MissingPhiOperand(prev))?;
+                    // undefined operands leave the destination undefined.
+                    if let SSAOpd::Undef = var { continue; }
+                    phi_values.push((dest.clone(), self.value(var)?));
+                }
+            }
+            for (dest, value) in phi_values { self.assign(&dest, value); }
+
+            let mut taken = None;
+            let mut instr_idx = block.first_index;
+            for instr in block.instructions.iter() {
+                steps += 1;
+                if steps > self.max_steps { return Err(InterpError::StepLimit(self.max_steps)); }
+                taken = self.step(instr, instr_idx)?;
+                instr_idx += 1;
+            }
+
+            prev = curr;
+            curr = match taken {
+                Some(dest) => Some(dest),
+                None => successors(func.blocks.as_slice(), block_idx).fallthrough,
+            };
+        }
+        Ok(&self.output)
+    }
+
+    /// Execute a non-phi instruction at `instr_idx`, and returns the
+    /// destination if a branch is taken.
+    fn step(&mut self, instr: &SSAInstr, instr_idx: usize) -> Result<Option<usize>, InterpError> {
+        match instr {
+            Instr::Binary {op, lhs, rhs} => {
+                let res = eval_binary(&op.to_string(), self.value(lhs)?, self.value(rhs)?)?;
+                self.regs.insert(instr_idx, res);
+            }
+            Instr::Unary {op, operand} => {
+                let res = eval_unary(&op.to_string(), self.value(operand)?)?;
+                self.regs.insert(instr_idx, res);
+            }
+            Instr::Branch(branching) => {
+                let taken = match &branching.method {
+                    BranchKind::Unconditional => true,
+                    BranchKind::If(opd) => self.value(opd)? & 1 != 0,
+                    BranchKind::Unless(opd) => self.value(opd)? & 1 == 0,
+                };
+                if taken { return Ok(Some(branching.dest)); }
+            }
+            Instr::Load(address) => {
+                let address = self.value(address)?;
+                let value = self.memory.get(&address).cloned().unwrap_or(0);
+                self.regs.insert(instr_idx, value);
+            }
+            Instr::Store {data, address} => {
+                let (data, address) = (self.value(data)?, self.value(address)?);
+                self.memory.insert(address, data);
+            }
+            Instr::Move {source, dest} => {
+                let value = self.value(source)?;
+                self.assign(dest, value);
+            }
+            Instr::Read => {
+                let value = self.input.pop_front().ok_or(InterpError::NoInput)?;
+                self.regs.insert(instr_idx, value);
+            }
+            Instr::Write(opd) => {
+                let value = self.value(opd)?;
+                self.output.push_str(&format!(" {}", value));
+            }
+            Instr::WriteLn => self.output.push('\n'),
+            Instr::InterProc(_) =>
+                return Err(InterpError::Unsupported(instr.to_string())),
+            Instr::Nop | Instr::Marker(_) | Instr::Extra(_) => (),
+        }
+        Ok(None)
+    }
+
+    fn value(&self, opd: &SSAOpd) -> Result<i64, InterpError> {
+        match opd {
+            SSAOpd::Operand(Operand::Const(i)) => Ok(*i),
+            SSAOpd::Operand(Operand::Register(r)) =>
+                self.regs.get(r).cloned().ok_or_else(|| InterpError::Undefined(opd.clone())),
+            SSAOpd::Undef => Err(InterpError::Undefined(opd.clone())),
+            SSAOpd::Subscribed(_, _) | SSAOpd::NOpd =>
+                self.vars.get(opd).cloned().ok_or_else(|| InterpError::Undefined(opd.clone())),
+            SSAOpd::Operand(_) => Ok(self.vars.get(opd).cloned().unwrap_or_else(|| {
+                let s = opd.to_string();
+                s.rsplit_once('#').and_then(|(_, offset)| offset.parse().ok()).unwrap_or(0)
+            })),
+        }
+    }
+
+    fn assign(&mut self, dest: &SSAOpd, value: i64) {
+        match dest {
+            SSAOpd::Operand(Operand::Register(r)) => { self.regs.insert(*r, value); }
+            _ => { self.vars.insert(dest.clone(), value); }
+        }
+    }
+}
+
+/// Evaluate the binary operator with mnemonic `op` on `l` and `r`.
+pub fn eval_binary(op: &str, l: i64, r: i64) -> Result<i64, InterpError> {
+    Ok(match op {
+        "add" => l.wrapping_add(r),
+        "sub" => l.wrapping_sub(r),
+        "mul" => l.wrapping_mul(r),
+        "div" => l.checked_div(r).ok_or(InterpError::DivisionByZero)?,
+        "mod" => l.checked_rem(r).ok_or(InterpError::DivisionByZero)?,
+        "cmpeq" => (l == r) as i64,
+        "cmple" => (l <= r) as i64,
+        "cmplt" => (l < r) as i64,
+        _ => return Err(InterpError::Unsupported(op.to_string())),
+    })
+}
+
+/// Evaluate the unary operator with mnemonic `op` on `v`.
+pub fn eval_unary(op: &str, v: i64) -> Result<i64, InterpError> {
+    match op {
+        "neg" => Ok(v.wrapping_neg()),
+        _ => Err(InterpError::Unsupported(op.to_string())),
+    }
+}
+
+/// Run `func` with `input`, and returns its output.
+pub fn interpret(func: &SSAFunction, input: &[i64]) -> Result<String, InterpError> {
+    let mut interp = Interpreter::new().with_input(input);
+    interp.run(func).map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::{INVARIANT, REDUCTION};
+
+    #[test]
+    fn test_interpret() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(INVARIANT));
+        assert_eq!(interpret(&ssa.functions[0], &[]).unwrap(), " 14".repeat(10) + "\n");
+
+        let (ssa, _) = PhiForge::run(&get_sample_functions(REDUCTION));
+        assert_eq!(interpret(&ssa.functions[0], &[]).unwrap(), "\n");
+    }
+}
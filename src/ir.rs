@@ -3,3 +3,7 @@ pub mod converter;
 pub mod panning;
 pub mod ssa_to_aaa;
 pub mod params;
+pub mod lcssa;
+pub mod llvm;
+#[cfg(feature = "serde")]
+pub mod json;
@@ -1,58 +1,93 @@
-use depile::ir::{Block, Functions};
+use depile::ir::{Block, Functions, Instr};
 use depile::ir::instr::stripped::{Function, Kind};
-use crate::ssa::{SSABlock, SSAFunction, SSAFunctions, SSAInstr};
+use displaydoc::Display as DisplayDoc;
+use thiserror::Error;
+use crate::ssa::{SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAOpd, SSAValues};
+
+/// Errors converting between the stripped form and SSA.
+#[derive(Debug, DisplayDoc, Error, Clone, Eq, PartialEq)]
+pub enum ConvertError {
+    /// cannot convert extra instruction {0} to SSA form
+    ExtraInstr(usize),
+    /// cannot revert subscribed operand {0} to stripped form
+    SubscribedOperand(SSAOpd),
+    /// cannot revert unknown operand at instruction {0} to stripped form
+    UnknownOperand(usize),
+    /// cannot revert phi node at instruction {0} to stripped form
+    PhiNode(usize),
+}
 
 /// Convert a block with kind `Stripped` to `SSAKind` straight forward.
-pub fn block_convert(block: &Block<Kind>) -> SSABlock {
+pub fn block_convert(block: &Block<Kind>) -> Result<SSABlock, ConvertError> {
     let mut instrs: Vec<SSAInstr> = Vec::new();
-    for instr in block.instructions.iter() {
+    for (j, instr) in block.instructions.iter().enumerate() {
+        if let Instr::Extra(_) = instr { return Err(ConvertError::ExtraInstr(block.first_index + j)); }
         instrs.push(instr.clone().map_kind(
             convert::map_operand,
             convert::map_branching,
             convert::map_inter_proc,
             std::convert::identity,
-            |_| panic!(""),
+            |_| unreachable!("extra instructions are rejected above"),
         ))
     }
-    SSABlock { first_index: block.first_index, instructions: instrs.into_boxed_slice() }
+    Ok(SSABlock { first_index: block.first_index, instructions: instrs.into_boxed_slice() })
 }
 
-pub fn functions_revert(funcs: &SSAFunctions) -> Functions<Kind> {
+pub fn functions_revert(funcs: &SSAFunctions) -> Result<Functions<Kind>, ConvertError> {
     let mut funcs_ = Vec::new();
     for func in &funcs.functions {
-        funcs_.push(func_revert(func));
+        funcs_.push(func_revert(func)?);
     }
-    Functions {
+    Ok(Functions {
         functions: funcs_,
         entry_function: funcs.entry_function,
-    }
+    })
 }
 
-pub fn func_revert(func: &SSAFunction) -> Function {
+pub fn func_revert(func: &SSAFunction) -> Result<Function, ConvertError> {
     let mut blocks = Vec::new();
     for block in &func.blocks {
-        blocks.push(block_revert(block));
+        blocks.push(block_revert(block)?);
     }
-    Function {
+    Ok(Function {
         parameter_count: func.parameter_count,
         local_var_count: func.local_var_count,
         entry_block: func.entry_block,
         blocks: blocks,
-    }
+    })
 }
 
-pub fn block_revert(block: &SSABlock) -> Block<Kind> {
+/// Revert a block to kind `Stripped`, which is only possible after phi nodes
+/// are removed and subscribed variables are lowered, e.g. by
+/// [`SSATo3Addr`](crate::ir::ssa_to_aaa::SSATo3Addr).
+pub fn block_revert(block: &SSABlock) -> Result<Block<Kind>, ConvertError> {
     let mut instrs = Vec::new();
-    for instr in block.instructions.iter() {
+    for (j, instr) in block.instructions.iter().enumerate() {
+        check_revert(instr, block.first_index + j)?;
         instrs.push(instr.clone().map_kind(
             revert::map_operand,
             revert::map_branching,
             revert::map_inter_proc,
             std::convert::identity,
-            |_| panic!(""),
+            |_| unreachable!("phi nodes are rejected by `check_revert`"),
         ))
     }
-    Block { first_index: block.first_index, instructions: instrs.into_boxed_slice() }
+    Ok(Block { first_index: block.first_index, instructions: instrs.into_boxed_slice() })
+}
+
+/// Check that `instr` at `instr_idx` has a stripped form.
+fn check_revert(instr: &SSAInstr, instr_idx: usize) -> Result<(), ConvertError> {
+    if let Instr::Extra(_) = instr { return Err(ConvertError::PhiNode(instr_idx)); }
+    let mut opds = instr.used_values();
+    if let Instr::Move {source: _, dest} = instr { opds.push(dest); }
+    for opd in opds {
+        match opd {
+            SSAOpd::Operand(_) => (),
+            SSAOpd::Subscribed(_, _) => return Err(ConvertError::SubscribedOperand(opd.clone())),
+            SSAOpd::Undef | SSAOpd::NOpd => return Err(ConvertError::UnknownOperand(instr_idx)),
+        }
+    }
+    Ok(())
 }
 
 ///
@@ -96,7 +131,7 @@ mod revert {
     pub fn map_operand(opd: SSAOpd) -> Operand {
         match opd {
             SSAOpd::Operand(opd) => opd,
-            _ => panic!(""),
+            _ => unreachable!("operands are checked by `check_revert`"),
         }
     }
 
@@ -126,14 +161,23 @@ mod revert {
 #[cfg(test)]
 mod test {
     use depile::ir::Function;
-    use crate::ir::converter::block_convert;
-    use crate::samples::{get_sample_functions, PRIME};
+    use crate::analysis::phi::PhiForge;
+    use crate::ir::converter::{block_convert, ConvertError, func_revert};
+    use crate::samples::{get_sample_functions, GCD, PRIME};
 
     #[test]
     fn test_convert() {
         let funcs = get_sample_functions(PRIME);
         let func: &Function = &funcs.functions[0];
-        let block = block_convert(&func.blocks[0]);
+        let block = block_convert(&func.blocks[0]).unwrap();
         println!("{}", block);
     }
+
+    #[test]
+    fn test_revert_subscribed() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let err = func_revert(&ssa.functions[0]).unwrap_err();
+        assert!(matches!(err, ConvertError::SubscribedOperand(_) | ConvertError::PhiNode(_)));
+        assert!(err.to_string().starts_with("cannot revert"));
+    }
 }
@@ -1,33 +1,105 @@
 use depile::ir::Instr;
-use depile::ir::instr::Branching;
-use crate::ir::panning::panning_function;
+use depile::ir::instr::{Branching, BranchKind};
+use crate::analysis::cfg::successors;
+use displaydoc::Display as DisplayDoc;
+use thiserror::Error;
+use crate::ir::panning::{panning_function, unpanned_blocks};
 use crate::ssa::{Phi, SSABlock, SSAFunction, SSAInstr};
 
+/// Errors inserting blocks, see [`BlockInserter::insert_many`].
+#[derive(Debug, DisplayDoc, Error, Clone, Eq, PartialEq)]
+pub enum InsertError {
+    /// cannot insert a block at {index} into a function with {blocks} blocks
+    OutOfRange { index: usize, blocks: usize },
+    /// cannot insert more than one block at {0}
+    Duplicate(usize),
+}
+
 pub struct BlockInserter {
-    pub insert_idx: usize,
+    /// Indices of the blocks to insert a block before, in ascending order.
+    /// The number of blocks appends a block at the end.
+    pub positions: Vec<usize>,
 }
 
 impl BlockInserter {
-    pub fn new(idx: usize) -> Self { BlockInserter { insert_idx: idx } }
+    pub fn new(idx: usize) -> Self { BlockInserter { positions: vec![idx] } }
+
+    /// Insert an empty block before block `insert_idx`, and returns the index
+    /// of the inserted block.
+    pub fn run(func: &mut SSAFunction, insert_idx: usize) -> Result<usize, InsertError> {
+        Ok(BlockInserter::insert_many(func, &[insert_idx])?[0])
+    }
 
-    pub fn run(func: &mut SSAFunction, insert_idx: usize) {
-        BlockInserter::new(insert_idx).modify_function(func);
+    /// Insert an empty block before each block in `positions` at once, and
+    /// returns the indices of the inserted blocks, in the order of
+    /// `positions`. Branches to a position from the blocks before it go to
+    /// the inserted block, like the fall-through does.
+    pub fn insert_many(func: &mut SSAFunction, positions: &[usize]) -> Result<Vec<usize>, InsertError> {
+        let mut sorted = positions.to_vec();
+        sorted.sort();
+        for (i, p) in sorted.iter().enumerate() {
+            if *p > func.blocks.len() {
+                return Err(InsertError::OutOfRange { index: *p, blocks: func.blocks.len() });
+            }
+            if i > 0 && sorted[i - 1] == *p { return Err(InsertError::Duplicate(*p)); }
+        }
+
+        let inserter = BlockInserter { positions: sorted };
+        inserter.modify_function(func);
         *func = panning_function(func, func.blocks[0].first_index).0;
+        debug_assert!(unpanned_blocks(func).is_empty(), "blocks not panned: {:?}", unpanned_blocks(func));
+        Ok(positions.iter().map(|p| inserter.inserted(*p)).collect())
+    }
+
+    /// Split the edge from block `from` to block `to` with a new block, and
+    /// returns the index of the new block. Phi nodes in `to` take the values
+    /// from the new block instead of `from`.
+    ///
+    /// A fall-through edge is split by a block inserted right before `to`,
+    /// and a branch edge by a block appended to the function, branching to
+    /// `to`, so that no other edge passes through the new block.
+    pub fn split_edge(func: &mut SSAFunction, from: usize, to: usize) -> usize {
+        let first_index = func.blocks[0].first_index;
+        if successors(func.blocks.as_slice(), from).taken != Some(to) {
+            let new = BlockInserter::run(func, to).unwrap();
+            // Branches to `to` from the blocks before it are redirected to
+            // the new block, but only the fall-through from `from` should be.
+            for block in func.blocks.iter_mut() {
+                for instr in block.instructions.iter_mut() {
+                    if let Instr::Branch(branching) = instr {
+                        if branching.dest == new { branching.dest = new + 1; }
+                    }
+                }
+            }
+            helper::replace_pred(&mut func.blocks[new + 1], from, new);
+            new
+        } else {
+            let new = func.blocks.len();
+            let branch = Instr::Branch(Branching { method: BranchKind::Unconditional, dest: to });
+            func.blocks.push(SSABlock { first_index: 0, instructions: vec![branch].into_boxed_slice() });
+            if let Some(Instr::Branch(branching)) = func.blocks[from].instructions.last_mut() {
+                branching.dest = new;
+            }
+            helper::replace_pred(&mut func.blocks[to], from, new);
+            *func = panning_function(func, first_index).0;
+            new
+        }
     }
 
     pub fn modify_function(&self, func:&mut SSAFunction) {
         let mut blocks = Vec::new();
+        let empty = || SSABlock { first_index: 0, instructions: Vec::new().into_boxed_slice() };
 
         for (i, block) in func.blocks.iter_mut().enumerate() {
-            if self.insert_idx == i {
-                blocks.push(SSABlock { first_index: 0, instructions: Vec::new().into_boxed_slice() })
-            }
+            if self.positions.contains(&i) { blocks.push(empty()); }
             self.modify_block(block, i);
             blocks.push(block.clone());
         }
+        if self.positions.contains(&func.blocks.len()) { blocks.push(empty()); }
 
         func.blocks = blocks;
-        if func.entry_block > self.insert_idx { func.entry_block += 1; }
+        // A block inserted before the entry becomes the entry.
+        func.entry_block = self.inserted(func.entry_block);
     }
 
     pub fn modify_block(&self, block:&mut SSABlock, block_idx: usize) {
@@ -39,34 +111,153 @@ impl BlockInserter {
     pub fn modify_instr(&self, instr: &mut SSAInstr, block_idx: usize) {
         match instr {
             Instr::Branch(Branching {method: _, dest}) =>
-                *dest = helper::modify(block_idx, self.insert_idx, *dest),
+                *dest = if self.positions.contains(dest) && block_idx < *dest {
+                    self.inserted(*dest)
+                } else {
+                    self.shifted(*dest)
+                },
             Instr::Extra(Phi {vars: _, blocks, dest: _}) =>
                 for block in blocks {
-                    *block = if *block > self.insert_idx { *block + 1 } else { *block };
+                    *block = self.shifted(*block);
                 }
             _ => ()
         }
     }
+
+    /// The index of the block inserted at `position`.
+    fn inserted(&self, position: usize) -> usize {
+        position + self.positions.iter().filter(|p| **p < position).count()
+    }
+
+    /// The index of block `block_idx` after insertion.
+    fn shifted(&self, block_idx: usize) -> usize {
+        block_idx + self.positions.iter().filter(|p| **p <= block_idx).count()
+    }
 }
 
 mod helper {
-    pub fn modify(block_idx: usize, insert_idx: usize, dest: usize) -> usize {
-        if dest < insert_idx || dest == insert_idx && block_idx < insert_idx { dest }
-        else { dest + 1 }
+    use depile::ir::Instr;
+    use crate::ssa::{Phi, SSABlock};
+
+    /// Make the phi nodes in `block` take the values from `pred` from `new`.
+    pub fn replace_pred(block: &mut SSABlock, pred: usize, new: usize) {
+        for instr in block.instructions.iter_mut() {
+            if let Instr::Extra(Phi {vars: _, blocks, dest: _}) = instr {
+                for block in blocks.iter_mut() {
+                    if *block == pred { *block = new; }
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::analysis::phi::{PhiForge};
-    use crate::ir::insert_block::BlockInserter;
+    use crate::analysis::cfg::SimpleCfg;
+    use crate::analysis::phi::{PhiForge, phi_nodes};
+    use crate::interp::interpret;
+    use crate::analysis::cfg::invalid_branches;
+    use crate::ir::insert_block::{BlockInserter, InsertError};
+    use crate::ssa::SSAFunction;
     use crate::samples::{get_sample_functions, PRIME};
+    use crate::samples::synthetic::{CRITICAL_EDGE, PRUNED_PHI};
 
     #[test]
     fn test_insert() {
         let funcs = get_sample_functions(PRIME);
         let (mut ssa, _) = PhiForge::run(&funcs);
-        BlockInserter::run(&mut ssa.functions[0], 3);
+        BlockInserter::run(&mut ssa.functions[0], 3).unwrap();
         println!("{}", ssa);
+
+        let count = ssa.functions[0].blocks.len();
+        assert_eq!(BlockInserter::run(&mut ssa.functions[0], count + 1),
+                   Err(InsertError::OutOfRange { index: count + 1, blocks: count }));
+        assert_eq!(ssa.functions[0].blocks.len(), count);
+        assert_eq!(BlockInserter::run(&mut ssa.functions[0], count), Ok(count));
+        assert_eq!(ssa.functions[0].blocks.len(), count + 1);
+    }
+
+    #[test]
+    fn test_insert_many() {
+        let funcs = get_sample_functions(PRIME);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let origin = &ssa.functions[0];
+        let mut func = origin.clone();
+        assert_eq!(BlockInserter::insert_many(&mut func, &[5, 2]), Ok(vec![6, 2]));
+        assert_eq!(func.blocks.len(), origin.blocks.len() + 2);
+        assert!(func.blocks[2].instructions.is_empty());
+        assert!(func.blocks[6].instructions.is_empty());
+        assert!(invalid_branches(&func).is_empty());
+
+        // Blocks, with their branches and phi nodes, are shifted by the blocks inserted before them.
+        let shifted = |b: usize| b + usize::from(b >= 2) + usize::from(b >= 5);
+        let cfg = SimpleCfg::from(origin.entry_block, origin.blocks.as_slice());
+        let new_cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        for (b, block) in origin.blocks.iter().enumerate() {
+            assert_eq!(func.blocks[shifted(b)].instructions.len(), block.instructions.len());
+            for s in cfg.get_succs(b) {
+                let target = if (s == 2 || s == 5) && b < s { if s == 2 { 2 } else { 6 } } else { shifted(s) };
+                assert!(new_cfg.get_succs(shifted(b)).contains(&target), "{} -> {}", b, s);
+            }
+        }
+        let preds = |func: &SSAFunction| -> Vec<Vec<usize>> {
+            phi_nodes(func).iter().map(|(_, _, phi)| phi.blocks.clone()).collect()
+        };
+        let expected: Vec<Vec<usize>> = preds(origin).iter().map(|bs| bs.iter().map(|b| shifted(*b)).collect()).collect();
+        assert_eq!(preds(&func), expected);
+
+        assert_eq!(BlockInserter::insert_many(&mut func, &[1, 1]), Err(InsertError::Duplicate(1)));
+    }
+
+    #[test]
+    fn test_split_edge() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(CRITICAL_EDGE));
+        let func = &mut ssa.functions[0];
+        let expected = [interpret(func, &[0]).unwrap(), interpret(func, &[1]).unwrap()];
+        assert_eq!(expected, [" 2\n", " 3\n"]);
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        // Both a fall-through and a branch edge are critical.
+        let critical = cfg.critical_edges();
+        assert_eq!(critical.len(), 2);
+
+        let count = func.blocks.len();
+        for _ in 0..critical.len() {
+            let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+            let (from, to) = cfg.critical_edges()[0];
+            let new = BlockInserter::split_edge(func, from, to);
+            let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+            assert_eq!(cfg.get_prevs(new).len(), 1);
+            assert_eq!(cfg.get_succs(new).len(), 1);
+        }
+        assert_eq!(func.blocks.len(), count + critical.len());
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert!(cfg.critical_edges().is_empty());
+        for (b, _, phi) in phi_nodes(func) {
+            for pred in &phi.blocks { assert!(cfg.get_succs(*pred).contains(&b)); }
+        }
+        assert_eq!([interpret(func, &[0]).unwrap(), interpret(func, &[1]).unwrap()], expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_split_diamond() {
+        // Block 0 branches to blocks 1 and 2, which both reach the phi node of `y` in block 3.
+        let (ssa, _) = PhiForge::run(&get_sample_functions(PRUNED_PHI));
+        let origin = &ssa.functions[0];
+        let expected = interpret(origin, &[]).unwrap();
+        for (from, other) in [(1, 2), (2, 1)] {
+            let mut func = origin.clone();
+            let new = BlockInserter::split_edge(&mut func, from, 3);
+            let to = if new <= 3 { 4 } else { 3 };
+            let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+            assert_eq!(cfg.get_succs(new), [to].into());
+            assert_eq!(cfg.get_prevs(new), [from].into());
+            assert_eq!(cfg.get_succs(from), [new].into());
+            assert_eq!(cfg.get_prevs(to), [other, new].into());
+            for (b, _, phi) in phi_nodes(&func) {
+                assert_eq!(b, to);
+                assert_eq!(phi.blocks.iter().cloned().collect::<std::collections::BTreeSet<_>>(), [other, new].into());
+            }
+            assert_eq!(interpret(&func, &[]).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! JSON representation of [`SSAFunctions`], for feeding the SSA form into
+//! other tools. The schema is given by the types below, each serialized as a
+//! JSON object with the fields of the type, and is kept stable: fields are
+//! only ever added, as optional ones.
+
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use depile::ir::instr::BranchKind;
+use serde::{Deserialize, Serialize};
+use crate::ssa::{Phi, SSABlock, SSAFunction, SSAFunctions, SSAInterProc, SSAInstr, SSAOpd};
+
+/// A program: `{"functions": [...]}`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JsonProgram {
+    pub functions: Vec<JsonFunction>,
+}
+
+/// A function, with its blocks in order.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JsonFunction {
+    pub parameter_count: u64,
+    pub local_var_count: u64,
+    /// Index of the entry block in `blocks`.
+    pub entry_block: usize,
+    pub blocks: Vec<JsonBlock>,
+}
+
+/// A basic block, falling through into the next one in the function unless
+/// it ends with `br` or `ret`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JsonBlock {
+    /// Index of the block in the function, as used by branches and phi nodes.
+    pub index: usize,
+    pub instructions: Vec<JsonInstr>,
+}
+
+/// An instruction, e.g. `{"index": 8, "op": "mul", "operands": [...]}`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JsonInstr {
+    /// Index of the instruction, which is also the register of its result.
+    pub index: usize,
+    /// Mnemonic as in the textual SSA form, e.g. `add`, `blbc`, `move` or
+    /// `phi`. Markers are given by their whole text, e.g. `enter 8`.
+    pub op: String,
+    /// Operands read by the instruction, in order.
+    pub operands: Vec<JsonOperand>,
+    /// Operand written by `move` and `phi`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dest: Option<JsonOperand>,
+    /// Block branched to by a branch, or function called by `call`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<usize>,
+    /// Predecessor block of each operand of `phi`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preds: Vec<usize>,
+}
+
+/// An operand, tagged by `kind`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JsonOperand {
+    /// `{"kind": "const", "value": 2}`.
+    Const { value: i64 },
+    /// Result of the instruction at `index`: `{"kind": "register", "index": 8}`.
+    Register { index: usize },
+    /// A renamed variable: `{"kind": "var", "name": "i", "version": 1}`.
+    /// Values undefined on some path are [`JsonOperand::Undef`] instead.
+    Var { name: String, version: isize },
+    /// Any other operand by its text, e.g. `{"kind": "other", "text": "GP"}`.
+    Other { text: String },
+    /// Undefined value: `{"kind": "undef"}`.
+    Undef,
+}
+
+impl From<&SSAOpd> for JsonOperand {
+    fn from(opd: &SSAOpd) -> Self {
+        match opd {
+            SSAOpd::Operand(Operand::Const(c)) => JsonOperand::Const { value: *c },
+            SSAOpd::Operand(Operand::Register(r)) => JsonOperand::Register { index: *r },
+            SSAOpd::Subscribed(x, i) => JsonOperand::Var { name: x.clone(), version: *i },
+            SSAOpd::Undef => JsonOperand::Undef,
+            opd => JsonOperand::Other { text: opd.to_string() },
+        }
+    }
+}
+
+impl JsonInstr {
+    fn new(index: usize, op: impl ToString, operands: &[&SSAOpd]) -> Self {
+        JsonInstr {
+            index,
+            op: op.to_string(),
+            operands: operands.iter().map(|opd| JsonOperand::from(*opd)).collect(),
+            dest: None,
+            target: None,
+            preds: Vec::new(),
+        }
+    }
+
+    pub fn from(instr: &SSAInstr, index: usize) -> Self {
+        match instr {
+            Instr::Binary {op, lhs, rhs} => JsonInstr::new(index, op, &[lhs, rhs]),
+            Instr::Unary {op, operand} => JsonInstr::new(index, op, &[operand]),
+            Instr::Branch(branching) => {
+                let res = match &branching.method {
+                    BranchKind::Unconditional => JsonInstr::new(index, "br", &[]),
+                    BranchKind::If(opd) => JsonInstr::new(index, "blbs", &[opd]),
+                    BranchKind::Unless(opd) => JsonInstr::new(index, "blbc", &[opd]),
+                };
+                JsonInstr { target: Some(branching.dest), ..res }
+            }
+            Instr::Load(address) => JsonInstr::new(index, "load", &[address]),
+            Instr::Store {data, address} => JsonInstr::new(index, "store", &[data, address]),
+            Instr::Move {source, dest} =>
+                JsonInstr { dest: Some(dest.into()), ..JsonInstr::new(index, "move", &[source]) },
+            Instr::Read => JsonInstr::new(index, "read", &[]),
+            Instr::Write(opd) => JsonInstr::new(index, "write", &[opd]),
+            Instr::WriteLn => JsonInstr::new(index, "wrl", &[]),
+            Instr::InterProc(SSAInterProc::PushParam(opd)) => JsonInstr::new(index, "param", &[opd]),
+            Instr::InterProc(SSAInterProc::Call {dest}) =>
+                JsonInstr { target: Some(*dest), ..JsonInstr::new(index, "call", &[]) },
+            Instr::Nop => JsonInstr::new(index, "nop", &[]),
+            Instr::Marker(marker) => JsonInstr::new(index, marker, &[]),
+            Instr::Extra(Phi {vars, blocks, dest}) => JsonInstr {
+                dest: Some(dest.into()),
+                preds: blocks.clone(),
+                ..JsonInstr::new(index, "phi", &vars.iter().collect::<Vec<_>>())
+            },
+        }
+    }
+}
+
+impl JsonBlock {
+    pub fn from(block: &SSABlock, index: usize) -> Self {
+        JsonBlock {
+            index,
+            instructions: block.instructions.iter().enumerate()
+                .map(|(j, instr)| JsonInstr::from(instr, block.first_index + j))
+                .collect(),
+        }
+    }
+}
+
+impl From<&SSAFunction> for JsonFunction {
+    fn from(func: &SSAFunction) -> Self {
+        JsonFunction {
+            parameter_count: func.parameter_count,
+            local_var_count: func.local_var_count,
+            entry_block: func.entry_block,
+            blocks: func.blocks.iter().enumerate()
+                .map(|(index, block)| JsonBlock::from(block, index))
+                .collect(),
+        }
+    }
+}
+
+impl From<&SSAFunctions> for JsonProgram {
+    fn from(funcs: &SSAFunctions) -> Self {
+        JsonProgram { functions: funcs.functions.iter().map(JsonFunction::from).collect() }
+    }
+}
+
+/// Serialize `funcs` as pretty-printed JSON.
+pub fn to_json(funcs: &SSAFunctions) -> String {
+    serde_json::to_string_pretty(&JsonProgram::from(funcs)).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::phi::PhiForge;
+    use crate::ir::json::{JsonOperand, JsonProgram, to_json};
+    use crate::samples::{get_sample_functions, GCD};
+
+    #[test]
+    fn test_round_trip() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let program = JsonProgram::from(&ssa);
+        let parsed: JsonProgram = serde_json::from_str(&to_json(&ssa)).unwrap();
+        assert_eq!(parsed, program);
+
+        let phi = parsed.functions[0].blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .find(|instr| instr.op == "phi")
+            .unwrap();
+        assert_eq!(phi.preds.len(), phi.operands.len());
+        assert!(matches!(phi.dest, Some(JsonOperand::Var {..})));
+    }
+}
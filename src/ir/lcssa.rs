@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use depile::ir::Instr;
+use depile::ir::instr::BranchKind;
+use crate::analysis::cfg::SimpleCfg;
+use crate::analysis::domtree::{BlockSet, compute_domtree, dominate};
+use crate::analysis::natural_loop::NaturalLoop;
+use crate::ir::panning::insert_instr;
+use crate::opt::loop_invariant::loop_defs;
+use crate::ssa::{Phi, SSAFunction, SSAInstr, SSAInterProc, SSAOpd, SSAValues};
+
+/// Exits of `nl`, i.e. blocks outside the loop with a predecessor in it.
+pub fn loop_exits(cfg: &SimpleCfg, nl: &NaturalLoop) -> BlockSet {
+    nl.nodes.iter()
+        .flat_map(|n| cfg.get_succs(*n))
+        .filter(|s| !nl.nodes.contains(s))
+        .collect()
+}
+
+/// Convert `nl` in `func` into loop-closed SSA (LCSSA) form: for each
+/// variable defined in the loop and used after it, insert a phi node at the
+/// loop exit dominating the uses, and let the uses refer to the phi instead.
+///
+/// Uses not dominated by a single exit are left untouched. Registers are not
+/// considered, as they never live across blocks in the source programs.
+/// Returns the exit blocks and the destinations of the inserted phi nodes.
+pub fn to_lcssa(func: &mut SSAFunction, nl: &NaturalLoop) -> Vec<(usize, SSAOpd)> {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    let domtree = compute_domtree(func);
+    let exits = loop_exits(&cfg, nl);
+    let defs: Vec<SSAOpd> = loop_defs(func, nl).into_iter()
+        .filter(|def| matches!(def, SSAOpd::Subscribed(_, _)))
+        .collect();
+
+    // The phi nodes are only inserted at the end, so the next subscript of
+    // each variable is counted here.
+    let mut next: BTreeMap<String, isize> = BTreeMap::new();
+    let mut pending = Vec::new();
+    for def in &defs {
+        let name = match def { SSAOpd::Subscribed(name, _) => name.clone(), _ => unreachable!() };
+        for exit in &exits {
+            let covers = |b: usize| !nl.nodes.contains(&b) && dominate(&domtree, *exit, b);
+            if !has_use_in(func, def, &covers) { continue; }
+
+            let sub = next.entry(name.clone()).or_insert_with(|| max_subscript(func, &name) + 1);
+            let closed = SSAOpd::Subscribed(name.clone(), *sub);
+            *sub += 1;
+            replace_uses_in(func, def, &closed, &covers);
+            pending.push((*exit, def.clone(), closed));
+        }
+    }
+
+    let mut inserted = Vec::new();
+    for (exit, def, closed) in pending {
+        let blocks: Vec<usize> = cfg.get_prevs(exit).into_iter().collect();
+        // `def` is not available on the edges from outside the loop.
+        let vars = blocks.iter()
+            .map(|p| if nl.nodes.contains(p) { def.clone() } else { SSAOpd::Undef })
+            .collect();
+        let phi = Phi { vars, blocks, dest: closed.clone() };
+        insert_instr(func, exit, 0, Instr::Extra(phi));
+        inserted.push((exit, closed));
+    }
+    inserted
+}
+
+/// The largest subscript of variable `name` defined in `func`.
+fn max_subscript(func: &SSAFunction, name: &str) -> isize {
+    let mut max = -1;
+    for block in &func.blocks {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            for def in instr.defined_values(block.first_index + i) {
+                if let SSAOpd::Subscribed(x, sub) = def {
+                    if x == name && sub > max { max = sub; }
+                }
+            }
+        }
+    }
+    max
+}
+
+/// Returns `true` if `def` is used in a block satisfying `covers`. An operand
+/// of a phi node is used at the end of the corresponding predecessor.
+fn has_use_in(func: &SSAFunction, def: &SSAOpd, covers: &impl Fn(usize) -> bool) -> bool {
+    func.blocks.iter().enumerate().any(|(b, block)| {
+        block.instructions.iter().any(|instr| match instr {
+            Instr::Extra(Phi {vars, blocks, dest: _}) =>
+                vars.iter().zip(blocks.iter()).any(|(v, p)| v == def && covers(*p)),
+            _ => covers(b) && instr.used_values().contains(&def),
+        })
+    })
+}
+
+fn replace_uses_in(func: &mut SSAFunction, def: &SSAOpd, new: &SSAOpd, covers: &impl Fn(usize) -> bool) {
+    for (b, block) in func.blocks.iter_mut().enumerate() {
+        for instr in block.instructions.iter_mut() {
+            match instr {
+                Instr::Extra(Phi {vars, blocks, dest: _}) =>
+                    for (v, p) in vars.iter_mut().zip(blocks.iter()) {
+                        if v == def && covers(*p) { *v = new.clone(); }
+                    }
+                _ => if covers(b) { replace_uses(instr, def, new); }
+            }
+        }
+    }
+}
+
+fn replace_uses(instr: &mut SSAInstr, origin: &SSAOpd, new: &SSAOpd) {
+    let subst = |opd: &mut SSAOpd| if *opd == *origin { *opd = new.clone(); };
+    match instr {
+        Instr::Binary {op: _, lhs, rhs} => { subst(lhs); subst(rhs); }
+        Instr::Unary {op: _, operand} => subst(operand),
+        Instr::Branch(branching) =>
+            match &mut branching.method {
+                BranchKind::If(opd) | BranchKind::Unless(opd) => subst(opd),
+                _ => (),
+            },
+        Instr::Load(opd) => subst(opd),
+        Instr::Store {data, address} => { subst(data); subst(address); }
+        Instr::Move {source, dest: _} => subst(source),
+        Instr::Write(opd) => subst(opd),
+        Instr::InterProc(SSAInterProc::PushParam(opd)) => subst(opd),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::natural_loop::NaturalLoop;
+    use crate::analysis::phi::PhiForge;
+    use crate::analysis::verify::check_ssa;
+    use crate::interp::interpret;
+    use crate::ir::lcssa::to_lcssa;
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::{LOOP_OUTPUT, TWO_EXITS};
+    use crate::ssa::{Phi, SSAOpd, SSAValues};
+
+    #[test]
+    fn test_lcssa() {
+        let funcs = get_sample_functions(LOOP_OUTPUT);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[]).unwrap();
+        assert_eq!(expected, " 45\n");
+
+        let nl = NaturalLoop::compute_loops(func).pop().unwrap();
+        let inserted = to_lcssa(func, &nl);
+        assert_eq!(inserted.len(), 1);
+        let (exit, closed) = &inserted[0];
+        assert!(matches!(closed, SSAOpd::Subscribed(s, _) if s == "s"));
+
+        let block = &func.blocks[*exit];
+        assert!(matches!(&block.instructions[0], Instr::Extra(Phi {dest, ..}) if dest == closed));
+        assert!(block.instructions.iter().any(|instr|
+            matches!(instr, Instr::Write(_)) && instr.used_values() == vec![closed]));
+        assert_eq!(interpret(func, &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_two_exits() {
+        // `i` is used after both exits of the loop.
+        let funcs = get_sample_functions(TWO_EXITS);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[]).unwrap();
+        assert_eq!(expected, " 5\n");
+
+        let nl = NaturalLoop::compute_loops(func).pop().unwrap();
+        let inserted = to_lcssa(func, &nl);
+        assert_eq!(inserted.len(), 2);
+        assert_ne!(inserted[0].0, inserted[1].0);
+        assert_ne!(inserted[0].1, inserted[1].1);
+        assert!(check_ssa(func).is_ok());
+        assert_eq!(interpret(func, &[]).unwrap(), expected);
+    }
+}
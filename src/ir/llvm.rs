@@ -0,0 +1,116 @@
+//! LLVM-like textual output of [`SSAFunction`]s, for cross-checking with
+//! other SSA tools. Values are printed as `%n` for registers and `%x.i` for
+//! subscribed variables, and every block ends with an explicit terminator.
+
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use depile::ir::instr::BranchKind;
+use crate::analysis::cfg::successors;
+use crate::ssa::{Phi, SSAFunction, SSAFunctions, SSAInterProc, SSAOpd};
+
+fn value(opd: &SSAOpd) -> String {
+    match opd {
+        SSAOpd::Operand(Operand::Const(c)) => format!("{}", c),
+        SSAOpd::Operand(Operand::Register(r)) => format!("%{}", r),
+        SSAOpd::Operand(opd) => format!("@{}", opd),
+        SSAOpd::Subscribed(x, i) => format!("%{}.{}", x, i),
+        SSAOpd::Undef | SSAOpd::NOpd => "undef".to_string(),
+    }
+}
+
+/// Emit function `func` named `@f{func_idx}`.
+pub fn emit_function(func: &SSAFunction, func_idx: usize) -> String {
+    let mut lines = vec![format!("define @f{}() {{", func_idx)];
+    let mut needs_exit = false;
+    for (block_idx, block) in func.blocks.iter().enumerate() {
+        lines.push(format!("bb{}:", block_idx));
+        let next = successors(func.blocks.as_slice(), block_idx).fallthrough;
+        let mut terminated = false;
+        let mut instr_idx = block.first_index;
+        for instr in block.instructions.iter() {
+            let line = match instr {
+                Instr::Binary {op, lhs, rhs} =>
+                    format!("%{} = {} {}, {}", instr_idx, op, value(lhs), value(rhs)),
+                Instr::Unary {op, operand} =>
+                    format!("%{} = {} {}", instr_idx, op, value(operand)),
+                Instr::Branch(branching) => {
+                    terminated = true;
+                    let taken = format!("label %bb{}", branching.dest);
+                    needs_exit |= next.is_none() && !matches!(branching.method, BranchKind::Unconditional);
+                    let fallthrough = next.map_or("label %exit".to_string(), |n| format!("label %bb{}", n));
+                    match &branching.method {
+                        BranchKind::Unconditional => format!("br {}", taken),
+                        BranchKind::If(opd) => format!("br {}, {}, {}", value(opd), taken, fallthrough),
+                        BranchKind::Unless(opd) => format!("br {}, {}, {}", value(opd), fallthrough, taken),
+                    }
+                }
+                Instr::Load(address) => format!("%{} = load {}", instr_idx, value(address)),
+                Instr::Store {data, address} => format!("store {}, {}", value(data), value(address)),
+                Instr::Move {source, dest} => format!("{} = copy {}", value(dest), value(source)),
+                Instr::Read => format!("%{} = read", instr_idx),
+                Instr::Write(opd) => format!("write {}", value(opd)),
+                Instr::WriteLn => "writeln".to_string(),
+                Instr::InterProc(SSAInterProc::PushParam(opd)) => format!("param {}", value(opd)),
+                Instr::InterProc(SSAInterProc::Call {dest}) => format!("call @f{}", dest),
+                Instr::Nop => { instr_idx += 1; continue; }
+                Instr::Marker(marker) => format!("; {}", marker),
+                Instr::Extra(Phi {vars, blocks, dest}) => {
+                    let args: Vec<String> = vars.iter().zip(blocks.iter())
+                        .map(|(v, b)| format!("[{}, %bb{}]", value(v), b))
+                        .collect();
+                    format!("{} = phi {}", value(dest), args.join(", "))
+                }
+            };
+            lines.push(format!("  {}", line));
+            instr_idx += 1;
+        }
+        if !terminated {
+            lines.push(match next {
+                Some(n) => format!("  br label %bb{}", n),
+                None => "  ret".to_string(),
+            });
+        }
+    }
+    if needs_exit {
+        lines.push("exit:".to_string());
+        lines.push("  ret".to_string());
+    }
+    lines.push("}".to_string());
+    lines.join("\n")
+}
+
+/// Emit all the functions in `funcs`.
+pub fn emit_functions(funcs: &SSAFunctions) -> String {
+    funcs.functions.iter().enumerate()
+        .map(|(i, func)| emit_function(func, i))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use crate::analysis::phi::PhiForge;
+    use crate::ir::llvm::emit_function;
+    use crate::samples::{get_sample_functions, GCD};
+
+    #[test]
+    fn test_emit() {
+        let funcs = get_sample_functions(GCD);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let text = emit_function(&ssa.functions[0], 0);
+        println!("{}", text);
+
+        assert!(text.starts_with("define @f0() {\nbb0:"));
+        assert!(text.lines().any(|l| l.contains(" = phi [") && l.ends_with("]")));
+
+        let labels: BTreeSet<&str> = text.lines()
+            .filter_map(|l| l.strip_suffix(':'))
+            .collect();
+        assert!(labels.len() >= ssa.functions[0].blocks.len());
+        for target in text.split("label %").skip(1) {
+            let target: String = target.chars().take_while(|c| c.is_alphanumeric()).collect();
+            assert!(labels.contains(target.as_str()), "missing label {}", target);
+        }
+    }
+}
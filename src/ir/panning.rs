@@ -1,4 +1,5 @@
-use depile::ir::{Block, Function, Instr};
+use std::collections::BTreeMap;
+use depile::ir::{Block, Function, Functions, Instr};
 use depile::ir::instr::{Branching, BranchKind, InstrExt};
 use depile::ir::instr::stripped::{Marker, Operand};
 use crate::ssa::{Phi, SSAInterProc, SSAOpd};
@@ -25,6 +26,11 @@ impl<K: InstrExt> PannableBlock for Block<K>
 
 pub trait Pannable {
     fn pan(&self, f: &impl Fn(usize) -> usize) -> Self;
+
+    /// Renumber the indices through `table`, keeping those not in the table.
+    fn pan_map(&self, table: &BTreeMap<usize, usize>) -> Self where Self: Sized {
+        self.pan(&|x| *table.get(&x).unwrap_or(&x))
+    }
 }
 
 impl Pannable for Operand {
@@ -104,6 +110,105 @@ pub fn panning_function<K: InstrExt>(func: &Function<K>, first_index: usize) ->
     }, index)
 }
 
+/// Pan all the functions in `funcs` one after another, so that the first
+/// instruction is at `first_index`.
+pub fn panning_functions<K: InstrExt>(funcs: &mut Functions<K>, first_index: usize)
+    where K::Operand: Pannable,
+          K::Branching: Pannable,
+          K::Marker: Pannable,
+          K::InterProc: Pannable,
+          K::Extra: Pannable {
+    let mut index = first_index;
+    for func in funcs.functions.iter_mut() {
+        let res = panning_function(func, index);
+        *func = res.0;
+        index = res.1;
+    }
+}
+
+/// Insert `instr` into block `block_idx` of `func` before its `offset`-th
+/// instruction, and returns the index of the inserted instruction.
+///
+/// Unlike [`panning_function`], registers are renumbered globally, so values
+/// used across blocks are kept. Registers in `instr` refer to the indices
+/// before insertion.
+pub fn insert_instr<K: InstrExt>(func: &mut Function<K>, block_idx: usize, offset: usize, instr: Instr<K>) -> usize
+    where K::Operand: Pannable,
+          K::Branching: Pannable,
+          K::Marker: Pannable,
+          K::InterProc: Pannable,
+          K::Extra: Pannable {
+    let at = func.blocks[block_idx].first_index + offset;
+    let shift = |x: usize| if x >= at { x + 1 } else { x };
+    for block in func.blocks.iter_mut() {
+        let instrs: Vec<Instr<K>> = block.instructions.iter().map(|instr| instr.pan(&shift)).collect();
+        block.instructions = instrs.into_boxed_slice();
+    }
+    let block = &mut func.blocks[block_idx];
+    let mut instrs = std::mem::take(&mut block.instructions).into_vec();
+    instrs.insert(offset, instr.pan(&shift));
+    block.instructions = instrs.into_boxed_slice();
+
+    let mut index = func.blocks[0].first_index;
+    for block in func.blocks.iter_mut() {
+        block.first_index = index;
+        index += block.instructions.len();
+    }
+    at
+}
+
+/// Remove all the `nop`s in `func` and renumber the remaining instructions
+/// from the first index of `func`, rewriting the registers through the old to
+/// new index mapping. Blocks are kept even if they become empty, so branch
+/// destinations are unchanged.
+pub fn compact<K: InstrExt>(func: &mut Function<K>)
+    where K::Operand: Pannable,
+          K::Branching: Pannable,
+          K::Marker: Pannable,
+          K::InterProc: Pannable,
+          K::Extra: Pannable {
+    let mut table = BTreeMap::new();
+    let mut first_indices = Vec::new();
+    let mut index = func.blocks[0].first_index;
+    for block in func.blocks.iter() {
+        first_indices.push(index);
+        for (j, instr) in block.instructions.iter().enumerate() {
+            if matches!(instr, Instr::Nop) { continue; }
+            table.insert(block.first_index + j, index);
+            index += 1;
+        }
+    }
+    for (block, first_index) in func.blocks.iter_mut().zip(first_indices) {
+        let instrs: Vec<Instr<K>> = block.instructions.iter()
+            .filter(|instr| !matches!(instr, Instr::Nop))
+            .map(|instr| instr.pan_map(&table))
+            .collect();
+        block.instructions = instrs.into_boxed_slice();
+        block.first_index = first_index;
+    }
+}
+
+/// Blocks other than the first one whose `first_index` is zero, which are
+/// usually inserted blocks never panned.
+pub fn unpanned_blocks<K: InstrExt>(func: &Function<K>) -> Vec<usize> {
+    func.blocks.iter().enumerate().skip(1)
+        .filter(|(_, block)| block.first_index == 0)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Normalize `first_index` of the blocks in `func` containing only `nop`s,
+/// so that they follow the previous blocks. Other blocks are left untouched,
+/// as registers refer to their instructions by index.
+pub fn normalize_nop_blocks<K: InstrExt>(func: &mut Function<K>) {
+    for i in 1..func.blocks.len() {
+        if func.blocks[i].instructions.iter().all(|instr| matches!(instr, Instr::Nop)) {
+            let prev = &func.blocks[i - 1];
+            func.blocks[i].first_index = prev.first_index + prev.instructions.len();
+        }
+    }
+}
+
 impl Pannable for Marker {
     fn pan(&self, _: &impl Fn(usize) -> usize) -> Self { self.clone() }
 }
@@ -156,19 +261,121 @@ impl<K: InstrExt> Pannable for Instr<K>
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+    use depile::ir::Instr;
+    use depile::ir::instr::basic::Operand;
+    use crate::analysis::phi::PhiForge;
     use crate::ir::converter::block_convert;
-    use crate::ir::panning::PannableBlock;
+    use crate::ir::insert_block::BlockInserter;
+    use crate::interp::interpret;
+    use crate::ir::panning::{compact, insert_instr, normalize_nop_blocks, Pannable, PannableBlock, unpanned_blocks};
+    use crate::opt::const_prop::ConstProp;
     use crate::samples::{get_sample_functions, PRIME};
+    use crate::ssa::{SSAFunction, SSAInstr, SSAOpd, SSAValues};
+
+    fn instr_at(func: &SSAFunction, instr_idx: usize) -> &SSAInstr {
+        let block = func.blocks.iter()
+            .find(|b| b.first_index <= instr_idx && instr_idx < b.first_index + b.instructions.len())
+            .unwrap();
+        &block.instructions[instr_idx - block.first_index]
+    }
 
     #[test]
     fn test_forward_fill() {
         let funcs = get_sample_functions(PRIME);
         let func = &funcs.functions[0];
         for block in &func.blocks {
-            let block = block_convert(block);
+            let block = block_convert(block).unwrap();
             let block_pan = block.panning_forward_fill(5);
             assert_eq!(block.first_index, block_pan.first_index);
             assert_eq!(block.instructions.len() + 5, block_pan.instructions.len());
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pan_map() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(PRIME));
+        let block = &ssa.functions[0].blocks[0];
+        let table = BTreeMap::from([(block.first_index, 100), (block.first_index + 2, 102), (9, 109)]);
+        let mapped = block.pan_map(&table);
+        assert_eq!(mapped.first_index, 100);
+        assert_eq!(mapped.instructions.len(), block.instructions.len());
+        for (instr, instr_new) in block.instructions.iter().zip(mapped.instructions.iter()) {
+            let regs = |instr: &SSAInstr| instr.used_values().into_iter()
+                .filter_map(|opd| match opd {
+                    SSAOpd::Operand(Operand::Register(x)) => Some(*x),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let expected: Vec<usize> = regs(instr).into_iter()
+                .map(|x| *table.get(&x).unwrap_or(&x))
+                .collect();
+            assert_eq!(regs(instr_new), expected);
+        }
+        assert_eq!(block.pan_map(&BTreeMap::new()).instructions, block.instructions);
+    }
+
+    #[test]
+    fn test_compact() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PRIME));
+        ConstProp::run(&mut ssa);
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[]).unwrap();
+        let first_index = func.blocks[0].first_index;
+        assert!(func.blocks.iter().any(|b| b.instructions.iter().any(|i| matches!(i, Instr::Nop))));
+
+        compact(func);
+        let mut index = first_index;
+        for block in &func.blocks {
+            assert_eq!(block.first_index, index);
+            index += block.instructions.len();
+            for instr in block.instructions.iter() {
+                assert!(!matches!(instr, Instr::Nop));
+                for opd in instr.used_values() {
+                    if let SSAOpd::Operand(Operand::Register(x)) = opd {
+                        assert!(!matches!(instr_at(func, *x), Instr::Nop));
+                    }
+                }
+            }
+        }
+        assert_eq!(interpret(func, &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_insert_instr() {
+        let funcs = get_sample_functions(PRIME);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[0];
+        let mut inserted = func.clone();
+        let at = insert_instr(&mut inserted, 3, 1, Instr::WriteLn);
+        assert_eq!(at, func.blocks[3].first_index + 1);
+        assert!(matches!(instr_at(&inserted, at), Instr::WriteLn));
+
+        let shift = |x: usize| if x >= at { x + 1 } else { x };
+        for block in &func.blocks {
+            for instr in block.instructions.iter() {
+                for opd in instr.used_values() {
+                    if let SSAOpd::Operand(Operand::Register(x)) = opd {
+                        assert_eq!(&instr_at(func, *x).pan(&shift), instr_at(&inserted, shift(*x)));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_nop_blocks() {
+        let funcs = get_sample_functions(PRIME);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let mut func = ssa.functions[0].clone();
+        BlockInserter::new(3).modify_function(&mut func);
+        assert_eq!(unpanned_blocks(&func), vec![3]);
+
+        normalize_nop_blocks(&mut func);
+        assert!(unpanned_blocks(&func).is_empty());
+        assert_eq!(func.blocks[3].first_index, func.blocks[4].first_index);
+        for (block, origin) in func.blocks.iter().skip(4).zip(ssa.functions[0].blocks.iter().skip(3)) {
+            assert_eq!(block.first_index, origin.first_index);
+        }
+    }
+}
@@ -2,6 +2,9 @@ use depile::ir::instr::HasOperand;
 use depile::ir::instr::stripped::{Function, Operand};
 use smallvec::SmallVec;
 
+/// Names of the parameters of `func` by their slots, or `<unknown>` if a
+/// parameter is never used. Operands at offsets not mapping to a parameter
+/// slot, i.e. not 8-aligned or beyond the parameters, are ignored.
 pub fn scan_parameters(func: &Function) -> Vec<String> {
     let count = func.parameter_count;
     let mut params: Vec<String> = Vec::new();
@@ -11,8 +14,8 @@ pub fn scan_parameters(func: &Function) -> Vec<String> {
             let opds: SmallVec<[&Operand;2]> = instr.get_operands();
             for opd in opds {
                 match opd {
-                    Operand::Var(var, x) => if *x > 0 {
-                        *params.get_mut(usize::try_from(x / 8 - 2).unwrap()).unwrap() = var.clone();
+                    Operand::Var(var, x) => if let Some(param) = param_slot(*x).and_then(|i| params.get_mut(i)) {
+                        *param = var.clone();
                     }
                     _ => ()
                 }
@@ -22,10 +25,18 @@ pub fn scan_parameters(func: &Function) -> Vec<String> {
     params
 }
 
+/// The parameter slot at offset `x`, skipping the saved frame pointer and the
+/// return address.
+fn param_slot(x: i64) -> Option<usize> {
+    if x <= 0 || x % 8 != 0 { return None; }
+    usize::try_from(x / 8 - 2).ok()
+}
+
 #[cfg(test)]
 mod test {
     use crate::ir::params::scan_parameters;
     use crate::samples::{GCD, get_sample_functions};
+    use crate::samples::synthetic::ODD_PARAMS;
 
     #[test]
     fn test_scan() {
@@ -34,4 +45,11 @@ mod test {
         let params = scan_parameters(func);
         println!("{:?}", params);
     }
+
+    #[test]
+    fn test_misaligned_offsets() {
+        let funcs = get_sample_functions(ODD_PARAMS);
+        let params = scan_parameters(&funcs.functions[0]);
+        assert_eq!(params, vec!["a".to_string(), "<unknown>".to_string()]);
+    }
 }
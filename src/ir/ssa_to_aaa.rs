@@ -1,15 +1,57 @@
+use std::collections::{BTreeMap, BTreeSet};
 use depile::ir::Instr;
-use crate::ir::panning::panning_function;
+use crate::analysis::cfg::SimpleCfg;
+use crate::ir::insert_block::BlockInserter;
+use crate::ir::panning::{panning_function, panning_functions};
 use crate::ir::ssa_to_aaa::helper::Substitutable;
-use crate::ssa::{Phi, SSAFunction, SSAFunctions, SSAOpd};
+use crate::ssa::{Phi, SSAFunction, SSAFunctions, SSAInterProc, SSAOpd, SSAValues};
 
-pub struct SSATo3Addr { }
+/// Layout of the program prologue and stack frames in the recovered output.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameLayout {
+    /// Index of the first instruction after the program prologue.
+    pub first_index: usize,
+    /// Size of a stack slot in bytes.
+    pub slot_size: i64,
+    /// Offset of the first parameter from the frame pointer, skipping the
+    /// saved frame pointer and the return address.
+    pub param_base: i64,
+}
+
+impl Default for FrameLayout {
+    fn default() -> Self {
+        FrameLayout { first_index: 3, slot_size: 8, param_base: 16 }
+    }
+}
+
+impl FrameLayout {
+    /// Offset of the `position`-th parameter.
+    pub fn param_offset(&self, position: usize) -> i64 {
+        position as i64 * self.slot_size + self.param_base
+    }
+
+    /// Offset of the `position`-th local variable.
+    pub fn local_offset(&self, position: usize) -> i64 {
+        -(position as i64) * self.slot_size - self.slot_size
+    }
+}
+
+pub struct SSATo3Addr {
+    pub layout: FrameLayout,
+}
 
 impl SSATo3Addr {
-    pub fn new() -> Self { SSATo3Addr { } }
+    pub fn new() -> Self { SSATo3Addr { layout: FrameLayout::default() } }
+
+    pub fn with_layout(layout: FrameLayout) -> Self { SSATo3Addr { layout } }
 
     pub fn run(funcs: &mut SSAFunctions, params: &Vec<Vec<String>>) -> Vec<Vec<SSAOpd>> {
-        let s23 = SSATo3Addr::new();
+        SSATo3Addr::run_with(funcs, params, FrameLayout::default())
+    }
+
+    /// Same as [`SSATo3Addr::run`], recovering with frame layout `layout`.
+    pub fn run_with(funcs: &mut SSAFunctions, params: &Vec<Vec<String>>, layout: FrameLayout) -> Vec<Vec<SSAOpd>> {
+        let s23 = SSATo3Addr::with_layout(layout);
         let mut locals = Vec::new();
 
         for i in 0..params.len() {
@@ -17,19 +59,32 @@ impl SSATo3Addr {
             let params = &params[i];
             s23.remove_phi_func(func);
             locals.push(s23.rename_params(func, params));
+            debug_assert!(remaining_subscripts(func).is_empty(),
+                          "subscribed operands left after renaming: {:?}", remaining_subscripts(func));
         }
         s23.flatten(funcs);
         locals
     }
 
+    /// Replace the phi nodes in `func` by moves at the end of predecessors.
+    ///
+    /// Critical edges into blocks with phi nodes are split first, as copies at
+    /// the end of a predecessor with several successors would also run on
+    /// the paths not reaching the phi nodes. The phi nodes of a block are
+    /// evaluated simultaneously, so the copies on each incoming edge are
+    /// sequentialized, which might need temporaries when the copies form a
+    /// cycle, e.g. two phi nodes swapping their values.
     pub fn remove_phi_func(&self, func: &mut SSAFunction) {
-        let mut work_list = Vec::new();
-        for block in &mut func.blocks {
+        split_phi_critical_edges(func);
+
+        // Parallel copies on each edge `(pred, block)`, as `(dest, source)`.
+        let mut copies: BTreeMap<(usize, usize), Vec<(SSAOpd, SSAOpd)>> = BTreeMap::new();
+        for (b, block) in func.blocks.iter_mut().enumerate() {
             for instr in block.instructions.iter_mut() {
                 match instr {
                     Instr::Extra(Phi {vars, blocks, dest}) => {
                         for i in 0..vars.len() {
-                            work_list.push((blocks[i], vars[i].clone(), dest.clone()));
+                            copies.entry((blocks[i], b)).or_default().push((dest.clone(), vars[i].clone()));
                         }
                     }
                     _ => break
@@ -37,42 +92,148 @@ impl SSATo3Addr {
                 *instr = Instr::Nop;
             }
         }
-        for (block_idx, src, dst) in work_list {
-            helper::push_var_assignment(&mut func.blocks[block_idx], &src, &dst);
+
+        let temp = fresh_var(func, "swap");
+        let mut temps = 0;
+        for ((pred, _), parallel) in copies {
+            let moves = sequentialize(parallel, || {
+                temps += 1;
+                SSAOpd::Subscribed(temp.clone(), temps - 1)
+            });
+            for (dst, src) in moves {
+                helper::push_var_assignment(&mut func.blocks[pred], &src, &dst);
+            }
         }
     }
 
     pub fn rename_params(&self, func: &mut SSAFunction, params: &Vec<String>) -> Vec<SSAOpd> {
         let mut locals = Vec::new();
         for block in &mut func.blocks {
-            block.subst(params, &mut locals);
+            block.subst(params, &mut locals, &self.layout);
         }
         func.local_var_count = locals.len() as u64;
         locals
     }
 
     pub fn flatten(&self, funcs: &mut SSAFunctions) {
-        let mut index: usize = 3;
-        for func in funcs.functions.iter_mut() {
-            let res = panning_function(func, index);
-            *func = res.0;
-            index = res.1;
+        panning_functions(funcs, self.layout.first_index);
+        debug_assert_eq!(check_flattened(funcs), Ok(()));
+    }
+}
+
+/// Order the parallel copies `copies`, given as `(dest, source)`, into moves
+/// with the same effect when executed one by one. A copy is only emitted once
+/// its destination is no longer read by the pending copies, and a cycle of
+/// copies is broken by saving a destination in a temporary from `fresh`.
+/// Copies from undefined values and self-copies are dropped.
+pub fn sequentialize(copies: Vec<(SSAOpd, SSAOpd)>, mut fresh: impl FnMut() -> SSAOpd) -> Vec<(SSAOpd, SSAOpd)> {
+    let mut pending: Vec<(SSAOpd, SSAOpd)> = copies.into_iter()
+        .filter(|(dst, src)| dst != src && src != &SSAOpd::Undef)
+        .collect();
+    let mut res = Vec::new();
+    while !pending.is_empty() {
+        let ready = pending.iter()
+            .position(|(dst, _)| !pending.iter().any(|(_, src)| src == dst));
+        match ready {
+            Some(i) => res.push(pending.remove(i)),
+            None => {
+                let dst = pending[0].0.clone();
+                let temp = fresh();
+                res.push((temp.clone(), dst.clone()));
+                for (_, src) in pending.iter_mut() {
+                    if *src == dst { *src = temp.clone(); }
+                }
+            }
         }
+    }
+    res
+}
+
+/// Split the critical edges in `func` leading to blocks with phi nodes, and
+/// returns the number of edges split.
+pub fn split_phi_critical_edges(func: &mut SSAFunction) -> usize {
+    let mut count = 0;
+    loop {
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let edge = cfg.critical_edges().into_iter()
+            .find(|(_, to)| matches!(func.blocks[*to].instructions.first(), Some(Instr::Extra(_))));
+        match edge {
+            Some((from, to)) => { BlockInserter::split_edge(func, from, to); }
+            None => return count,
+        }
+        count += 1;
+    }
+}
 
+/// A variable name starting with `prefix` and not used in `func`.
+fn fresh_var(func: &SSAFunction, prefix: &str) -> String {
+    let mut used = BTreeSet::new();
+    for block in &func.blocks {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let defs = instr.defined_values(block.first_index + i);
+            for opd in instr.used_values().into_iter().chain(defs.iter()) {
+                if let SSAOpd::Subscribed(var, _) = opd { used.insert(var.clone()); }
+            }
+        }
     }
+    let mut name = prefix.to_string();
+    while used.contains(&name) { name.push('_'); }
+    name
+}
+
+/// Check that flattened `funcs` occupy a contiguous range of instruction
+/// indices, that every branch stays inside its function, and that every call
+/// targets an existing function. A function might have several exit blocks.
+pub fn check_flattened(funcs: &SSAFunctions) -> Result<(), String> {
+    let mut index = None;
+    for (i, func) in funcs.functions.iter().enumerate() {
+        for (b, block) in func.blocks.iter().enumerate() {
+            if index.map_or(false, |idx| idx != block.first_index) {
+                return Err(format!("block #{} of function #{} starts at {}, expect {}",
+                                   b, i, block.first_index, index.unwrap()));
+            }
+            index = Some(block.first_index + block.instructions.len());
+            for instr in block.instructions.iter() {
+                match instr {
+                    Instr::Branch(branching) if branching.dest >= func.blocks.len() =>
+                        return Err(format!("branch in block #{} of function #{} escapes to block #{}",
+                                           b, i, branching.dest)),
+                    Instr::InterProc(SSAInterProc::Call {dest}) if *dest >= funcs.functions.len() =>
+                        return Err(format!("call in block #{} of function #{} to unknown function #{}",
+                                           b, i, dest)),
+                    _ => (),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the subscribed operands remaining in `func`, together with the index
+/// of the instruction containing them. Should be empty after renaming.
+pub fn remaining_subscripts(func: &SSAFunction) -> Vec<(usize, SSAOpd)> {
+    let mut res = Vec::new();
+    for block in &func.blocks {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            let instr_idx = block.first_index + i;
+            let defs = instr.defined_values(instr_idx);
+            for opd in instr.used_values().into_iter().chain(defs.iter()) {
+                if let SSAOpd::Subscribed(_, _) = opd { res.push((instr_idx, opd.clone())); }
+            }
+        }
+    }
+    res
 }
 
 mod helper {
     use depile::ir::Instr;
     use depile::ir::instr::basic::Operand;
     use depile::ir::instr::BranchKind;
+    use crate::ir::ssa_to_aaa::FrameLayout;
     use crate::ssa::{SSABlock, SSAInstr, SSAInterProc, SSAOpd};
 
     pub fn push_var_assignment(block: &mut SSABlock, src: &SSAOpd, dst: &SSAOpd) {
-        match src {
-            SSAOpd::Subscribed(_, i) => if *i < 0 { return; }
-            _ => ()
-        }
+        if let SSAOpd::Undef = src { return; }
         let stmt = Instr::Move {source: src.clone(), dest: dst.clone()};
         let mut instrs = std::mem::take(&mut block.instructions).into_vec();
         if instrs.is_empty() {
@@ -97,43 +258,43 @@ mod helper {
     }
 
     pub trait Substitutable {
-        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>);
+        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>, layout: &FrameLayout);
     }
 
     impl Substitutable for SSABlock {
-        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>) {
+        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>, layout: &FrameLayout) {
             for instr in self.instructions.iter_mut() {
-                instr.subst(params, locals);
+                instr.subst(params, locals, layout);
             }
         }
     }
 
     impl Substitutable for SSAInstr {
-        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>) {
+        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>, layout: &FrameLayout) {
             match self {
                 Instr::Binary {op: _, lhs, rhs} =>
-                    { lhs.subst(params, locals); rhs.subst(params, locals) }
+                    { lhs.subst(params, locals, layout); rhs.subst(params, locals, layout) }
                 Instr::Unary {op: _, operand} =>
-                    { operand.subst(params, locals); }
+                    { operand.subst(params, locals, layout); }
                 Instr::Branch(branching) =>
                     match &mut branching.method {
-                        BranchKind::If(opd) => opd.subst(params, locals),
-                        BranchKind::Unless(opd) => opd.subst(params, locals),
+                        BranchKind::If(opd) => opd.subst(params, locals, layout),
+                        BranchKind::Unless(opd) => opd.subst(params, locals, layout),
                         _ => ()
                     },
                 Instr::Load(opd) =>
-                    opd.subst(params, locals),
+                    opd.subst(params, locals, layout),
                 Instr::Store {data, address} =>
-                    { data.subst(params, locals); address.subst(params, locals); }
+                    { data.subst(params, locals, layout); address.subst(params, locals, layout); }
                 Instr::Move {source, dest} =>
-                    { source.subst(params, locals); dest.subst(params, locals); }
+                    { source.subst(params, locals, layout); dest.subst(params, locals, layout); }
                 Instr::Read => (),
                 Instr::Write(opd) =>
-                    opd.subst(params, locals),
+                    opd.subst(params, locals, layout),
                 Instr::WriteLn => (),
                 Instr::InterProc(interproc) =>
                     match interproc {
-                        SSAInterProc::PushParam(opd) => opd.subst(params, locals),
+                        SSAInterProc::PushParam(opd) => opd.subst(params, locals, layout),
                         _ => (),
                     },
                 Instr::Nop => (),
@@ -144,18 +305,18 @@ mod helper {
     }
 
     impl Substitutable for SSAOpd {
-        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>) {
+        fn subst(&mut self, params: &Vec<String>, locals: &mut Vec<SSAOpd>, layout: &FrameLayout) {
             match &self.clone() {
                 SSAOpd::Subscribed(var, i) => {
                     if params.contains(var) && *i == 0 {
-                        let offset: i64 = (params.iter().position(|v| v == var).unwrap()) as i64;
-                        *self = SSAOpd::Operand(Operand::Var(var.clone(), offset * 8 + 16));
+                        let position = params.iter().position(|v| v == var).unwrap();
+                        *self = SSAOpd::Operand(Operand::Var(var.clone(), layout.param_offset(position)));
                         return;
                     }
                     if !locals.contains(self) { locals.push(self.clone()); }
-                    let offset: i64 = locals.iter().position(|v| v == self).unwrap() as i64;
+                    let position = locals.iter().position(|v| v == self).unwrap();
                     let var_name = var.clone() + &*i.to_string();
-                    *self = SSAOpd::Operand(Operand::Var(var_name, -offset * 8 - 8));
+                    *self = SSAOpd::Operand(Operand::Var(var_name, layout.local_offset(position)));
                 }
                 _ => ()
             }
@@ -167,10 +328,20 @@ mod helper {
 
 #[cfg(test)]
 mod test {
+    use std::collections::{BTreeMap, BTreeSet};
     use std::io::{ Write, BufWriter };
+    use depile::ir::Instr;
     use crate::analysis::phi::PhiForge;
-    use crate::ir::ssa_to_aaa::SSATo3Addr;
-    use crate::samples::{ALL_SAMPLES, get_sample_functions, PRIME};
+    use depile::ir::instr::basic::Operand;
+    use depile::ir::instr::{Branching, BranchKind};
+    use depile::ir::program::display_program;
+    use crate::analysis::cfg::SimpleCfg;
+    use crate::ir::converter::functions_revert;
+    use crate::interp::interpret;
+    use crate::ir::ssa_to_aaa::{check_flattened, FrameLayout, remaining_subscripts, sequentialize, SSATo3Addr};
+    use crate::ssa::{Phi, SSAOpd, SSAValues};
+    use crate::samples::{ALL_SAMPLES, GCD, get_sample_functions, PRIME};
+    use crate::samples::synthetic::CRITICAL_EDGE;
 
     #[test]
     fn test_ssa_to_aaa() {
@@ -196,4 +367,168 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_remaining_subscripts() {
+        let funcs = get_sample_functions(PRIME);
+        let (mut ssa, params) = PhiForge::run(&funcs);
+        let s23 = SSATo3Addr::new();
+        let func = &mut ssa.functions[0];
+        s23.remove_phi_func(func);
+        s23.rename_params(func, &params[0]);
+        assert!(remaining_subscripts(func).is_empty());
+
+        let block = &mut func.blocks[1];
+        let instr_idx = block.first_index;
+        let leftover = SSAOpd::Subscribed("i".to_string(), 1);
+        block.instructions[0] = Instr::Write(leftover.clone());
+        assert_eq!(remaining_subscripts(func), vec![(instr_idx, leftover)]);
+    }
+
+    #[test]
+    fn test_local_var_count() {
+        for str in [GCD, PRIME] {
+            let (mut ssa, params) = PhiForge::run(&get_sample_functions(str));
+            let counts: Vec<u64> = ssa.functions.iter().map(|func| func.local_var_count).collect();
+            let locals = SSATo3Addr::run(&mut ssa, &params);
+            for (i, locals) in locals.iter().enumerate() {
+                let names: BTreeSet<&String> = locals.iter()
+                    .filter_map(|opd| match opd {
+                        SSAOpd::Subscribed(var, _) if !params[i].contains(var) => Some(var),
+                        _ => None,
+                    })
+                    .collect();
+                assert_eq!(counts[i], names.len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_exits() {
+        let funcs = get_sample_functions(GCD);
+        let (mut ssa, params) = PhiForge::run(&funcs);
+
+        // Exit the loop of gcd either from the header to a copy of the exit
+        // block, or from the end of the body to the original exit block.
+        let func = &mut ssa.functions[0];
+        let exit = func.blocks[3].clone();
+        func.blocks.push(exit);
+        let header = func.blocks[1].instructions.len() - 1;
+        if let Instr::Branch(branching) = &mut func.blocks[1].instructions[header] { branching.dest = 4; }
+        let body = func.blocks[2].instructions.len() - 1;
+        func.blocks[2].instructions[body] = Instr::Branch(Branching {
+            method: BranchKind::If(SSAOpd::Operand(Operand::Register(10))),
+            dest: 1,
+        });
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert_eq!(cfg.exits().len(), 2);
+
+        SSATo3Addr::run(&mut ssa, &params);
+        assert_eq!(check_flattened(&ssa), Ok(()));
+
+        let last = ssa.functions.last().unwrap().blocks.last().unwrap();
+        let count = last.first_index + last.instructions.len();
+        let program = display_program(&functions_revert(&ssa).unwrap().destruct().flatten()).unwrap();
+        assert_eq!(program.matches("ret").count(), 3);
+        for target in program.split('[').skip(1) {
+            let target: usize = target.split(']').next().unwrap().parse().unwrap();
+            assert!(target < count, "branch target {} escapes the program", target);
+        }
+    }
+
+    #[test]
+    fn test_frame_layout() {
+        let funcs = get_sample_functions(GCD);
+        let (mut ssa, params) = PhiForge::run(&funcs);
+        let layout = FrameLayout { first_index: 10, slot_size: 4, param_base: 12 };
+        let locals = SSATo3Addr::run_with(&mut ssa, &params, layout);
+        assert_eq!(ssa.functions[0].blocks[0].first_index, 10);
+        assert_eq!(check_flattened(&ssa), Ok(()));
+
+        let params_offsets: Vec<i64> = (0..params[0].len()).map(|i| layout.param_offset(i)).collect();
+        let locals_offsets: Vec<i64> = (0..locals[0].len()).map(|i| layout.local_offset(i)).collect();
+        assert_eq!(params_offsets[..2], [12, 16]);
+        for block in &ssa.functions[0].blocks {
+            for (i, instr) in block.instructions.iter().enumerate() {
+                let defs = instr.defined_values(block.first_index + i);
+                for opd in instr.used_values().into_iter().chain(defs.iter()) {
+                    if let SSAOpd::Operand(Operand::Var(var, offset)) = opd {
+                        assert!(params_offsets.contains(offset) || locals_offsets.contains(offset),
+                                "unexpected offset of {}: {}", var, offset);
+                    }
+                }
+            }
+        }
+
+        display_program(&functions_revert(&ssa).unwrap().destruct().flatten()).unwrap();
+    }
+
+    #[test]
+    fn test_sequentialize() {
+        let v = |name: &str| SSAOpd::Subscribed(name.to_string(), 1);
+        // Run `moves` one by one from `a = 1, b = 2, c = 3, d = 4`.
+        let run = |moves: &Vec<(SSAOpd, SSAOpd)>| {
+            let mut env: BTreeMap<SSAOpd, i64> = ["a", "b", "c", "d"].iter().zip(1..).map(|(n, i)| (v(*n), i)).collect();
+            for (dst, src) in moves {
+                let value = env[src];
+                env.insert(dst.clone(), value);
+            }
+            env
+        };
+        let check = |copies: Vec<(SSAOpd, SSAOpd)>, temps: usize| {
+            let mut expected = run(&Vec::new());
+            for (dst, src) in &copies { expected.insert(dst.clone(), run(&Vec::new())[src]); }
+            let mut count = 0;
+            let moves = sequentialize(copies, || { count += 1; SSAOpd::Subscribed("t".to_string(), count) });
+            let mut env = run(&moves);
+            env.retain(|opd, _| !matches!(opd, SSAOpd::Subscribed(var, _) if var == "t"));
+            assert_eq!(env, expected);
+            assert_eq!(count, temps);
+        };
+
+        check(vec![(v("a"), v("b")), (v("b"), v("a"))], 1);
+        check(vec![(v("a"), v("b")), (v("b"), v("c")), (v("c"), v("a"))], 1);
+        check(vec![(v("a"), v("b")), (v("b"), v("c")), (v("d"), v("a"))], 0);
+        check(vec![(v("a"), v("b")), (v("b"), v("a")), (v("c"), v("d")), (v("d"), v("c"))], 2);
+        check(vec![(v("a"), v("a")), (v("b"), v("a"))], 0);
+    }
+
+    #[test]
+    fn test_swapping_phis() {
+        let funcs = get_sample_functions(GCD);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+
+        // Make `a` and `b` swap their values along the back edge of the loop.
+        let a = |i| SSAOpd::Subscribed("a".to_string(), i);
+        let b = |i| SSAOpd::Subscribed("b".to_string(), i);
+        func.blocks[1].instructions[0] = Instr::Extra(Phi { vars: vec![a(0), b(1)], blocks: vec![0, 2], dest: a(1) });
+        func.blocks[1].instructions[1] = Instr::Extra(Phi { vars: vec![b(0), a(1)], blocks: vec![0, 2], dest: b(1) });
+        func.blocks[1].instructions[2] = Instr::Nop;
+        let before = func.blocks[2].instructions.len();
+
+        SSATo3Addr::new().remove_phi_func(func);
+        let moves: Vec<(SSAOpd, SSAOpd)> = func.blocks[2].instructions[before - 1..].iter()
+            .filter_map(|instr| match instr {
+                Instr::Move {source, dest} => Some((dest.clone(), source.clone())),
+                _ => None,
+            })
+            .collect();
+        let temp = SSAOpd::Subscribed("swap".to_string(), 0);
+        assert_eq!(moves, vec![(temp.clone(), a(1)), (a(1), b(1)), (b(1), temp)]);
+    }
+
+    #[test]
+    fn test_critical_edges() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(CRITICAL_EDGE));
+        let func = &mut ssa.functions[0];
+        let expected = [interpret(func, &[0]).unwrap(), interpret(func, &[1]).unwrap()];
+
+        let s23 = SSATo3Addr::new();
+        let count = func.blocks.len();
+        s23.remove_phi_func(func);
+        assert_eq!(func.blocks.len(), count + 2);
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        assert!(cfg.critical_edges().is_empty());
+        assert_eq!([interpret(func, &[0]).unwrap(), interpret(func, &[1]).unwrap()], expected);
+    }
 }
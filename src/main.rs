@@ -3,6 +3,7 @@ pub mod samples;
 pub mod analysis;
 pub mod ir;
 pub mod opt;
+pub mod interp;
 pub mod cli;
 
 fn main() {
@@ -1,2 +1,11 @@
 pub mod loop_invariant;
 pub mod const_prop;
+pub mod pinned;
+pub mod pass;
+pub mod driver;
+pub mod dead_blocks;
+pub mod common_code;
+pub mod branch_next;
+pub mod sccp;
+pub mod copy_prop;
+pub mod dce;
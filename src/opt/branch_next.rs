@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use depile::ir::instr::BranchKind;
+use crate::ssa::{SSAFunction, SSAFunctions};
+
+/// Reports the branches removed by [`BranchToNext`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BranchToNextReport {
+    pub instr_idx: usize,
+    pub blocks: Vec<usize>,
+    pub opt_count: usize,
+}
+
+impl Display for BranchToNextReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Function: {}", self.instr_idx)?;
+        writeln!(f, "  Blocks branching to the next block: {:?}", self.blocks)?;
+        writeln!(f, "  Number of statement removed: {}", self.opt_count)
+    }
+}
+
+/// Removes unconditional branches to the next block in layout, which falls
+/// through implicitly once the branch is gone.
+///
+/// Branches are replaced by `nop`s to keep the instruction indices. Since an
+/// unconditional branch is the only edge out of its block, no other edge
+/// depends on it. Blocks must not be reordered or inserted afterwards, or the
+/// fall-through would go elsewhere.
+pub struct BranchToNext;
+
+impl BranchToNext {
+    pub fn run(funcs: &mut SSAFunctions) -> Vec<BranchToNextReport> {
+        funcs.functions.iter_mut().map(BranchToNext::run_func).collect()
+    }
+
+    pub fn run_func(func: &mut SSAFunction) -> BranchToNextReport {
+        let mut report = BranchToNextReport {
+            instr_idx: func.blocks[0].first_index,
+            blocks: Vec::new(),
+            opt_count: 0,
+        };
+        for (i, block) in func.blocks.iter_mut().enumerate() {
+            let redundant = matches!(block.instructions.last(),
+                Some(Instr::Branch(b)) if matches!(b.method, BranchKind::Unconditional) && b.dest == i + 1);
+            if redundant {
+                *block.instructions.last_mut().unwrap() = Instr::Nop;
+                report.blocks.push(i);
+                report.opt_count += 1;
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use depile::ir::instr::BranchKind;
+    use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
+    use crate::opt::branch_next::BranchToNext;
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::BRANCH_NEXT;
+
+    #[test]
+    fn test_branch_to_next() {
+        let funcs = get_sample_functions(BRANCH_NEXT);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[]).unwrap();
+
+        let report = BranchToNext::run_func(func);
+        assert_eq!(report.opt_count, 1);
+        let block = &func.blocks[report.blocks[0]];
+        assert!(matches!(block.instructions.last(), Some(Instr::Nop)));
+
+        let kept = func.blocks.iter().enumerate().any(|(i, block)| matches!(block.instructions.last(),
+            Some(Instr::Branch(b)) if matches!(b.method, BranchKind::Unconditional) && b.dest > i + 1));
+        assert!(kept);
+        assert_eq!(interpret(func, &[]).unwrap(), expected);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use crate::analysis::cfg::{SimpleCfg, successors};
+use crate::ir::panning::{insert_instr, Pannable};
+use crate::ssa::{SSAFunction, SSAFunctions, SSAInstr};
+
+/// Reports the performance of hoisting common code.
+pub struct CommonCodeReport {
+    pub instr_idx: usize,
+    pub opt_count: usize,
+    pub instructions: Vec<(SSAInstr, usize)>,
+}
+
+impl Display for CommonCodeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Function: {}", self.instr_idx)?;
+        writeln!(f, "  Number of statement hoisted: {}", self.opt_count)?;
+        for (instr, id) in &self.instructions {
+            writeln!(f, "  {}: {}", id, instr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hoists identical computations leading both arms of a branch into the
+/// block before the branch, which is a lightweight form of partial
+/// redundancy elimination.
+pub struct CommonCode;
+
+impl CommonCode {
+    pub fn run(funcs: &mut SSAFunctions) -> Vec<CommonCodeReport> {
+        funcs.functions.iter_mut().map(CommonCode::run_func).collect()
+    }
+
+    pub fn run_func(func: &mut SSAFunction) -> CommonCodeReport {
+        let mut report = CommonCodeReport {
+            instr_idx: func.blocks[0].first_index,
+            opt_count: 0,
+            instructions: Vec::new(),
+        };
+        while let Some(hoisted) = hoist_common_code(func) {
+            report.opt_count += 1;
+            report.instructions.push(hoisted);
+        }
+        report
+    }
+}
+
+/// Hoist one computation leading both arms of a branch, and returns the
+/// hoisted instruction together with its new index.
+///
+/// Both arms should have the branching block as their only predecessor, so
+/// that the computation is performed exactly once on every path.
+pub fn hoist_common_code(func: &mut SSAFunction) -> Option<(SSAInstr, usize)> {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    for p in 0..func.blocks.len() {
+        let succs = successors(func.blocks.as_slice(), p);
+        let (a, b) = match (succs.fallthrough, succs.taken) {
+            (Some(a), Some(b)) if a != b => (a, b),
+            _ => continue,
+        };
+        if cfg.get_prevs(a).len() != 1 || cfg.get_prevs(b).len() != 1 { continue; }
+
+        let (ia, instr) = match leading_instr(func, a) { Some(x) => x, None => continue };
+        let (ib, other) = match leading_instr(func, b) { Some(x) => x, None => continue };
+        if instr != other || !matches!(instr, Instr::Binary {..} | Instr::Unary {..}) { continue; }
+
+        let offset = func.blocks[p].instructions.len() - 1;
+        let target = insert_instr(func, p, offset, instr.clone());
+        let shift = |x: usize| if x >= target { x + 1 } else { x };
+        let (ia, ib) = (shift(ia), shift(ib));
+        let merge = |x: usize| if x == ia || x == ib { target } else { x };
+        for block in func.blocks.iter_mut() {
+            let first_index = block.first_index;
+            let instrs: Vec<SSAInstr> = block.instructions.iter().enumerate()
+                .map(|(j, instr)| {
+                    if first_index + j == ia || first_index + j == ib { Instr::Nop }
+                    else { instr.pan(&merge) }
+                })
+                .collect();
+            block.instructions = instrs.into_boxed_slice();
+        }
+        return Some((instr, target));
+    }
+    None
+}
+
+/// The first instruction in `block_idx` which is not a `nop`, and its index.
+fn leading_instr(func: &SSAFunction, block_idx: usize) -> Option<(usize, SSAInstr)> {
+    let block = &func.blocks[block_idx];
+    block.instructions.iter().enumerate()
+        .find(|(_, instr)| !matches!(instr, Instr::Nop))
+        .map(|(j, instr)| (block.first_index + j, instr.clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use depile::ir::instr::basic::Operand;
+    use crate::analysis::phi::PhiForge;
+    use crate::opt::common_code::CommonCode;
+    use crate::samples::{ALL_SAMPLES, get_sample_functions};
+    use crate::samples::synthetic::COMMON_CODE;
+    use crate::ssa::SSAOpd;
+
+    #[test]
+    fn test_common_code() {
+        let funcs = get_sample_functions(COMMON_CODE);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let report = CommonCode::run_func(func);
+        assert_eq!(report.opt_count, 1);
+
+        let (_, target) = report.instructions[0];
+        let pred = &func.blocks[0];
+        assert!(matches!(pred.instructions[target - pred.first_index], Instr::Binary {..}));
+        let reg = SSAOpd::Operand(Operand::Register(target));
+        for block in &func.blocks[1..] {
+            for instr in block.instructions.iter() {
+                assert!(!matches!(instr, Instr::Binary {..}));
+                if let Instr::Write(opd) = instr { assert_eq!(opd, &reg); }
+            }
+        }
+    }
+
+    #[test]
+    fn test_samples_common_code() {
+        for sample in ALL_SAMPLES {
+            let (mut ssa, _) = PhiForge::run(&get_sample_functions(sample));
+            CommonCode::run(&mut ssa);
+        }
+    }
+}
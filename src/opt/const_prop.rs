@@ -4,7 +4,9 @@ use depile::ir::Instr;
 use depile::ir::instr::basic::Operand::Const;
 use depile::ir::instr::BranchKind;
 use depile::ir::instr::stripped::Operand;
-use crate::ssa::{Phi, SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAInterProc, SSAOpd};
+use crate::analysis::cfg::invalid_branches;
+use crate::interp::{eval_binary, eval_unary};
+use crate::ssa::{IndexedInstructions, Phi, SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAInterProc, SSAOpd};
 
 /// Reports the performance of constant propagation.
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -23,6 +25,8 @@ impl Display for ConstPropReport {
 pub struct ConstProp {
     pub count: usize,
     pub const_elements: BTreeMap<SSAOpd, SSAOpd>,
+    /// Number of scans over the function, including the pre-scan for seeds.
+    pub scans: usize,
 }
 
 impl ConstProp {
@@ -35,18 +39,32 @@ impl ConstProp {
     }
 
     pub fn run_func(func: &mut SSAFunction) -> ConstPropReport {
-        let mut cp = ConstProp::new();
-        while func.subst(&mut cp) { };
-        ConstPropReport {
-            instr_idx: func.blocks[0].first_index,
-            opt_count: cp.count,
+        // Only operands are propagated, as branch destinations are literals.
+        debug_assert!(invalid_branches(func).is_empty(),
+                      "branches to unknown blocks: {:?}", invalid_branches(func));
+        ConstProp::new().propagate(func)
+    }
+
+    /// Propagate constants in `func` until nothing changes. Functions without
+    /// any seed, i.e. a constant `Move` or a collapsible phi node, are left
+    /// after a single scan.
+    pub fn propagate(&mut self, func: &mut SSAFunction) -> ConstPropReport {
+        let instr_idx = func.blocks[0].first_index;
+        self.scans += 1;
+        if has_seeds(func) {
+            loop {
+                self.scans += 1;
+                if !func.subst(self) { break; }
+            }
         }
+        ConstPropReport { instr_idx, opt_count: self.count }
     }
 
     pub fn new() -> Self {
         ConstProp {
             count: 0,
             const_elements: BTreeMap::new(),
+            scans: 0,
         }
     }
 
@@ -87,9 +105,8 @@ impl Substitutable for SSAFunction {
 impl Substitutable for SSABlock {
     fn subst(&mut self, cp: &mut ConstProp) -> bool {
         let mut changed = false;
-        let instr_idx = self.first_index;
-        for instr in self.instructions.iter_mut() {
-            changed |= IdxInstr { idx: instr_idx, instr: instr }.subst(cp);
+        for (idx, instr) in self.indexed_instructions_mut() {
+            changed |= IdxInstr { idx, instr }.subst(cp);
         }
         changed
     }
@@ -102,12 +119,29 @@ pub struct IdxInstr<'a> {
 
 impl Substitutable for IdxInstr<'_> {
     fn subst(&mut self, cp: &mut ConstProp) -> bool {
+        let reg = SSAOpd::Operand(Operand::Register(self.idx));
         let instr = &mut self.instr;
         match instr {
-            Instr::Binary {op: _, lhs, rhs} =>
-                cp.check_subst(lhs) || cp.check_subst(rhs),
-            Instr::Unary {op: _, operand} =>
-                cp.check_subst(operand),
+            Instr::Binary {op: _, lhs, rhs} => {
+                let mut changed = cp.check_subst(lhs) || cp.check_subst(rhs);
+                // Division or modulo by zero is left for the program to fail at runtime.
+                let value = fold(instr).map(|v| SSAOpd::Operand(Const(v))).or_else(|| simplify(&**instr));
+                if let Some(value) = value {
+                    cp.insert(&reg, &value);
+                    **instr = Instr::Nop;
+                    changed = true;
+                }
+                changed
+            }
+            Instr::Unary {op: _, operand} => {
+                let mut changed = cp.check_subst(operand);
+                if let Some(value) = fold(instr) {
+                    cp.insert(&reg, &SSAOpd::Operand(Const(value)));
+                    **instr = Instr::Nop;
+                    changed = true;
+                }
+                changed
+            }
             Instr::Branch(branching) =>
                 match &mut branching.method {
                     BranchKind::If(opd) => cp.check_subst(opd),
@@ -159,6 +193,58 @@ impl Substitutable for IdxInstr<'_> {
     }
 }
 
+/// Returns `true` if `func` has a `Move` of a constant or a phi node always
+/// yielding the same constant, from which constant propagation starts.
+pub fn has_seeds(func: &SSAFunction) -> bool {
+    func.blocks.iter().flat_map(|block| block.instructions.iter()).any(|instr| match instr {
+        Instr::Move {source, dest: _} => as_constant(source).is_some(),
+        Instr::Extra(Phi {vars, blocks: _, dest: _}) => check_vars_in_phi(vars).is_some(),
+        _ => fold(instr).is_some() || simplify(instr).is_some(),
+    })
+}
+
+/// Evaluate a `Binary` or `Unary` instruction whose operands are all
+/// constants. Returns `None` for other instructions, or if the evaluation
+/// fails, e.g. on division by zero.
+pub fn fold(instr: &SSAInstr) -> Option<i64> {
+    match instr {
+        Instr::Binary {op, lhs, rhs} =>
+            eval_binary(&op.to_string(), const_value(lhs)?, const_value(rhs)?).ok(),
+        Instr::Unary {op, operand} =>
+            eval_unary(&op.to_string(), const_value(operand)?).ok(),
+        _ => None,
+    }
+}
+
+/// Simplify a `Binary` instruction by algebraic identities, such as `x + 0`
+/// or `x * 0`, and returns the operand it always yields. An identity giving
+/// back a variable only applies if the variable has a single definition, so
+/// that the instruction can be replaced by the variable at every use.
+pub fn simplify(instr: &SSAInstr) -> Option<SSAOpd> {
+    let (op, lhs, rhs) = match instr {
+        Instr::Binary {op, lhs, rhs} => (op.to_string(), lhs, rhs),
+        _ => return None,
+    };
+    let res = match (op.as_str(), const_value(lhs), const_value(rhs)) {
+        ("mul", Some(0), _) | ("mul", _, Some(0)) => return Some(SSAOpd::Operand(Const(0))),
+        ("sub", _, _) if lhs == rhs => return Some(SSAOpd::Operand(Const(0))),
+        ("add", _, Some(0)) | ("sub", _, Some(0)) | ("mul", _, Some(1)) | ("div", _, Some(1)) => lhs,
+        ("add", Some(0), _) | ("mul", Some(1), _) => rhs,
+        _ => return None,
+    };
+    match res {
+        SSAOpd::Subscribed(_, _) | SSAOpd::Operand(Operand::Register(_)) => Some(res.clone()),
+        _ => None,
+    }
+}
+
+pub fn const_value(opd: &SSAOpd) -> Option<i64> {
+    match opd {
+        SSAOpd::Operand(Operand::Const(i)) => Some(*i),
+        _ => None,
+    }
+}
+
 pub fn as_constant(opd: &SSAOpd) -> Option<&SSAOpd> {
     match opd {
         SSAOpd::Operand(Operand::Const(_)) => Some(opd),
@@ -166,6 +252,10 @@ pub fn as_constant(opd: &SSAOpd) -> Option<&SSAOpd> {
     }
 }
 
+/// Check whether a phi node with operands `vars` always yields the same
+/// constant. Undefined operands (e.g. `undef` on a back edge before the loop
+/// body assigns `x`) never contribute a value and are ignored, so `phi 4 undef`
+/// collapses to `4`. Returns `None` if no operand is constant.
 pub fn check_vars_in_phi(vars: &Vec<SSAOpd>) -> Option<SSAOpd> {
     let mut curr: Option<i64> = None;
     for var in vars {
@@ -175,24 +265,25 @@ pub fn check_vars_in_phi(vars: &Vec<SSAOpd>) -> Option<SSAOpd> {
                 else if curr.is_some() && curr == Some(*i) { }
                 else { return None; }
             }
-            SSAOpd::Subscribed(_, index) => {
-                if *index >= 0 { return None }
-                else { continue; }
-           }
-            _ => panic!("error phi")
+            SSAOpd::Undef => continue,
+            _ => return None,
         }
     }
-    Some(SSAOpd::Operand(Const(curr.unwrap())))
+    curr.map(|i| SSAOpd::Operand(Const(i)))
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{BufWriter, Write};
+    use depile::ir::Instr;
+    use depile::ir::instr::BinaryOp;
     use depile::ir::instr::basic::Operand::Const;
-    use crate::opt::const_prop::{check_vars_in_phi, ConstProp};
+    use crate::opt::const_prop::{check_vars_in_phi, ConstProp, fold, simplify};
     use crate::analysis::phi::PhiForge;
-    use crate::samples::{ALL_SAMPLES, get_sample_functions};
-    use crate::ssa::SSAOpd;
+    use crate::interp::interpret;
+    use crate::samples::{ALL_SAMPLES, get_sample_functions, GCD};
+    use crate::samples::synthetic::{CONST_CHAIN, INVARIANT};
+    use crate::ssa::{SSAInstr, SSAOpd};
 
     #[test]
     fn test_const_prop() {
@@ -218,7 +309,84 @@ mod test {
         for _ in 0..3 { vars.push(v.clone()); }
         assert!(check_vars_in_phi(&vars).is_some());
 
-        vars.push(SSAOpd::Subscribed(String::from("v"), -1));
+        vars.push(SSAOpd::Undef);
         assert!(check_vars_in_phi(&vars).is_some());
     }
+
+    #[test]
+    fn test_undefined_back_edge() {
+        let undef = SSAOpd::Undef;
+        let c = |i| SSAOpd::Operand(Const(i));
+
+        assert_eq!(check_vars_in_phi(&vec![c(4), undef.clone()]), Some(c(4)));
+        assert_eq!(check_vars_in_phi(&vec![undef.clone(), c(4), undef.clone()]), Some(c(4)));
+        assert_eq!(check_vars_in_phi(&vec![c(1), c(2)]), None);
+        assert_eq!(check_vars_in_phi(&vec![c(1), undef.clone(), c(2)]), None);
+        assert_eq!(check_vars_in_phi(&vec![c(1), SSAOpd::Subscribed(String::from("x"), 2)]), None);
+        // Nothing is known if all the operands are undefined.
+        assert_eq!(check_vars_in_phi(&vec![undef.clone(), undef]), None);
+    }
+
+    #[test]
+    fn test_no_seeds() {
+        // The loop in `gcd` never moves a constant.
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let mut cp = ConstProp::new();
+        let report = cp.propagate(&mut ssa.functions[0]);
+        assert_eq!(report.opt_count, 0);
+        assert_eq!(cp.scans, 1);
+
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(INVARIANT));
+        let mut cp = ConstProp::new();
+        assert!(cp.propagate(&mut ssa.functions[0]).opt_count > 0);
+        assert!(cp.scans > 1);
+    }
+
+    #[test]
+    fn test_fold_chain() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(CONST_CHAIN));
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[]).unwrap();
+        assert_eq!(expected, " -14\n");
+
+        ConstProp::run_func(func);
+        let instrs: Vec<&SSAInstr> = func.blocks.iter().flat_map(|block| block.instructions.iter()).collect();
+        assert!(!instrs.iter().any(|instr| matches!(instr, Instr::Binary {..} | Instr::Unary {..})));
+        assert!(instrs.iter().any(|instr| matches!(instr, Instr::Write(opd) if *opd == SSAOpd::Operand(Const(-14)))));
+        assert_eq!(interpret(func, &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_fold_division_by_zero() {
+        let c = |i| SSAOpd::Operand(Const(i));
+        let div: SSAInstr = Instr::Binary {op: BinaryOp::Div, lhs: c(1), rhs: c(0)};
+        assert_eq!(fold(&div), None);
+        let div: SSAInstr = Instr::Binary {op: BinaryOp::Div, lhs: c(6), rhs: c(3)};
+        assert_eq!(fold(&div), Some(2));
+    }
+
+    #[test]
+    fn test_simplify() {
+        let x = SSAOpd::Subscribed("x".to_string(), 1);
+        let c = |i| SSAOpd::Operand(Const(i));
+        let bin = |op, lhs: &SSAOpd, rhs: &SSAOpd| -> SSAInstr {
+            Instr::Binary {op, lhs: lhs.clone(), rhs: rhs.clone()}
+        };
+
+        assert_eq!(simplify(&bin(BinaryOp::Add, &x, &c(0))), Some(x.clone()));
+        assert_eq!(simplify(&bin(BinaryOp::Add, &c(0), &x)), Some(x.clone()));
+        assert_eq!(simplify(&bin(BinaryOp::Sub, &x, &c(0))), Some(x.clone()));
+        assert_eq!(simplify(&bin(BinaryOp::Mul, &x, &c(1))), Some(x.clone()));
+        assert_eq!(simplify(&bin(BinaryOp::Div, &x, &c(1))), Some(x.clone()));
+        assert_eq!(simplify(&bin(BinaryOp::Mul, &x, &c(0))), Some(c(0)));
+        assert_eq!(simplify(&bin(BinaryOp::Mul, &c(0), &x)), Some(c(0)));
+        assert_eq!(simplify(&bin(BinaryOp::Sub, &x, &x)), Some(c(0)));
+
+        // Subtraction and division are not commutative.
+        assert_eq!(simplify(&bin(BinaryOp::Sub, &c(0), &x)), None);
+        assert_eq!(simplify(&bin(BinaryOp::Div, &c(1), &x)), None);
+        // Variables not renamed might be redefined.
+        let gp = SSAOpd::Operand(depile::ir::instr::basic::Operand::GP);
+        assert_eq!(simplify(&bin(BinaryOp::Add, &gp, &c(0))), None);
+    }
 }
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use crate::ssa::{SSAFunction, SSAFunctions, SSAOpd, SSAValues};
+
+/// Reports the copies eliminated by [`CopyProp`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub struct CopyPropReport {
+    pub instr_idx: usize,
+    pub opt_count: usize,
+}
+
+impl Display for CopyPropReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Function: {}", self.instr_idx)?;
+        writeln!(f, "  Number of copies eliminated: {}", self.opt_count)
+    }
+}
+
+/// Copy propagation.
+///
+/// A `Move` from a subscribed variable or a register into a subscribed
+/// variable is a copy: both are defined exactly once, so the uses of the
+/// destination can read the source instead, and the move is removed. Moves
+/// into variables not renamed, which might be redefined, are kept.
+pub struct CopyProp {
+    /// Copies in the function, from the destination to the source.
+    pub copies: BTreeMap<SSAOpd, SSAOpd>,
+}
+
+impl CopyProp {
+    pub fn run(funcs: &mut SSAFunctions) -> Vec<CopyPropReport> {
+        funcs.functions.iter_mut().map(CopyProp::run_func).collect()
+    }
+
+    pub fn run_func(func: &mut SSAFunction) -> CopyPropReport {
+        let cp = CopyProp::from(&*func);
+        let mut report = CopyPropReport { instr_idx: func.blocks[0].first_index, opt_count: 0 };
+        for block in func.blocks.iter_mut() {
+            for instr in block.instructions.iter_mut() {
+                if let Instr::Move {source: _, dest} = instr {
+                    if cp.copies.contains_key(dest) {
+                        *instr = Instr::Nop;
+                        report.opt_count += 1;
+                        continue;
+                    }
+                }
+                for opd in instr.used_values_mut() {
+                    if let Some(source) = cp.resolve(opd) { *opd = source; }
+                }
+            }
+        }
+        report
+    }
+
+    /// The original source of `opd` following the chain of copies, or `None`
+    /// if `opd` is not the destination of a copy.
+    pub fn resolve(&self, opd: &SSAOpd) -> Option<SSAOpd> {
+        let mut curr = self.copies.get(opd)?;
+        // A chain never revisits a value in SSA form, but copies in
+        // unreachable code might form a cycle.
+        for _ in 0..self.copies.len() {
+            match self.copies.get(curr) {
+                Some(next) => curr = next,
+                None => break,
+            }
+        }
+        Some(curr.clone())
+    }
+}
+
+impl From<&SSAFunction> for CopyProp {
+    fn from(func: &SSAFunction) -> Self {
+        let mut copies = BTreeMap::new();
+        for instr in func.blocks.iter().flat_map(|block| block.instructions.iter()) {
+            if let Instr::Move {source, dest} = instr {
+                if is_copy(source, dest) { copies.insert(dest.clone(), source.clone()); }
+            }
+        }
+        CopyProp { copies }
+    }
+}
+
+/// Returns `true` if a `Move` from `source` to `dest` can be propagated.
+pub fn is_copy(source: &SSAOpd, dest: &SSAOpd) -> bool {
+    let single_def = |opd: &SSAOpd| matches!(opd,
+        SSAOpd::Subscribed(_, _) | SSAOpd::Operand(Operand::Register(_)));
+    matches!(dest, SSAOpd::Subscribed(_, _)) && single_def(source) && source != dest
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use depile::ir::instr::basic::Operand;
+    use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
+    use crate::opt::copy_prop::{CopyProp, is_copy};
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::COPY_CHAIN;
+    use crate::ssa::SSAOpd;
+
+    #[test]
+    fn test_copy_chain() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(COPY_CHAIN));
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[3]).unwrap();
+        assert_eq!(expected, " 8\n");
+
+        let report = CopyProp::run_func(func);
+        assert_eq!(report.opt_count, 3);
+        let add = func.blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .find_map(|instr| match instr {
+                Instr::Binary {op: _, lhs, rhs: _} => Some(lhs.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(add, SSAOpd::Operand(Operand::Register(5)));
+        assert_eq!(interpret(func, &[3]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_is_copy() {
+        let x = |i| SSAOpd::Subscribed("x".to_string(), i);
+        assert!(is_copy(&x(0), &x(1)));
+        assert!(is_copy(&SSAOpd::Operand(Operand::Register(5)), &x(1)));
+        assert!(!is_copy(&SSAOpd::Operand(Operand::Const(5)), &x(1)));
+        assert!(!is_copy(&x(0), &SSAOpd::Undef));
+        assert!(!is_copy(&x(0), &SSAOpd::Operand(Operand::GP)));
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use crate::analysis::liveness::is_tracked;
+use crate::opt::dead_blocks::has_side_effect;
+use crate::opt::pinned::Pinned;
+use crate::ssa::{SSAFunction, SSAFunctions, SSAInstr, SSAOpd, SSAValues};
+
+/// Reports the instructions removed by [`DeadCodeElim`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub struct DeadCodeElimReport {
+    pub instr_idx: usize,
+    pub opt_count: usize,
+}
+
+impl Display for DeadCodeElimReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Function: {}", self.instr_idx)?;
+        writeln!(f, "  Number of statement removed: {}", self.opt_count)
+    }
+}
+
+/// Dead code elimination.
+///
+/// Instructions without side effects whose values are never used are
+/// removed, until no more instructions can be removed, as removing one might
+/// leave the instructions computing its operands unused.
+pub struct DeadCodeElim;
+
+impl DeadCodeElim {
+    pub fn run(funcs: &mut SSAFunctions) -> Vec<DeadCodeElimReport> {
+        funcs.functions.iter_mut().map(DeadCodeElim::run_func).collect()
+    }
+
+    pub fn run_func(func: &mut SSAFunction) -> DeadCodeElimReport {
+        DeadCodeElim::run_func_pinned(func, &Pinned::new())
+    }
+
+    /// Eliminate dead code in `func`, leaving `pinned` instructions in place
+    /// even if their values are never used.
+    pub fn run_func_pinned(func: &mut SSAFunction, pinned: &Pinned) -> DeadCodeElimReport {
+        let mut report = DeadCodeElimReport { instr_idx: func.blocks[0].first_index, opt_count: 0 };
+        loop {
+            let used = used_values(func);
+            let mut changed = false;
+            for block in func.blocks.iter_mut() {
+                for (j, instr) in block.instructions.iter_mut().enumerate() {
+                    if is_dead(instr, block.first_index + j, &used, pinned) {
+                        *instr = Instr::Nop;
+                        report.opt_count += 1;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed { return report; }
+        }
+    }
+}
+
+/// All the values used in `func`.
+pub fn used_values(func: &SSAFunction) -> BTreeSet<SSAOpd> {
+    func.blocks.iter()
+        .flat_map(|block| block.instructions.iter())
+        .flat_map(|instr| instr.used_values())
+        .cloned()
+        .collect()
+}
+
+/// Returns `true` if `instr` placed at `instr_idx` only defines values not
+/// in `used`, and has no side effect. Moves into variables not renamed are
+/// kept, as the variables might be read elsewhere, and so are `pinned`
+/// instructions.
+pub fn is_dead(instr: &SSAInstr, instr_idx: usize, used: &BTreeSet<SSAOpd>, pinned: &Pinned) -> bool {
+    let defs = instr.defined_values(instr_idx);
+    !pinned.is_pinned(instr_idx) && !has_side_effect(instr) && !defs.is_empty()
+        && defs.iter().all(|def| is_tracked(def) && !used.contains(def))
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
+    use crate::opt::dce::DeadCodeElim;
+    use crate::opt::pinned::Pinned;
+    use crate::samples::{ALL_SAMPLES, get_sample_functions};
+    use crate::samples::synthetic::{DEAD_CODE, PINNED_LOAD};
+    use crate::ssa::{SSAFunction, SSAFunctions};
+
+    #[test]
+    fn test_dead_code() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(DEAD_CODE));
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[4]).unwrap();
+        assert_eq!(expected, " 1\n");
+
+        // The chain computing `y`, and the move of the input into `z`.
+        assert_eq!(DeadCodeElim::run_func(func).opt_count, 4);
+        let instrs: Vec<_> = func.blocks.iter().flat_map(|block| block.instructions.iter()).collect();
+        assert!(instrs.iter().any(|instr| matches!(instr, Instr::Read)));
+        assert!(!instrs.iter().any(|instr| matches!(instr, Instr::Binary {..})));
+        assert_eq!(interpret(func, &[4]).unwrap(), expected);
+        assert_eq!(DeadCodeElim::run_func(func).opt_count, 0);
+    }
+
+    #[test]
+    fn test_side_effects_kept() {
+        for str in ALL_SAMPLES {
+            let (mut ssa, _) = PhiForge::run(&get_sample_functions(str));
+            let count = |ssa: &SSAFunctions| ssa.functions.iter()
+                .flat_map(|func| func.blocks.iter())
+                .flat_map(|block| block.instructions.iter())
+                .filter(|instr| matches!(instr, Instr::Write(_) | Instr::Store {..} | Instr::Read |
+                                                Instr::InterProc(_) | Instr::Branch(_)))
+                .count();
+            let before = count(&ssa);
+            DeadCodeElim::run(&mut ssa);
+            assert_eq!(count(&ssa), before);
+        }
+    }
+
+    #[test]
+    fn test_pinned_kept() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PINNED_LOAD));
+        let func = &mut ssa.functions[0];
+        // Drop the output, so that the load is never used.
+        let mut pinned = Pinned::new();
+        for block in func.blocks.iter_mut() {
+            for (j, instr) in block.instructions.iter_mut().enumerate() {
+                match instr {
+                    Instr::Load(_) => { pinned.pin(block.first_index + j); }
+                    Instr::Write(_) => *instr = Instr::Nop,
+                    _ => (),
+                }
+            }
+        }
+        let has_load = |func: &SSAFunction| func.blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .any(|instr| matches!(instr, Instr::Load(_)));
+
+        let mut unpinned = func.clone();
+        DeadCodeElim::run_func(&mut unpinned);
+        assert!(!has_load(&unpinned));
+        DeadCodeElim::run_func_pinned(func, &pinned);
+        assert!(has_load(func));
+    }
+}
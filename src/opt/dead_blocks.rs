@@ -0,0 +1,98 @@
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use crate::analysis::cfg::SimpleCfg;
+use crate::ssa::{SSAFunction, SSAFunctions, SSAInstr};
+
+/// Reports the blocks cleaned up by [`DeadBlocks`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeadBlocksReport {
+    pub instr_idx: usize,
+    pub blocks: Vec<usize>,
+    pub opt_count: usize,
+}
+
+impl Display for DeadBlocksReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Function: {}", self.instr_idx)?;
+        writeln!(f, "  Blocks never reaching an exit: {:?}", self.blocks)?;
+        writeln!(f, "  Number of statement removed: {}", self.opt_count)
+    }
+}
+
+/// Removes the code in blocks which can never reach an exit of the function.
+///
+/// Once such a block is entered the function runs forever, so the values
+/// computed there are never observed, unless the blocks have side effects.
+/// Branches are kept to preserve the shape of the CFG.
+pub struct DeadBlocks;
+
+impl DeadBlocks {
+    pub fn run(funcs: &mut SSAFunctions) -> Vec<DeadBlocksReport> {
+        funcs.functions.iter_mut().map(DeadBlocks::run_func).collect()
+    }
+
+    pub fn run_func(func: &mut SSAFunction) -> DeadBlocksReport {
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let dead = cfg.reverse_unreachable();
+        let mut report = DeadBlocksReport {
+            instr_idx: func.blocks[0].first_index,
+            blocks: dead.iter().cloned().collect(),
+            opt_count: 0,
+        };
+
+        // Successors of a block in `dead` are also in `dead`, so the values
+        // defined in them are never used elsewhere.
+        let effectful = dead.iter()
+            .flat_map(|b| func.blocks[*b].instructions.iter())
+            .any(has_side_effect);
+        if effectful { return report; }
+
+        for b in &dead {
+            for instr in func.blocks[*b].instructions.iter_mut() {
+                if !matches!(instr, Instr::Nop | Instr::Branch(_)) {
+                    *instr = Instr::Nop;
+                    report.opt_count += 1;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Returns `true` if `instr` might be observed other than through its value.
+pub fn has_side_effect(instr: &SSAInstr) -> bool {
+    match instr {
+        Instr::Binary {..} | Instr::Unary {..} | Instr::Load(_) | Instr::Move {..} |
+        Instr::Branch(_) | Instr::Nop | Instr::Extra(_) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use crate::analysis::phi::PhiForge;
+    use crate::opt::dead_blocks::DeadBlocks;
+    use crate::samples::{get_sample_functions, GCD};
+    use crate::samples::synthetic::DEAD_LOOP;
+
+    #[test]
+    fn test_dead_loop() {
+        let funcs = get_sample_functions(DEAD_LOOP);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let report = DeadBlocks::run_func(func);
+        assert_eq!(report.blocks.len(), 1);
+        // The phi node, the addition and the move.
+        assert_eq!(report.opt_count, 3);
+        let block = &func.blocks[report.blocks[0]];
+        assert!(block.instructions.iter().all(|instr| matches!(instr, Instr::Nop | Instr::Branch(_))));
+
+        let funcs = get_sample_functions(GCD);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let before = ssa.to_string();
+        let reports = DeadBlocks::run(&mut ssa);
+        assert!(reports.iter().all(|r| r.blocks.is_empty()));
+        assert_eq!(ssa.to_string(), before);
+    }
+}
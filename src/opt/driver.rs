@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+use crate::analysis::stats::count_instructions;
+use crate::opt::pass::{Pass, PassReport};
+use crate::ssa::SSAFunctions;
+
+/// A single run of a pass in the pipeline.
+pub struct PassRun {
+    /// The round this run belongs to, starting from 1.
+    pub round: usize,
+    pub pass: Pass,
+    /// Reports for each function.
+    pub reports: Vec<PassReport>,
+    pub elapsed: Duration,
+    /// Number of non-`nop` instructions removed from all functions, which
+    /// is negative if instructions are added.
+    pub removed: isize,
+    /// The IR after this run, if requested by [`Driver::dump`].
+    pub ir: Option<String>,
+}
+
+/// Whole-program optimization driver, which repeats the pipeline over all
+/// the functions until no function changes, since one pass might expose
+/// opportunities for the others.
+pub struct Driver {
+    pub passes: Vec<Pass>,
+    /// Maximum number of rounds of the whole pipeline.
+    pub max_iterations: usize,
+    /// Keep the IR after each pass in the [`PassRun`]s.
+    pub dump: bool,
+    /// Functions with more instructions are not optimized by expensive passes.
+    pub budget: Option<usize>,
+}
+
+impl Driver {
+    pub fn new(passes: Vec<Pass>, max_iterations: usize) -> Self {
+        Driver { passes, max_iterations, dump: false, budget: None }
+    }
+
+    /// Run the pipeline on `funcs` to a fixpoint, and returns the number of
+    /// rounds together with all the pass runs.
+    pub fn run(&self, funcs: &mut SSAFunctions) -> (usize, Vec<PassRun>) {
+        let mut runs = Vec::new();
+        let mut round = 0;
+        while round < self.max_iterations && !self.passes.is_empty() {
+            round += 1;
+            let before = funcs.to_string();
+            for pass in &self.passes {
+                let count = count_all_instructions(funcs);
+                let start = Instant::now();
+                let reports = pass.run_with_budget(funcs, self.budget);
+                let elapsed = start.elapsed();
+                let removed = count as isize - count_all_instructions(funcs) as isize;
+                let ir = if self.dump { Some(funcs.to_string()) } else { None };
+                runs.push(PassRun { round, pass: *pass, reports, elapsed, removed, ir });
+            }
+            if funcs.to_string() == before { break; }
+        }
+        (round, runs)
+    }
+}
+
+/// Count the non-`nop` instructions in all the functions of `funcs`.
+pub fn count_all_instructions(funcs: &SSAFunctions) -> usize {
+    funcs.functions.iter().map(count_instructions).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
+    use crate::opt::driver::{count_all_instructions, Driver};
+    use crate::opt::pass::Pass;
+    use crate::samples::{get_sample_functions, PRIME};
+    use crate::samples::synthetic::{COPY_SUB, INVARIANT};
+
+    #[test]
+    fn test_fixpoint() {
+        // `a - b` only becomes `a - a` after copy propagation, which runs
+        // after constant propagation, so it is folded in the second round,
+        // and the third one finds nothing left to change.
+        let funcs = get_sample_functions(COPY_SUB);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let expected = interpret(&ssa.functions[0], &[5]).unwrap();
+        assert_eq!(expected, " 0\n");
+
+        let (rounds, runs) = Driver::new(vec![Pass::ConstProp, Pass::CopyProp], 5).run(&mut ssa);
+        assert_eq!(rounds, 3);
+        assert_eq!(runs.len(), 6);
+        assert_eq!((runs[0].pass, runs[0].reports[0].opt_count), (Pass::ConstProp, 0));
+        assert_eq!((runs[2].round, runs[2].pass), (2, Pass::ConstProp));
+        assert_eq!(runs[2].reports[0].opt_count, 1);
+        assert_eq!(interpret(&ssa.functions[0], &[5]).unwrap(), expected);
+
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PRIME));
+        let (rounds, _) = Driver::new(vec![Pass::ConstProp], 1).run(&mut ssa);
+        assert_eq!(rounds, 1);
+    }
+
+    #[test]
+    fn test_removed() {
+        let funcs = get_sample_functions(INVARIANT);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let initial = count_all_instructions(&ssa);
+        let (_, runs) = Driver::new(vec![Pass::ConstProp, Pass::LoopInv], 1).run(&mut ssa);
+        assert!(runs.iter().all(|run| run.reports.iter().any(|r| r.opt_count > 0)));
+
+        let total: isize = runs.iter().map(|run| run.removed).sum();
+        assert_eq!(total, initial as isize - count_all_instructions(&ssa) as isize);
+        assert!(total > 0);
+    }
+}
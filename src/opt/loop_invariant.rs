@@ -1,24 +1,42 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Display;
 use depile::ir::Instr;
+use depile::ir::instr::BinaryOp;
 use depile::ir::instr::basic::Operand;
 use smallvec::alloc::fmt::Formatter;
 use crate::opt::loop_invariant::helper::Substitutable;
-use crate::analysis::natural_loop::NaturalLoop;
-use crate::ir::panning::panning_function;
-use crate::ir::insert_block::BlockInserter;
-use crate::ssa::{SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAOpd};
+use crate::analysis::alias::{AddressMap, AliasOracle, ConservativeOracle};
+use crate::analysis::cfg::{SimpleCfg, successors};
+use crate::analysis::domtree::{BlockMap, BlockSet, compute_domtree, dominate};
+use crate::analysis::natural_loop::{enclosing_loops, irreducible_headers, NaturalLoop};
+use crate::analysis::preheader::ensure_preheader;
+use crate::ir::panning::{Pannable, panning_function};
+use crate::opt::pinned::Pinned;
+use crate::ssa::{SSAFunction, SSAFunctions, SSAInstr, SSAInterProc, SSAOpd};
 
 pub struct LoopInvariantReport {
     pub instr_idx: usize,
     pub opt_count: usize,
     pub instructions: Vec<(SSAInstr, usize)>,
+    /// Headers of the loops, and whether each loop had a preheader before
+    /// LICM inserted one.
+    pub preheaders: Vec<(usize, bool)>,
+    /// Headers of the irreducible loops. Functions with such loops are left
+    /// unchanged.
+    pub irreducible: BlockSet,
 }
 
 impl Display for LoopInvariantReport {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "  Function: {}", self.instr_idx)?;
         writeln!(f, "  Number of statement hoisted: {}", self.opt_count)?;
+        for (header, has_preheader) in &self.preheaders {
+            writeln!(f, "  Loop at block {}: {}", header,
+                     if *has_preheader { "has preheader" } else { "needs preheader" })?;
+        }
+        if !self.irreducible.is_empty() {
+            writeln!(f, "  Warning: irreducible loops at blocks {:?}", self.irreducible)?;
+        }
         for (instr, id) in &self.instructions {
             writeln!(f, "  {}: {}", id, instr)?;
         }
@@ -26,13 +44,37 @@ impl Display for LoopInvariantReport {
     }
 }
 
-pub struct LoopInVariant {
+/// An instruction hoisted out of a loop.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Hoist {
+    /// Index of the instruction before hoisting.
+    pub origin: usize,
+    /// Index of the instruction in the preheader after hoisting.
+    pub target: usize,
+    /// The preheader block.
+    pub preheader: usize,
+}
+
+pub struct LoopInVariant<'a> {
     pub counter: usize,
     pub opt_instr: Vec<(SSAInstr, usize)>,
+    /// Instructions never hoisted.
+    pub pinned: Pinned,
+    /// Mapping loop headers to the preheaders inserted for them.
+    pub preheaders: BTreeMap<usize, usize>,
+    /// Decides whether a load might read memory written by a store in the
+    /// loop, which prevents hoisting the load.
+    pub oracle: &'a dyn AliasOracle,
 }
 
-impl LoopInVariant {
-    pub fn new() -> Self { LoopInVariant { counter: 0, opt_instr: Vec::new() } }
+impl<'a> LoopInVariant<'a> {
+    pub fn new() -> Self { LoopInVariant {
+        counter: 0,
+        opt_instr: Vec::new(),
+        pinned: Pinned::new(),
+        preheaders: BTreeMap::new(),
+        oracle: &ConservativeOracle,
+    } }
 
     pub fn run(funcs: &mut SSAFunctions) -> Vec<LoopInvariantReport> {
         let mut reports = Vec::new();
@@ -43,61 +85,224 @@ impl LoopInVariant {
     }
 
     pub fn run_func(func: &mut SSAFunction) -> LoopInvariantReport {
-        let mut lv = LoopInVariant::new();
-        let loops = NaturalLoop::compute_loops(func);
+        LoopInVariant::run_func_pinned(func, &Pinned::new())
+    }
 
-        for nl in &loops { BlockInserter::run(func, nl.root); }
-        // Re-compute the natural loop for inserting blocks.
-        let loops = NaturalLoop::compute_loops(func);
-        let mut changed = true;
-
-        while changed {
-            changed = false;
-
-            // For each natural loop,
-            for nl in &loops {
-                let root = nl.root;
-                let nodes = &nl.nodes;
-
-                // Get the definitions in these blocks.
-                let mut defs = BTreeSet::new();
-                for n in nodes {
-                    helper::get_defs(&func.blocks[*n], &mut defs);
-                }
-
-                // Find invariant instruction.
-                let mut res: Option<(SSAInstr, usize)> = None;
-                for n in nodes {
-                    let mut block = &mut func.blocks[*n];
-                    res = lv.invariant_block(&mut block, &defs);
-                    if res.is_some() { break; }
-                }
-                if res.is_none() { continue; }
-
-                // Substitution
-                lv.counter += 1;
-                changed = true;
-                let (instr, instr_idx) = res.unwrap();
-                lv.opt_instr.push((instr.clone(), instr_idx.clone()));
-                let src = SSAOpd::Operand(Operand::Register(instr_idx));
-                let tgt = lv.compute_target_opd(func, root);
-                for block in &mut func.blocks {
-                    block.subst(&src, &tgt);
-                }
-                lv.push_invariant_instr(func, instr, root);
-                break;
-            }
+    /// Perform loop invariant code motion on `func`, leaving `pinned`
+    /// instructions in place.
+    pub fn run_func_pinned(func: &mut SSAFunction, pinned: &Pinned) -> LoopInvariantReport {
+        LoopInVariant::run_func_with(func, pinned, &ConservativeOracle)
+    }
+
+    /// Same as [`LoopInVariant::run_func_pinned`], consulting `oracle` before
+    /// hoisting loads.
+    pub fn run_func_with(func: &mut SSAFunction, pinned: &Pinned, oracle: &dyn AliasOracle) -> LoopInvariantReport {
+        let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+        let mut preheaders: Vec<(usize, bool)> = NaturalLoop::compute_loops(func).iter()
+            .map(|nl| (nl.root, nl.preheader(&cfg).is_some()))
+            .collect();
+        preheaders.sort();
+        preheaders.dedup();
+        let irreducible = irreducible_headers(func);
+
+        let mut lv = LoopInVariant::new();
+        lv.pinned = pinned.clone();
+        lv.oracle = oracle;
+        // Cycles with several entries have no preheader to hoist into.
+        if irreducible.is_empty() {
+            let loops = lv.insert_preheaders(func);
+            while !lv.hoist_all(func, &loops).is_empty() { }
         }
 
         LoopInvariantReport {
             instr_idx: func.blocks[0].first_index,
             opt_count: lv.counter,
             instructions: lv.opt_instr,
+            preheaders,
+            irreducible,
+        }
+    }
+
+    /// Ensure a preheader for each natural loop in `func`, and returns the
+    /// natural loops re-computed after insertion.
+    pub fn insert_preheaders(&mut self, func: &mut SSAFunction) -> Vec<NaturalLoop> {
+        let roots: BTreeSet<usize> = NaturalLoop::compute_loops(func).iter().map(|nl| nl.root).collect();
+        self.preheaders.clear();
+        // Inserting a block shifts all the blocks after it, so the loops are
+        // handled from the last one, and the preheaders found are shifted.
+        for root in roots.into_iter().rev() {
+            // Loops sharing a header share the preheader as well.
+            let mut loops = NaturalLoop::compute_loops(func).into_iter().filter(|nl| nl.root == root);
+            let mut nl = loops.next().unwrap();
+            for other in loops { nl.nodes.extend(other.nodes); }
+
+            let count = func.blocks.len();
+            let preheader = ensure_preheader(func, &nl);
+            if func.blocks.len() == count {
+                self.preheaders.insert(root, preheader);
+                continue;
+            }
+            // More than one block is inserted before a header with a latch
+            // falling through into it, see `ensure_preheader`.
+            let added = func.blocks.len() - count;
+            let shift = |b: usize| if b >= root { b + added } else { b };
+            self.preheaders = self.preheaders.iter()
+                .map(|(h, p)| (shift(*h), shift(*p)))
+                .collect();
+            self.preheaders.insert(preheader + 1, preheader);
         }
+        // Re-compute the natural loop for inserting blocks.
+        NaturalLoop::compute_loops(func)
     }
 
-    fn push_invariant_instr(&self, func: &mut SSAFunction, instr: SSAInstr, root: usize) {
-        let block = &mut func.blocks[root - 1];
+    /// Hoist one invariant instruction out of `loops` into the preheader of
+    /// the outermost loop in which it is still invariant. Returns `None` if
+    /// there is no invariant instruction left.
+    pub fn hoist_once(&mut self, func: &mut SSAFunction, loops: &[NaturalLoop]) -> Option<Hoist> {
+        let domtree = compute_domtree(func);
+
+        // For each natural loop,
+        for nl in loops {
+            // Find invariant instruction.
+            let info = LoopInfo::new(func, nl, &domtree);
+            let (n, j) = match self.find_hoistable(func, nl, &info) {
+                Some(found) => found,
+                None => continue,
+            };
+            let instr_idx = func.blocks[n].first_index + j;
+
+            // Loops enclosing `nl` also contain block `n`. Operands defined
+            // in an outer loop are defined in the inner ones as well, so the
+            // instruction is invariant from `nl` up to some outer loop.
+            let target = enclosing_loops(nl, loops).into_iter()
+                .find(|outer| {
+                    let info = LoopInfo::new(func, outer, &domtree);
+                    self.is_hoistable(&info, n, instr_idx, &func.blocks[n].instructions[j])
+                })
+                .unwrap_or(nl);
+            let instr = std::mem::replace(&mut func.blocks[n].instructions[j], Instr::Nop);
+
+            // Substitution
+            let preheader = self.preheader_of(func, target);
+            self.counter += 1;
+            self.opt_instr.push((instr.clone(), instr_idx));
+            let src = SSAOpd::Operand(Operand::Register(instr_idx));
+            let tgt = self.compute_target_opd(func, preheader);
+            for block in &mut func.blocks {
+                block.subst(&src, &tgt);
+            }
+            self.push_invariant_instr(func, instr, preheader);
+
+            let block = &func.blocks[preheader];
+            let target = block.first_index + block.instructions.len() - 1;
+            self.pinned.shift_from(target);
+            return Some(Hoist { origin: instr_idx, target, preheader });
+        }
+        None
+    }
+
+    /// Hoist the invariant instructions out of each loop in `loops` at once,
+    /// see [`LoopInVariant::hoist_loop`]. Outer loops are handled first, so
+    /// that instructions invariant in several nested loops are hoisted into
+    /// the preheader of the outermost one.
+    pub fn hoist_all(&mut self, func: &mut SSAFunction, loops: &[NaturalLoop]) -> Vec<Hoist> {
+        let mut order: Vec<&NaturalLoop> = loops.iter().collect();
+        order.sort_by_key(|nl| std::cmp::Reverse(nl.nodes.len()));
+        let mut hoists = Vec::new();
+        for nl in order {
+            hoists.extend(self.hoist_loop(func, nl));
+        }
+        hoists
+    }
+
+    /// Finds the first hoistable instruction in `nl`, as a pair of its block
+    /// and its offset in the block.
+    fn find_hoistable(&self, func: &SSAFunction, nl: &NaturalLoop, info: &LoopInfo) -> Option<(usize, usize)> {
+        nl.nodes.iter().find_map(|n| {
+            let block = &func.blocks[*n];
+            block.instructions.iter().enumerate()
+                .find(|(j, instr)| self.is_hoistable(info, *n, block.first_index + j, instr))
+                .map(|(j, _)| (*n, j))
+        })
+    }
+
+    /// The preheader inserted for `nl` by `insert_preheaders`.
+    fn preheader_of(&self, func: &SSAFunction, nl: &NaturalLoop) -> usize {
+        let root = nl.root;
+        let preheader = *self.preheaders.get(&root)
+            .expect("loop without preheader, run `insert_preheaders` first");
+        debug_assert_eq!(find_preheader(func, nl), Some(preheader));
+        debug_assert!(dominate(&compute_domtree(func), preheader, root),
+                      "preheader {} does not dominate loop header {}", preheader, root);
+        preheader
+    }
+
+    /// Hoist all the invariant instructions out of `nl` into its preheader,
+    /// renumbering the function only once. An instruction becomes invariant
+    /// once its operands are, so the instructions are collected until no
+    /// more is found, in an order respecting their dependencies.
+    pub fn hoist_loop(&mut self, func: &mut SSAFunction, nl: &NaturalLoop) -> Vec<Hoist> {
+        let domtree = compute_domtree(func);
+        let mut info = LoopInfo::new(func, nl, &domtree);
+        let mut found: Vec<(SSAInstr, usize)> = Vec::new();
+        loop {
+            let (n, j) = match self.find_hoistable(func, nl, &info) {
+                Some(found) => found,
+                None => break,
+            };
+            let instr_idx = func.blocks[n].first_index + j;
+            let instr = std::mem::replace(&mut func.blocks[n].instructions[j], Instr::Nop);
+            info.defs.remove(&SSAOpd::Operand(Operand::Register(instr_idx)));
+            found.push((instr, instr_idx));
+        }
+        if found.is_empty() { return Vec::new(); }
+
+        let preheader = self.preheader_of(func, nl);
+
+        // The hoisted instructions are appended to the preheader, shifting
+        // the instructions after it. Their original places are left as `nop`s.
+        let block = &func.blocks[preheader];
+        let end = block.first_index + block.instructions.len();
+        let count = found.len();
+        let targets: BTreeMap<usize, usize> = found.iter().enumerate()
+            .map(|(i, (_, instr_idx))| (*instr_idx, end + i))
+            .collect();
+        let renumber = |x: usize| match targets.get(&x) {
+            Some(target) => *target,
+            None => if x >= end { x + count } else { x },
+        };
+        for (b, block) in func.blocks.iter_mut().enumerate() {
+            let mut instrs: Vec<SSAInstr> = block.instructions.iter().map(|instr| instr.pan(&renumber)).collect();
+            if b == preheader {
+                instrs.extend(found.iter().map(|(instr, _)| instr.pan(&renumber)));
+            }
+            block.instructions = instrs.into_boxed_slice();
+            if b > preheader { block.first_index += count; }
+        }
+
+        let mut hoists = Vec::new();
+        for (instr, instr_idx) in found {
+            let target = targets[&instr_idx];
+            self.pinned.shift_from(target);
+            self.counter += 1;
+            self.opt_instr.push((instr, instr_idx));
+            hoists.push(Hoist { origin: instr_idx, target, preheader });
+        }
+        hoists
+    }
+
+    /// Returns `true` if `instr` at `instr_idx` in block `n` can be hoisted
+    /// out of the loop described by `info`. Instructions in blocks not
+    /// executed on every way out of the loop are guarded, e.g. by a condition
+    /// in the loop body, and are only hoisted if they never fault.
+    fn is_hoistable(&self, info: &LoopInfo, n: usize, instr_idx: usize, instr: &SSAInstr) -> bool {
+        !self.pinned.is_pinned(instr_idx)
+            && self.check_invariant_instr(instr, &info.defs, &info.stores, info.calls)
+            && (!info.guarded.contains(&n) || never_faults(instr, &info.fixed))
+    }
+
+    fn push_invariant_instr(&self, func: &mut SSAFunction, instr: SSAInstr, preheader: usize) {
+        let block = &mut func.blocks[preheader];
         let mut instrs = std::mem::take(&mut block.instructions).into_vec();
         instrs.push(instr);
         block.instructions = instrs.into_boxed_slice();
@@ -105,38 +310,26 @@ impl LoopInVariant {
     }
 
     /// Compute the index of target instruction.
-    fn compute_target_opd(&self, func: &SSAFunction, root: usize) -> SSAOpd {
-        let block = &func.blocks[root - 1];
+    fn compute_target_opd(&self, func: &SSAFunction, preheader: usize) -> SSAOpd {
+        let block = &func.blocks[preheader];
         let target_idx = block.first_index + block.instructions.len();
         // This is dirty for the following panning.
         SSAOpd::Operand(Operand::Register(target_idx - 1))
     }
 
-    /// Find invariant code in a `block` according to `defs`.
-    fn invariant_block(&self, block: &mut SSABlock, defs: &BTreeSet<SSAOpd>) -> Option<(SSAInstr, usize)> {
-        let mut instr_index = block.first_index;
-        for instr in block.instructions.iter_mut() {
-            if self.check_invariant_instr(instr, &defs) {
-                let instr_ = instr.clone();
-                *instr = Instr::Nop;
-                return Some((instr_, instr_index));
-            }
-            instr_index += 1;
-        }
-        None
-    }
-
-    /// Check whether an `instr`uction is invariant according to `defs`.
-    fn check_invariant_instr(&self, instr: &SSAInstr, defs: &BTreeSet<SSAOpd>) -> bool {
+    /// Check whether an `instr`uction is invariant according to `defs`. Loads
+    /// are not if they might alias any of the `stores` in the loop, or if the
+    /// loop `calls` a function, which might store anywhere. Stores and other
+    /// instructions with side effects are never hoisted, as they would be
+    /// reordered with the memory accesses and outputs in the loop.
+    fn check_invariant_instr(&self, instr: &SSAInstr, defs: &BTreeSet<SSAOpd>, stores: &[SSAOpd], calls: bool) -> bool {
         match instr {
             Instr::Binary {op: _, lhs, rhs} =>
                 !defs.contains(lhs) && !defs.contains(rhs),
             Instr::Unary {op: _, operand} =>
                 !defs.contains(operand),
             Instr::Load(opd) =>
-                !defs.contains(opd),
-            Instr::Store {data, address} =>
-                !defs.contains(data) && !defs.contains(address),
+                !defs.contains(opd) && !calls && !stores.iter().any(|addr| self.oracle.may_alias(opd, addr)),
             Instr::Move {source, dest} =>
                 !defs.contains(source) && !defs.contains(dest),
             _ => false
@@ -144,16 +337,130 @@ impl LoopInVariant {
     }
 }
 
+/// Facts about a loop deciding which instructions can be hoisted out of it.
+struct LoopInfo {
+    defs: BTreeSet<SSAOpd>,
+    stores: Vec<SSAOpd>,
+    calls: bool,
+    fixed: BTreeSet<SSAOpd>,
+    /// Blocks of the loop not dominating all the exits and latches, i.e. not
+    /// executed on every iteration.
+    guarded: BlockSet,
+}
+
+impl LoopInfo {
+    fn new(func: &SSAFunction, nl: &NaturalLoop, domtree: &BlockMap) -> Self {
+        // A loop without exits still runs the blocks on the way to every
+        // latch on each iteration.
+        let mut ends = loop_exits(func, nl);
+        ends.extend(loop_latches(func, nl));
+        LoopInfo {
+            defs: loop_defs(func, nl),
+            stores: loop_stores(func, nl),
+            calls: loop_has_call(func, nl),
+            fixed: fixed_loads(func, nl),
+            guarded: nl.nodes.iter()
+                .filter(|n| !ends.iter().all(|e| dominate(domtree, **n, *e)))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Values defined in the blocks of `nl`, including registers of all the
+/// instructions and destinations of moves and phi nodes.
+pub fn loop_defs(func: &SSAFunction, nl: &NaturalLoop) -> BTreeSet<SSAOpd> {
+    let mut defs = BTreeSet::new();
+    for n in &nl.nodes {
+        helper::get_defs(&func.blocks[*n], &mut defs);
+    }
+    defs
+}
+
+/// Addresses stored to in the blocks of `nl`.
+pub fn loop_stores(func: &SSAFunction, nl: &NaturalLoop) -> Vec<SSAOpd> {
+    nl.nodes.iter()
+        .flat_map(|n| func.blocks[*n].instructions.iter())
+        .filter_map(|instr| match instr {
+            Instr::Store {data: _, address} => Some(address.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns `true` if there is a call in the blocks of `nl`.
+pub fn loop_has_call(func: &SSAFunction, nl: &NaturalLoop) -> bool {
+    nl.nodes.iter()
+        .flat_map(|n| func.blocks[*n].instructions.iter())
+        .any(|instr| matches!(instr, Instr::InterProc(SSAInterProc::Call {..})))
+}
+
+/// Blocks of `nl` with a successor outside the loop.
+pub fn loop_exits(func: &SSAFunction, nl: &NaturalLoop) -> BlockSet {
+    nl.nodes.iter()
+        .filter(|n| successors(func.blocks.as_slice(), **n).ordered().iter().any(|s| !nl.nodes.contains(s)))
+        .cloned()
+        .collect()
+}
+
+/// Blocks of `nl` with an edge back to its header.
+pub fn loop_latches(func: &SSAFunction, nl: &NaturalLoop) -> BlockSet {
+    nl.nodes.iter()
+        .filter(|n| successors(func.blocks.as_slice(), **n).ordered().contains(&nl.root))
+        .cloned()
+        .collect()
+}
+
+/// Addresses loaded from in the blocks of `nl` which are fixed locations,
+/// i.e. symbolic bases plus a constant offset, such as a global variable.
+pub fn fixed_loads(func: &SSAFunction, nl: &NaturalLoop) -> BTreeSet<SSAOpd> {
+    let addresses = AddressMap::new(func);
+    nl.nodes.iter()
+        .flat_map(|n| func.blocks[*n].instructions.iter())
+        .filter_map(|instr| match instr {
+            Instr::Load(opd) if addresses.decompose(opd).index.is_empty() => Some(opd.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns `true` if `instr` can be executed speculatively, i.e. it has no
+/// side effect and never faults. Divisions might divide by zero unless the
+/// divisor is a non-zero constant, and loads might fault unless the address
+/// is one of the `fixed` locations.
+pub fn never_faults(instr: &SSAInstr, fixed: &BTreeSet<SSAOpd>) -> bool {
+    match instr {
+        Instr::Binary {op: BinaryOp::Div | BinaryOp::Mod, lhs: _, rhs} =>
+            matches!(rhs, SSAOpd::Operand(Operand::Const(c)) if *c != 0),
+        Instr::Binary {..} | Instr::Unary {..} | Instr::Move {..} => true,
+        Instr::Load(opd) => fixed.contains(opd),
+        _ => false,
+    }
+}
+
+/// Returns `true` if `opd` is invariant in `nl`, i.e. it is a constant or is
+/// defined outside the loop.
+pub fn is_loop_invariant(func: &SSAFunction, nl: &NaturalLoop, opd: &SSAOpd) -> bool {
+    !loop_defs(func, nl).contains(opd)
+}
+
+/// Find the preheader of `nl` in `func`, which falls through into the header
+/// so that instructions can be appended to it.
+pub fn find_preheader(func: &SSAFunction, nl: &NaturalLoop) -> Option<usize> {
+    let cfg = SimpleCfg::from(func.entry_block, func.blocks.as_slice());
+    nl.preheader(&cfg)
+        .filter(|p| successors(func.blocks.as_slice(), *p).taken.is_none())
+}
+
 mod helper {
     use std::collections::BTreeSet;
     use depile::ir::Instr;
     use depile::ir::instr::basic::Operand;
     use depile::ir::instr::BranchKind;
-    use crate::ssa::{Phi, SSABlock, SSAInstr, SSAInterProc, SSAOpd};
+    use crate::ssa::{IndexedInstructions, Phi, SSABlock, SSAInstr, SSAInterProc, SSAOpd};
 
     pub fn get_defs(block: &SSABlock, defs: &mut BTreeSet<SSAOpd>) {
-        let mut instr_index = block.first_index;
-        for instr in block.instructions.iter() {
+        for (instr_index, instr) in block.indexed_instructions() {
             defs.insert(SSAOpd::Operand(Operand::Register(instr_index)));
             match instr {
                 SSAInstr::Move {source: _, dest} =>
@@ -162,7 +469,6 @@ mod helper {
                     { defs.insert(dest.clone()); }
                 _ => (),
             }
-            instr_index += 1;
         }
     }
 
@@ -224,9 +530,18 @@ mod helper {
 mod test {
     use std::io::Write;
     use std::io::BufWriter;
-    use crate::opt::loop_invariant::LoopInVariant;
+    use depile::ir::Instr;
+    use depile::ir::instr::basic::Operand;
+    use crate::analysis::alias::AliasOracle;
+    use crate::opt::loop_invariant::{find_preheader, is_loop_invariant, LoopInVariant};
+    use crate::opt::pinned::Pinned;
+    use crate::analysis::domtree::{BlockSet, compute_domtree, dominate};
+    use crate::analysis::natural_loop::NaturalLoop;
     use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
     use crate::samples::{ALL_SAMPLES, COLLATZ, get_sample_functions};
+    use crate::samples::synthetic::{GUARDED_DIV, INVARIANT, IRREDUCIBLE, JUMP_ENTRY, LOOP_CALL, NESTED_LOOPS, NO_EXIT, PINNED_LOAD, STORE_HEADER, STORE_LOAD, TWO_LOOPS};
+    use crate::ssa::SSAOpd;
 
     #[test]
     fn test_loop() {
@@ -253,4 +568,249 @@ mod test {
             write!(&mut writer, "{}", ssa).unwrap();
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pinned_load() {
+        let funcs = get_sample_functions(PINNED_LOAD);
+        let (ssa, _) = PhiForge::run(&funcs);
+
+        let mut func = ssa.functions[0].clone();
+        let report = LoopInVariant::run_func(&mut func);
+        assert!(report.instructions.iter().any(|(instr, _)| matches!(instr, Instr::Load(_))));
+
+        let mut func = ssa.functions[0].clone();
+        let mut pinned = Pinned::new();
+        for block in &func.blocks {
+            for (j, instr) in block.instructions.iter().enumerate() {
+                if let Instr::Load(_) = instr { pinned.pin(block.first_index + j); }
+            }
+        }
+        let report = LoopInVariant::run_func_pinned(&mut func, &pinned);
+        assert!(report.instructions.iter().all(|(instr, _)| !matches!(instr, Instr::Load(_))));
+    }
+
+    #[test]
+    fn test_find_preheader() {
+        let funcs = get_sample_functions(JUMP_ENTRY);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+
+        // The loop is entered by a branch, so the block before its header is
+        // not the preheader.
+        let loops = NaturalLoop::compute_loops(func);
+        let root = loops[0].root;
+        assert_eq!(find_preheader(func, &loops[0]), None);
+        assert!(!dominate(&compute_domtree(func), root - 1, root));
+
+        let mut lv = LoopInVariant::new();
+        let loops = lv.insert_preheaders(func);
+        let preheader = find_preheader(func, &loops[0]).unwrap();
+        assert!(dominate(&compute_domtree(func), preheader, loops[0].root));
+        let hoist = lv.hoist_once(func, &loops).unwrap();
+        assert_eq!(hoist.preheader, preheader);
+        let block = &func.blocks[preheader];
+        assert!(matches!(block.instructions[hoist.target - block.first_index], Instr::Binary {..}));
+    }
+
+    #[test]
+    fn test_two_loops() {
+        let funcs = get_sample_functions(TWO_LOOPS);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let blocks = func.blocks.len();
+        let roots: Vec<usize> = NaturalLoop::compute_loops(func).iter().map(|nl| nl.root).collect();
+
+        let mut lv = LoopInVariant::new();
+        let loops = lv.insert_preheaders(func);
+        // Both loops are entered by falling through, so no block is inserted.
+        assert_eq!(func.blocks.len(), blocks);
+        assert_eq!(lv.preheaders.keys().cloned().collect::<Vec<_>>(), roots);
+        while lv.hoist_once(func, &loops).is_some() { }
+
+        let hoisted: Vec<String> = lv.preheaders.values()
+            .map(|p| func.blocks[*p].instructions.last().unwrap().to_string())
+            .collect();
+        assert_eq!(hoisted, vec!["mul n$0 2".to_string(), "mul n$0 3".to_string()]);
+    }
+
+    #[test]
+    fn test_hoist_loop() {
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PINNED_LOAD));
+        let origin = ssa.functions[0].clone();
+        let func = &mut ssa.functions[0];
+        let mut lv = LoopInVariant::new();
+        let loops = lv.insert_preheaders(func);
+
+        // The load depends on the address computed in the loop, and both are
+        // hoisted at once.
+        let hoists = lv.hoist_loop(func, &loops[0]);
+        assert_eq!(hoists.len(), 2);
+        assert_eq!(hoists[1].target, hoists[0].target + 1);
+        let block = &func.blocks[hoists[0].preheader];
+        let hoisted: Vec<String> = block.instructions[block.instructions.len() - 2..].iter()
+            .map(|instr| instr.to_string())
+            .collect();
+        assert_eq!(hoisted, vec!["add a_base#32760 GP".to_string(), format!("load ({})", hoists[0].target)]);
+        assert!(lv.hoist_loop(func, &loops[0]).is_empty());
+
+        let report = LoopInVariant::run_func(&mut origin.clone());
+        assert_eq!(report.opt_count, 2);
+    }
+
+    #[test]
+    fn test_is_loop_invariant() {
+        let funcs = get_sample_functions(INVARIANT);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let func = &ssa.functions[0];
+        let loops = NaturalLoop::compute_loops(func);
+        let nl = &loops[0];
+
+        let header = &func.blocks[nl.root];
+        let i = match &header.instructions[0] {
+            Instr::Extra(phi) => phi.dest.clone(),
+            _ => panic!("expect a phi node for `i` in the loop header"),
+        };
+        assert!(!is_loop_invariant(func, nl, &i));
+        assert!(!is_loop_invariant(func, nl, &SSAOpd::Operand(Operand::Register(header.first_index + 1))));
+        assert!(is_loop_invariant(func, nl, &SSAOpd::Subscribed("n".to_string(), 0)));
+        assert!(is_loop_invariant(func, nl, &SSAOpd::Operand(Operand::Const(2))));
+    }
+
+    #[test]
+    fn test_report_preheaders() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(INVARIANT));
+        let report = LoopInVariant::run_func(&mut ssa.functions[0].clone());
+        assert!(report.preheaders.iter().all(|(_, has)| *has));
+        assert!(report.to_string().contains("has preheader"));
+
+        let (ssa, _) = PhiForge::run(&get_sample_functions(JUMP_ENTRY));
+        let report = LoopInVariant::run_func(&mut ssa.functions[0].clone());
+        assert_eq!(report.preheaders.len(), 1);
+        assert!(!report.preheaders[0].1);
+        assert!(report.to_string().contains("needs preheader"));
+    }
+
+    /// Knows that the arrays `a` and `b` never overlap.
+    struct NoOverlap;
+
+    impl AliasOracle for NoOverlap {
+        fn may_alias(&self, _: &SSAOpd, _: &SSAOpd) -> bool { false }
+    }
+
+    #[test]
+    fn test_alias_oracle() {
+        let funcs = get_sample_functions(STORE_LOAD);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let is_load = |(instr, _): &(Instr<_>, usize)| matches!(instr, Instr::Load(_));
+
+        // The store to `b` in the loop might overwrite `a` by default.
+        let mut func = ssa.functions[0].clone();
+        let report = LoopInVariant::run_func(&mut func);
+        assert!(!report.instructions.iter().any(is_load));
+
+        let mut func = ssa.functions[0].clone();
+        let report = LoopInVariant::run_func_with(&mut func, &Pinned::new(), &NoOverlap);
+        assert!(report.instructions.iter().any(is_load));
+    }
+
+    #[test]
+    fn test_guarded_division() {
+        // `n / d` is only computed in the loop if `d` is not zero, while
+        // `n + 1` is computed anyway.
+        let funcs = get_sample_functions(GUARDED_DIV);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let mut func = ssa.functions[0].clone();
+        let report = LoopInVariant::run_func(&mut func);
+        let hoisted: Vec<String> = report.instructions.iter().map(|(instr, _)| instr.to_string()).collect();
+        assert!(hoisted.contains(&"add n$0 1".to_string()));
+        assert!(!hoisted.iter().any(|instr| instr.starts_with("div")));
+
+        for input in [0, 7] {
+            assert_eq!(interpret(&func, &[input]), interpret(&ssa.functions[0], &[input]));
+        }
+    }
+
+    #[test]
+    fn test_keep_store() {
+        // The store to `a` in the header has invariant operands, but the
+        // store in the body must not win on the last iteration.
+        let funcs = get_sample_functions(STORE_HEADER);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let mut func = ssa.functions[0].clone();
+        let root = NaturalLoop::compute_loops(&func)[0].root;
+        let report = LoopInVariant::run_func(&mut func);
+        assert!(!report.instructions.iter().any(|(instr, _)| matches!(instr, Instr::Store {..})));
+
+        let root = root + func.blocks.len() - ssa.functions[0].blocks.len();
+        assert!(func.blocks[root].instructions.iter().any(|instr| matches!(instr, Instr::Store {..})));
+        assert_eq!(interpret(&func, &[]).unwrap(), " 1\n");
+    }
+
+    #[test]
+    fn test_no_exit() {
+        // The loop never exits, and `100 / d` is only computed if `d` is not
+        // zero, while the check itself is computed on every iteration.
+        let funcs = get_sample_functions(NO_EXIT);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let mut func = ssa.functions[0].clone();
+        let report = LoopInVariant::run_func(&mut func);
+        let hoisted: Vec<String> = report.instructions.iter().map(|(instr, _)| instr.to_string()).collect();
+        assert!(hoisted.iter().any(|instr| instr.starts_with("cmpeq")));
+        assert!(!hoisted.iter().any(|instr| instr.starts_with("div")));
+    }
+
+    #[test]
+    fn test_hoist_load() {
+        // Nothing is stored in the loop, so the load of `a` is hoisted into
+        // the preheader, together with its address.
+        let funcs = get_sample_functions(PINNED_LOAD);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let mut lv = LoopInVariant::new();
+        let loops = lv.insert_preheaders(func);
+        while lv.hoist_once(func, &loops).is_some() { }
+        let preheader = *lv.preheaders.values().next().unwrap();
+        let hoisted: Vec<String> = func.blocks[preheader].instructions.iter().map(|instr| instr.to_string()).collect();
+        let hoisted = &hoisted[hoisted.len() - 2..];
+        assert!(hoisted[0].starts_with("add a_base"));
+        assert!(hoisted[1].starts_with("load"));
+
+        // The function called in the loop stores to `a`.
+        let funcs = get_sample_functions(LOOP_CALL);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let report = LoopInVariant::run_func(&mut ssa.functions[1]);
+        assert!(report.instructions.iter().any(|(instr, _)| matches!(instr, Instr::Binary {..})));
+        assert!(!report.instructions.iter().any(|(instr, _)| matches!(instr, Instr::Load(_))));
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        // `n * 2` in the inner loop does not depend on either loop.
+        let funcs = get_sample_functions(NESTED_LOOPS);
+        let (ssa, _) = PhiForge::run(&funcs);
+        let mut func = ssa.functions[0].clone();
+        let mut lv = LoopInVariant::new();
+        let loops = lv.insert_preheaders(&mut func);
+        let hoist = lv.hoist_once(&mut func, &loops).unwrap();
+
+        let outer = loops.iter().max_by_key(|nl| nl.nodes.len()).unwrap();
+        let inner = loops.iter().min_by_key(|nl| nl.nodes.len()).unwrap();
+        assert_eq!(hoist.preheader, lv.preheaders[&outer.root]);
+        let block = &func.blocks[hoist.preheader];
+        assert_eq!(block.instructions[hoist.target - block.first_index].to_string(), "mul n$0 2");
+        assert!(func.blocks[lv.preheaders[&inner.root]].instructions.iter().all(|instr| instr.to_string() != "mul n$0 2"));
+
+        while lv.hoist_once(&mut func, &loops).is_some() { }
+        assert_eq!(interpret(&func, &[]), interpret(&ssa.functions[0], &[]));
+    }
+
+    #[test]
+    fn test_irreducible() {
+        let (ssa, _) = PhiForge::run(&get_sample_functions(IRREDUCIBLE));
+        let mut func = ssa.functions[0].clone();
+        let report = LoopInVariant::run_func(&mut func);
+        assert_eq!(report.opt_count, 0);
+        assert_eq!(func.blocks.len(), ssa.functions[0].blocks.len());
+        assert!(report.to_string().contains("Warning: irreducible loops at blocks {1, 2}"));
+    }
+}
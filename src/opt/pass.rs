@@ -0,0 +1,128 @@
+use std::fmt::Formatter;
+use parse_display::Display;
+use crate::opt::const_prop::ConstProp;
+use crate::opt::copy_prop::CopyProp;
+use crate::opt::dce::DeadCodeElim;
+use crate::opt::loop_invariant::LoopInVariant;
+use crate::opt::sccp::SCCP;
+use crate::analysis::stats::count_instructions;
+use crate::ssa::{SSAFunction, SSAFunctions};
+
+/// Report of running a pass on a function.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PassReport {
+    /// Number of statements optimized.
+    pub opt_count: usize,
+    /// The report in text.
+    pub text: String,
+}
+
+impl std::fmt::Display for PassReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Optimization passes to be scheduled in a pipeline.
+#[derive(Debug, Display, Copy, Clone, Eq, PartialEq)]
+pub enum Pass {
+    /// Constant propagation.
+    #[display("constant propagation")]
+    ConstProp,
+    /// Sparse conditional constant propagation.
+    #[display("sparse conditional constant propagation")]
+    Sccp,
+    /// Copy propagation.
+    #[display("copy propagation")]
+    CopyProp,
+    /// Dead code elimination.
+    #[display("dead code elimination")]
+    Dce,
+    /// Loop invariant code motion.
+    #[display("loop invariant")]
+    LoopInv,
+}
+
+impl Pass {
+    /// Expensive passes are skipped on functions over the optimization budget.
+    pub fn is_expensive(&self) -> bool {
+        match self {
+            Pass::ConstProp | Pass::Sccp | Pass::CopyProp | Pass::Dce => false,
+            Pass::LoopInv => true,
+        }
+    }
+
+    /// Run this pass on all the functions in `funcs`, and returns the reports.
+    pub fn run(&self, funcs: &mut SSAFunctions) -> Vec<PassReport> {
+        self.run_with_budget(funcs, None)
+    }
+
+    /// Run this pass on all the functions in `funcs`, skipping the functions
+    /// with more than `budget` instructions if this pass is expensive.
+    pub fn run_with_budget(&self, funcs: &mut SSAFunctions, budget: Option<usize>) -> Vec<PassReport> {
+        let mut reports = Vec::new();
+        for func in funcs.functions.iter_mut() {
+            let size = count_instructions(func);
+            match budget {
+                Some(budget) if self.is_expensive() && size > budget =>
+                    reports.push(PassReport {
+                        opt_count: 0,
+                        text: format!("  Function: {}\n  Skipped: {} instructions over budget {}\n",
+                                      func.blocks[0].first_index, size, budget),
+                    }),
+                _ => reports.push(self.run_func(func)),
+            }
+        }
+        reports
+    }
+
+    /// Run this pass on a single function, and returns the report.
+    pub fn run_func(&self, func: &mut SSAFunction) -> PassReport {
+        match self {
+            Pass::ConstProp => {
+                let report = ConstProp::run_func(func);
+                PassReport { opt_count: report.opt_count, text: report.to_string() }
+            }
+            Pass::Sccp => {
+                let report = SCCP::run_func(func);
+                PassReport { opt_count: report.opt_count, text: report.to_string() }
+            }
+            Pass::CopyProp => {
+                let report = CopyProp::run_func(func);
+                PassReport { opt_count: report.opt_count, text: report.to_string() }
+            }
+            Pass::Dce => {
+                let report = DeadCodeElim::run_func(func);
+                PassReport { opt_count: report.opt_count, text: report.to_string() }
+            }
+            Pass::LoopInv => {
+                let report = LoopInVariant::run_func(func);
+                PassReport { opt_count: report.opt_count, text: report.to_string() }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analysis::phi::PhiForge;
+    use crate::analysis::stats::count_instructions;
+    use crate::opt::pass::Pass;
+    use crate::samples::{get_sample_functions, GCD};
+
+    #[test]
+    fn test_budget() {
+        // In GCD, `main` is larger than `gcd`.
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(GCD));
+        let sizes: Vec<usize> = ssa.functions.iter().map(count_instructions).collect();
+        let budget = *sizes.iter().min().unwrap();
+        assert!(sizes.iter().any(|s| *s > budget));
+
+        let reports = Pass::LoopInv.run_with_budget(&mut ssa, Some(budget));
+        for (size, report) in sizes.iter().zip(reports.iter()) {
+            assert_eq!(*size > budget, report.text.contains("Skipped"));
+        }
+        let reports = Pass::ConstProp.run_with_budget(&mut ssa, Some(budget));
+        assert!(reports.iter().all(|r| !r.text.contains("Skipped")));
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::BTreeSet;
+
+/// Instructions that must stay in place, such as volatile-like loads or
+/// ordered writes. Passes moving or removing instructions consult this set
+/// and leave pinned instructions untouched.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Pinned {
+    /// Indices of pinned instructions.
+    pub indices: BTreeSet<usize>,
+}
+
+impl Pinned {
+    pub fn new() -> Self { Pinned { indices: BTreeSet::new() } }
+
+    /// Pin the instruction at `instr_idx`.
+    pub fn pin(&mut self, instr_idx: usize) -> bool {
+        self.indices.insert(instr_idx)
+    }
+
+    /// Returns `true` if the instruction at `instr_idx` is pinned.
+    pub fn is_pinned(&self, instr_idx: usize) -> bool {
+        self.indices.contains(&instr_idx)
+    }
+
+    /// Keep track of pinned instructions after a new instruction is inserted
+    /// at `instr_idx`, which shifts all the following instructions by one.
+    pub fn shift_from(&mut self, instr_idx: usize) {
+        self.indices = self.indices.iter()
+            .map(|x| if *x >= instr_idx { x + 1 } else { *x })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opt::pinned::Pinned;
+
+    #[test]
+    fn test_shift() {
+        let mut pinned = Pinned::new();
+        pinned.pin(3);
+        pinned.pin(8);
+        pinned.shift_from(5);
+        assert!(pinned.is_pinned(3));
+        assert!(pinned.is_pinned(9));
+        assert!(!pinned.is_pinned(8));
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use depile::ir::Instr;
+use depile::ir::instr::basic::Operand;
+use depile::ir::instr::{Branching, BranchKind};
+use crate::analysis::cfg::successors;
+use crate::interp::{eval_binary, eval_unary};
+use crate::ssa::{Phi, SSABlock, SSAFunction, SSAFunctions, SSAInstr, SSAOpd, SSAValues};
+
+/// Lattice of values in [`SCCP`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Lattice {
+    /// Not yet known to be defined on any executable path.
+    Undefined,
+    Const(i64),
+    /// Might take more than one value.
+    Overdefined,
+}
+
+impl Lattice {
+    pub fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Undefined, x) | (x, Lattice::Undefined) => x,
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => self,
+            _ => Lattice::Overdefined,
+        }
+    }
+}
+
+/// Reports the result of [`SCCP`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SCCPReport {
+    pub instr_idx: usize,
+    pub opt_count: usize,
+    /// Edges never executed, whose branches are folded.
+    pub pruned: Vec<(usize, usize)>,
+}
+
+impl Display for SCCPReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  Function: {}", self.instr_idx)?;
+        writeln!(f, "  Number of statement optimized: {}", self.opt_count)?;
+        writeln!(f, "  Edges pruned: {:?}", self.pruned)
+    }
+}
+
+/// Sparse conditional constant propagation.
+///
+/// Unlike [`ConstProp`](crate::opt::const_prop::ConstProp), only the edges
+/// found executable are followed, so a phi node is evaluated over its
+/// executable predecessors only. Operands from pruned edges are dropped, and
+/// a phi node left with a single operand becomes a copy.
+pub struct SCCP {
+    pub values: BTreeMap<SSAOpd, Lattice>,
+    /// Values defined in the function. Others, such as parameters, are
+    /// overdefined.
+    pub defined: BTreeSet<SSAOpd>,
+    pub executable: BTreeSet<(usize, usize)>,
+    pub reachable: BTreeSet<usize>,
+}
+
+impl SCCP {
+    pub fn run(funcs: &mut SSAFunctions) -> Vec<SCCPReport> {
+        funcs.functions.iter_mut().map(SCCP::run_func).collect()
+    }
+
+    pub fn run_func(func: &mut SSAFunction) -> SCCPReport {
+        SCCP::analyze(func).rewrite(func)
+    }
+
+    /// Compute the lattice values and the executable edges of `func`, using
+    /// the flow and SSA worklists of Wegman and Zadeck. An instruction is
+    /// evaluated when its block is first reached, and then only when one of
+    /// its operands changes, or, for phi nodes, when a new edge into the
+    /// block becomes executable.
+    pub fn analyze(func: &SSAFunction) -> SCCP {
+        let mut defined = BTreeSet::new();
+        let mut uses: BTreeMap<SSAOpd, Vec<(usize, usize)>> = BTreeMap::new();
+        for (b, block) in func.blocks.iter().enumerate() {
+            for (j, instr) in block.instructions.iter().enumerate() {
+                defined.extend(instr.defined_values(block.first_index + j));
+                for opd in instr.used_values() {
+                    uses.entry(opd.clone()).or_default().push((b, j));
+                }
+            }
+        }
+        let mut sccp = SCCP {
+            values: BTreeMap::new(),
+            defined,
+            executable: BTreeSet::new(),
+            reachable: BTreeSet::new(),
+        };
+
+        let mut flow_work: VecDeque<(Option<usize>, usize)> = VecDeque::from([(None, func.entry_block)]);
+        let mut ssa_work: VecDeque<(usize, usize)> = VecDeque::new();
+        loop {
+            let mut changed = Vec::new();
+            if let Some((pred, b)) = flow_work.pop_front() {
+                if let Some(p) = pred {
+                    if !sccp.executable.insert((p, b)) { continue; }
+                }
+                let first = sccp.reachable.insert(b);
+                let block = &func.blocks[b];
+                for (j, instr) in block.instructions.iter().enumerate() {
+                    if first || matches!(instr, Instr::Extra(_)) {
+                        changed.extend(sccp.visit(block, b, j));
+                    }
+                }
+                if first {
+                    flow_work.extend(sccp.targets(func, b).into_iter().map(|t| (Some(b), t)));
+                }
+            } else if let Some((b, j)) = ssa_work.pop_front() {
+                if !sccp.reachable.contains(&b) { continue; }
+                let block = &func.blocks[b];
+                changed.extend(sccp.visit(block, b, j));
+                if let Instr::Branch(_) = block.instructions[j] {
+                    flow_work.extend(sccp.targets(func, b).into_iter().map(|t| (Some(b), t)));
+                }
+            } else {
+                break;
+            }
+            for opd in changed {
+                ssa_work.extend(uses.get(&opd).into_iter().flatten());
+            }
+        }
+        sccp
+    }
+
+    /// Evaluate the `j`-th instruction of block `b`, and returns the value it
+    /// defines if the value changes.
+    fn visit(&mut self, block: &SSABlock, b: usize, j: usize) -> Option<SSAOpd> {
+        let reg = SSAOpd::Operand(Operand::Register(block.first_index + j));
+        let (opd, v) = match &block.instructions[j] {
+            Instr::Extra(Phi {vars, blocks, dest}) => {
+                let v = vars.iter().zip(blocks.iter())
+                    .filter(|(_, p)| self.executable.contains(&(**p, b)))
+                    .fold(Lattice::Undefined, |acc, (var, _)| acc.meet(self.value(var)));
+                (dest.clone(), v)
+            }
+            Instr::Binary {op, lhs, rhs} => {
+                let v = match (self.value(lhs), self.value(rhs)) {
+                    (Lattice::Const(l), Lattice::Const(r)) =>
+                        eval_binary(&op.to_string(), l, r).map_or(Lattice::Overdefined, Lattice::Const),
+                    (Lattice::Overdefined, _) | (_, Lattice::Overdefined) => Lattice::Overdefined,
+                    _ => Lattice::Undefined,
+                };
+                (reg, v)
+            }
+            Instr::Unary {op, operand} => {
+                let v = match self.value(operand) {
+                    Lattice::Const(x) =>
+                        eval_unary(&op.to_string(), x).map_or(Lattice::Overdefined, Lattice::Const),
+                    v => v,
+                };
+                (reg, v)
+            }
+            Instr::Move {source, dest} => (dest.clone(), self.value(source)),
+            Instr::Load(_) | Instr::Read => (reg, Lattice::Overdefined),
+            _ => return None,
+        };
+        if self.update(&opd, v) { Some(opd) } else { None }
+    }
+
+    /// Successors of block `b` that might be executed, given the current
+    /// value of the branch condition.
+    fn targets(&self, func: &SSAFunction, b: usize) -> Vec<usize> {
+        let succs = successors(func.blocks.as_slice(), b);
+        match func.blocks[b].instructions.last() {
+            Some(Instr::Branch(branching)) => match self.branch_taken(branching) {
+                Some(true) => vec![branching.dest],
+                Some(false) => succs.fallthrough.into_iter().collect(),
+                None if matches!(self.branch_condition(branching), Lattice::Undefined) => Vec::new(),
+                None => succs.ordered(),
+            },
+            _ => succs.ordered(),
+        }
+    }
+
+    /// Rewrite `func` according to the analysis.
+    pub fn rewrite(&self, func: &mut SSAFunction) -> SCCPReport {
+        let mut report = SCCPReport {
+            instr_idx: func.blocks[0].first_index,
+            opt_count: 0,
+            pruned: Vec::new(),
+        };
+        for b in &self.reachable {
+            for s in successors(func.blocks.as_slice(), *b).ordered() {
+                if !self.executable.contains(&(*b, s)) { report.pruned.push((*b, s)); }
+            }
+        }
+
+        for (b, block) in func.blocks.iter_mut().enumerate() {
+            let reachable = self.reachable.contains(&b);
+            let mut instr_idx = block.first_index;
+            for instr in block.instructions.iter_mut() {
+                let reg = SSAOpd::Operand(Operand::Register(instr_idx));
+                instr_idx += 1;
+
+                // Fold branches and prune phi nodes before substitution.
+                if reachable {
+                    let replacement = match instr {
+                        Instr::Extra(Phi {vars, blocks, dest}) => {
+                            let before = vars.len();
+                            let kept: Vec<(SSAOpd, usize)> = vars.iter().cloned().zip(blocks.iter().cloned())
+                                .filter(|(_, p)| self.executable.contains(&(*p, b)))
+                                .collect();
+                            report.opt_count += before - kept.len();
+                            if kept.len() == 1 && kept.len() < before {
+                                Some(Instr::Move {source: kept[0].0.clone(), dest: dest.clone()})
+                            } else {
+                                *vars = kept.iter().map(|(v, _)| v.clone()).collect();
+                                *blocks = kept.iter().map(|(_, p)| *p).collect();
+                                None
+                            }
+                        }
+                        Instr::Branch(branching) => match self.branch_taken(branching) {
+                            Some(true) if !matches!(branching.method, BranchKind::Unconditional) =>
+                                Some(Instr::Branch(Branching {method: BranchKind::Unconditional, dest: branching.dest})),
+                            Some(false) => Some(Instr::Nop),
+                            _ => None,
+                        }
+                        _ => None,
+                    };
+                    if let Some(new) = replacement {
+                        if !matches!(new, Instr::Move {..}) { report.opt_count += 1; }
+                        *instr = new;
+                    }
+                }
+
+                for opd in instr.used_values_mut() {
+                    if let Lattice::Const(c) = self.value(opd) {
+                        *opd = SSAOpd::Operand(Operand::Const(c));
+                        report.opt_count += 1;
+                    }
+                }
+
+                let folded = match instr {
+                    Instr::Binary {..} | Instr::Unary {..} => self.value(&reg),
+                    Instr::Move {source: _, dest} | Instr::Extra(Phi {vars: _, blocks: _, dest})
+                        if matches!(dest, SSAOpd::Subscribed(_, _)) => self.value(dest),
+                    _ => Lattice::Overdefined,
+                };
+                if let Lattice::Const(_) = folded {
+                    *instr = Instr::Nop;
+                    report.opt_count += 1;
+                }
+            }
+            restore_phis_first(&mut block.instructions);
+        }
+        report
+    }
+
+    /// The lattice value of `opd`.
+    pub fn value(&self, opd: &SSAOpd) -> Lattice {
+        match opd {
+            SSAOpd::Operand(Operand::Const(c)) => Lattice::Const(*c),
+            SSAOpd::Undef => Lattice::Undefined,
+            _ if self.defined.contains(opd) => *self.values.get(opd).unwrap_or(&Lattice::Undefined),
+            _ => Lattice::Overdefined,
+        }
+    }
+
+    fn update(&mut self, opd: &SSAOpd, v: Lattice) -> bool {
+        let old = self.value(opd);
+        let new = old.meet(v);
+        if new == old { return false; }
+        self.values.insert(opd.clone(), new);
+        true
+    }
+
+    fn branch_condition(&self, branching: &Branching<SSAOpd>) -> Lattice {
+        match &branching.method {
+            BranchKind::Unconditional => Lattice::Const(1),
+            BranchKind::If(opd) | BranchKind::Unless(opd) => self.value(opd),
+        }
+    }
+
+    /// Returns whether `branching` is always or never taken, if known.
+    fn branch_taken(&self, branching: &Branching<SSAOpd>) -> Option<bool> {
+        match (&branching.method, self.branch_condition(branching)) {
+            (BranchKind::Unconditional, _) => Some(true),
+            (BranchKind::If(_), Lattice::Const(c)) => Some(c & 1 != 0),
+            (BranchKind::Unless(_), Lattice::Const(c)) => Some(c & 1 == 0),
+            _ => None,
+        }
+    }
+}
+
+/// Move the phi nodes in the leading run of phi nodes, `nop`s and copies back
+/// to the head of the block, as later passes expect phi nodes to come first.
+/// None of these instructions define registers, so no index is invalidated.
+fn restore_phis_first(instrs: &mut [SSAInstr]) {
+    let len = instrs.iter()
+        .take_while(|instr| matches!(instr, Instr::Extra(_) | Instr::Nop | Instr::Move {..}))
+        .count();
+    instrs[..len].sort_by_key(|instr| !matches!(instr, Instr::Extra(_)));
+}
+
+#[cfg(test)]
+mod test {
+    use depile::ir::Instr;
+    use depile::ir::instr::basic::Operand;
+    use depile::ir::instr::BranchKind;
+    use crate::analysis::phi::PhiForge;
+    use crate::interp::interpret;
+    use crate::opt::sccp::{Lattice, SCCP};
+    use crate::samples::get_sample_functions;
+    use crate::samples::synthetic::PRUNED_PHI;
+    use crate::ssa::{Phi, SSAOpd};
+
+    #[test]
+    fn test_pruned_phi() {
+        let funcs = get_sample_functions(PRUNED_PHI);
+        let (mut ssa, _) = PhiForge::run(&funcs);
+        let func = &mut ssa.functions[0];
+        let expected = interpret(func, &[]).unwrap();
+        assert_eq!(expected, " 15\n");
+
+        let sccp = SCCP::analyze(func);
+        let dest = func.blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .find_map(|instr| match instr {
+                Instr::Extra(Phi {dest, ..}) => Some(dest.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(sccp.value(&dest), Lattice::Const(3));
+
+        let report = sccp.rewrite(func);
+        assert_eq!(report.pruned.len(), 1);
+        let written: Vec<&SSAOpd> = func.blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .filter_map(|instr| match instr { Instr::Write(opd) => Some(opd), _ => None })
+            .collect();
+        assert_eq!(written, vec![&SSAOpd::Operand(Operand::Const(15))]);
+        assert_eq!(interpret(func, &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_taken_branch() {
+        // `x < 2` always holds, so the branch to the `else` block is folded.
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PRUNED_PHI));
+        let func = &mut ssa.functions[0];
+        let sccp = SCCP::analyze(func);
+        let dead = func.blocks.iter().position(|block| block.first_index == 9).unwrap();
+        assert!(!sccp.reachable.contains(&dead));
+        sccp.rewrite(func);
+        assert!(!func.blocks.iter()
+            .flat_map(|block| block.instructions.iter())
+            .any(|instr| matches!(instr, Instr::Branch(b) if !matches!(b.method, BranchKind::Unconditional))));
+    }
+}
@@ -38,6 +38,43 @@ include_samples! {
     PHI,
 }
 
+/// Small hand-written programs, each exercising a specific transformation.
+pub mod synthetic {
+    pub const INVARIANT: &str = include_str!("../samples/synthetic/invariant.txt");
+    pub const PINNED_LOAD: &str = include_str!("../samples/synthetic/pinned_load.txt");
+    pub const DEAD_LOOP: &str = include_str!("../samples/synthetic/dead_loop.txt");
+    pub const COMMON_CODE: &str = include_str!("../samples/synthetic/common_code.txt");
+    pub const REDUCTION: &str = include_str!("../samples/synthetic/reduction.txt");
+    pub const PARALLEL: &str = include_str!("../samples/synthetic/parallel.txt");
+    pub const JUMP_ENTRY: &str = include_str!("../samples/synthetic/jump_entry.txt");
+    pub const TWO_LOOPS: &str = include_str!("../samples/synthetic/two_loops.txt");
+    pub const MANY_PREDS: &str = include_str!("../samples/synthetic/many_preds.txt");
+    pub const LOOP_OUTPUT: &str = include_str!("../samples/synthetic/loop_output.txt");
+    pub const BRANCH_NEXT: &str = include_str!("../samples/synthetic/branch_next.txt");
+    pub const COPY_SUB: &str = include_str!("../samples/synthetic/copy_sub.txt");
+    pub const NO_EXIT: &str = include_str!("../samples/synthetic/no_exit.txt");
+    pub const FALLTHROUGH_LATCH: &str = include_str!("../samples/synthetic/fallthrough_latch.txt");
+    pub const LATCH_EXIT: &str = include_str!("../samples/synthetic/latch_exit.txt");
+    pub const BOTTOM_TEST: &str = include_str!("../samples/synthetic/bottom_test.txt");
+    pub const TWO_EXITS: &str = include_str!("../samples/synthetic/two_exits.txt");
+    pub const STORE_HEADER: &str = include_str!("../samples/synthetic/store_header.txt");
+    pub const STORE_LOAD: &str = include_str!("../samples/synthetic/store_load.txt");
+    pub const PRUNED_PHI: &str = include_str!("../samples/synthetic/pruned_phi.txt");
+    pub const DEEP_DEF: &str = include_str!("../samples/synthetic/deep_def.txt");
+    pub const NESTED_DEF: &str = include_str!("../samples/synthetic/nested_def.txt");
+    pub const CONST_CHAIN: &str = include_str!("../samples/synthetic/const_chain.txt");
+    pub const COPY_CHAIN: &str = include_str!("../samples/synthetic/copy_chain.txt");
+    pub const CRITICAL_EDGE: &str = include_str!("../samples/synthetic/critical_edge.txt");
+    pub const DEAD_CODE: &str = include_str!("../samples/synthetic/dead_code.txt");
+    pub const LIVE_ACROSS: &str = include_str!("../samples/synthetic/live_across.txt");
+    pub const GUARDED_DIV: &str = include_str!("../samples/synthetic/guarded_div.txt");
+    pub const LOOP_CALL: &str = include_str!("../samples/synthetic/loop_call.txt");
+    pub const NESTED_LOOPS: &str = include_str!("../samples/synthetic/nested_loops.txt");
+    pub const TWO_ENTRIES: &str = include_str!("../samples/synthetic/two_entries.txt");
+    pub const IRREDUCIBLE: &str = include_str!("../samples/synthetic/irreducible.txt");
+    pub const ODD_PARAMS: &str = include_str!("../samples/synthetic/odd_params.txt");
+}
+
 pub fn get_sample_functions(str: &str) -> Functions {
     let program: Box<Program> = read_program(str).unwrap();
     let blocks: Blocks<depile::ir::instr::basic::Kind> = Blocks::try_from(program.as_ref()).unwrap();
@@ -7,11 +7,14 @@
 #![allow(unused)]
 
 use std::fmt::Formatter;
+use std::iter::Zip;
+use std::ops::RangeFrom;
 use smallvec::{SmallVec, smallvec};
 use depile::analysis::control_flow::{BranchingBehaviour, HasBranchingBehaviour};
+use depile::ir::Instr;
 use depile::ir::instr::basic::{InterProc, Operand};
-use depile::ir::instr::{HasDest, HasOperand, OutputInfo};
-use parse_display::{Display, FromStr};
+use depile::ir::instr::{BranchKind, HasDest, HasOperand, OutputInfo};
+use parse_display::{Display, FromStr, ParseError};
 
 /// Instruction kind SSA
 pub type SSAKind = depile::ir::instr::Kind<
@@ -38,6 +41,10 @@ pub enum SSAOpd {
     /// Subscribed variable.
     #[display("{0}${1}")]
     Subscribed(String, isize),
+    /// Undefined value, e.g. a phi operand from a path on which the variable
+    /// is never assigned.
+    #[display("undef")]
+    Undef,
     /// Empty operand.
     #[display("<unknown>")]
     NOpd,
@@ -51,15 +58,51 @@ pub struct Phi {
     pub dest: SSAOpd,
 }
 
+/// Printed as `x$2 <- phi [b0: x$0] [b3: x$1]`, pairing each operand with
+/// the predecessor it comes from. Parsing also accepts `x$2 = phi ...`.
+///
+/// Operands or predecessors left over in a malformed phi node are printed
+/// with `?` in place of the missing half, e.g. `[b?: x$1]`, so that the
+/// mismatch shows up instead of being dropped.
 impl std::fmt::Display for Phi {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} <- phi", self.dest).unwrap();
-        for var in &self.vars { write!(f, " {}", var).unwrap(); }
-        for block in &self.blocks { write!(f, " [{}]", block).unwrap(); }
+        write!(f, "{} <- phi", self.dest)?;
+        for i in 0..self.vars.len().max(self.blocks.len()) {
+            match (self.vars.get(i), self.blocks.get(i)) {
+                (Some(var), Some(block)) => write!(f, " [b{}: {}]", block, var)?,
+                (Some(var), None) => write!(f, " [b?: {}]", var)?,
+                (None, Some(block)) => write!(f, " [b{}: ?]", block)?,
+                (None, None) => unreachable!(),
+            }
+        }
         Ok(())
     }
 }
 
+impl std::str::FromStr for Phi {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || ParseError::with_message("malformed phi node");
+        let (dest, mut rest) = s.split_once(" <- phi")
+            .or_else(|| s.split_once(" = phi"))
+            .ok_or_else(malformed)?;
+        let mut phi = Phi { vars: Vec::new(), blocks: Vec::new(), dest: dest.parse()? };
+        rest = rest.trim_start();
+        while !rest.is_empty() {
+            let (item, tail) = rest.strip_prefix('[')
+                .and_then(|r| r.split_once(']'))
+                .ok_or_else(malformed)?;
+            let (block, var) = item.split_once(": ").ok_or_else(malformed)?;
+            let block = block.strip_prefix('b').and_then(|b| b.parse().ok()).ok_or_else(malformed)?;
+            phi.blocks.push(block);
+            phi.vars.push(var.parse()?);
+            rest = tail.trim_start();
+        }
+        Ok(phi)
+    }
+}
+
 impl HasBranchingBehaviour for Phi {
     fn get_branching_behaviour(&self) -> BranchingBehaviour {
         BranchingBehaviour { might_fallthrough: true, alternative_dest: None }
@@ -102,9 +145,88 @@ impl HasDest for SSAInterProc {
     }
 }
 
+/// Values read and written by an [`SSAInstr`]uction.
+pub trait SSAValues {
+    /// Operands read by this instruction, including the operands of phi nodes.
+    fn used_values(&self) -> Vec<&SSAOpd>;
+    /// Same as [`SSAValues::used_values`], but mutable for substitution.
+    fn used_values_mut(&mut self) -> Vec<&mut SSAOpd>;
+    /// Values defined by this instruction, given that it is placed at `instr_idx`.
+    fn defined_values(&self, instr_idx: usize) -> Vec<SSAOpd>;
+}
+
+impl SSAValues for SSAInstr {
+    fn used_values(&self) -> Vec<&SSAOpd> {
+        match self {
+            Instr::Binary {op: _, lhs, rhs} => vec![lhs, rhs],
+            Instr::Unary {op: _, operand} => vec![operand],
+            Instr::Branch(branching) =>
+                match &branching.method {
+                    BranchKind::If(opd) => vec![opd],
+                    BranchKind::Unless(opd) => vec![opd],
+                    _ => Vec::new(),
+                },
+            Instr::Load(opd) => vec![opd],
+            Instr::Store {data, address} => vec![data, address],
+            Instr::Move {source, dest: _} => vec![source],
+            Instr::Write(opd) => vec![opd],
+            Instr::InterProc(SSAInterProc::PushParam(opd)) => vec![opd],
+            Instr::Extra(Phi {vars, blocks: _, dest: _}) => vars.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn used_values_mut(&mut self) -> Vec<&mut SSAOpd> {
+        match self {
+            Instr::Binary {op: _, lhs, rhs} => vec![lhs, rhs],
+            Instr::Unary {op: _, operand} => vec![operand],
+            Instr::Branch(branching) =>
+                match &mut branching.method {
+                    BranchKind::If(opd) => vec![opd],
+                    BranchKind::Unless(opd) => vec![opd],
+                    _ => Vec::new(),
+                },
+            Instr::Load(opd) => vec![opd],
+            Instr::Store {data, address} => vec![data, address],
+            Instr::Move {source, dest: _} => vec![source],
+            Instr::Write(opd) => vec![opd],
+            Instr::InterProc(SSAInterProc::PushParam(opd)) => vec![opd],
+            Instr::Extra(Phi {vars, blocks: _, dest: _}) => vars.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn defined_values(&self, instr_idx: usize) -> Vec<SSAOpd> {
+        match self {
+            Instr::Binary {..} | Instr::Unary {..} | Instr::Load(_) | Instr::Read =>
+                vec![SSAOpd::Operand(Operand::Register(instr_idx))],
+            Instr::Move {source: _, dest} => vec![dest.clone()],
+            Instr::Extra(Phi {vars: _, blocks: _, dest}) => vec![dest.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Instructions of an [`SSABlock`] paired with their indices, i.e.
+/// `first_index` plus their offsets in the block.
+pub trait IndexedInstructions {
+    fn indexed_instructions(&self) -> Zip<RangeFrom<usize>, std::slice::Iter<'_, SSAInstr>>;
+    fn indexed_instructions_mut(&mut self) -> Zip<RangeFrom<usize>, std::slice::IterMut<'_, SSAInstr>>;
+}
+
+impl IndexedInstructions for SSABlock {
+    fn indexed_instructions(&self) -> Zip<RangeFrom<usize>, std::slice::Iter<'_, SSAInstr>> {
+        (self.first_index..).zip(self.instructions.iter())
+    }
+
+    fn indexed_instructions_mut(&mut self) -> Zip<RangeFrom<usize>, std::slice::IterMut<'_, SSAInstr>> {
+        (self.first_index..).zip(self.instructions.iter_mut())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{SSAInstr, Phi, SSAOpd};
+    use super::{IndexedInstructions, SSAInstr, SSAInterProc, Phi, SSAOpd};
 
     macro_rules! assert_equiv {
         ($($str: expr => $val: expr),+ $(,)?) => {
@@ -122,7 +244,73 @@ mod tests {
         assert_equiv! {
             "GP" => SSAOpd::Operand(GP),
             "i$0" => SSAOpd::Subscribed("i".to_string(), 0),
+            "undef" => SSAOpd::Undef,
+        }
+    }
+
+    #[test]
+    fn test_phi() {
+        use depile::ir::instr::basic::Operand::Const;
+
+        let x = |i| SSAOpd::Subscribed("x".to_string(), i);
+        assert_equiv! {
+            "x$2 <- phi [b0: x$0] [b3: x$1]" => Phi { vars: vec![x(0), x(1)], blocks: vec![0, 3], dest: x(2) },
+            "x$0 <- phi [b1: undef] [b2: 4]" => Phi {
+                vars: vec![SSAOpd::Undef, SSAOpd::Operand(Const(4))],
+                blocks: vec![1, 2],
+                dest: x(0),
+            },
+            "x$1 <- phi" => Phi { vars: Vec::new(), blocks: Vec::new(), dest: x(1) },
+        }
+
+        assert_eq!(
+            "x$2 = phi [b0: x$0] [b3: x$1]".parse::<Phi>().unwrap(),
+            Phi { vars: vec![x(0), x(1)], blocks: vec![0, 3], dest: x(2) },
+        );
+
+        let unpaired = Phi { vars: vec![x(0), x(1)], blocks: vec![0], dest: x(2) };
+        assert_eq!(unpaired.to_string(), "x$2 <- phi [b0: x$0] [b?: x$1]");
+        let unpaired = Phi { vars: vec![x(0)], blocks: vec![0, 3], dest: x(2) };
+        assert_eq!(unpaired.to_string(), "x$2 <- phi [b0: x$0] [b3: ?]");
+
+        for malformed in ["x$2 <- phi x$0 x$1 [0] [3]", "x$2 <- phi [0: x$0]", "x$2 <- phi [b0: x$0", "x$2 phi", " = phi [b0: x$0]"] {
+            assert!(malformed.parse::<Phi>().is_err());
         }
     }
 
+    #[test]
+    fn test_indexed_instructions() {
+        use depile::ir::instr::basic::Operand::Const;
+        use crate::analysis::phi::PhiForge;
+        use crate::samples::{get_sample_functions, PRIME};
+
+        let (mut ssa, _) = PhiForge::run(&get_sample_functions(PRIME));
+        for block in ssa.functions[0].blocks.iter_mut() {
+            let expected: Vec<usize> = (0..block.instructions.len()).map(|j| block.first_index + j).collect();
+            let indices: Vec<usize> = block.indexed_instructions().map(|(i, _)| i).collect();
+            assert_eq!(indices, expected);
+            for (i, instr) in block.indexed_instructions_mut() {
+                *instr = SSAInstr::Write(SSAOpd::Operand(Const(i as i64)));
+            }
+            for (j, instr) in block.instructions.iter().enumerate() {
+                assert_eq!(*instr, SSAInstr::Write(SSAOpd::Operand(Const((block.first_index + j) as i64))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_inter_proc() {
+        use depile::ir::instr::basic::Operand::Register;
+
+        assert_equiv! {
+            "param i$3" => SSAInterProc::PushParam(SSAOpd::Subscribed("i".to_string(), 3)),
+            "param i$-1" => SSAInterProc::PushParam(SSAOpd::Subscribed("i".to_string(), -1)),
+            "param (5)" => SSAInterProc::PushParam(SSAOpd::Operand(Register(5))),
+            "call [5]" => SSAInterProc::Call { dest: 5 },
+        }
+
+        for malformed in ["param", "param ", "call [x]", "call 5", "push i$3"] {
+            assert!(malformed.parse::<SSAInterProc>().is_err());
+        }
+    }
 }